@@ -1,15 +1,43 @@
 use core::slice::Iter;
-use std::iter::Enumerate;
-
-use crate::runtime_val::{RuntimeValue, StringObj};
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    iter::Enumerate,
+};
+
+use crate::{
+    packed_val::PackedValue,
+    runtime_val::{FunctionObj, RuntimeValue, StringObj},
+    token::Span,
+    vm::Vm,
+};
 
 pub struct Chunk {
     pub code: Vec<Bytecode>,
-    pub constants: Vec<RuntimeValue>,
+    pub constants: Vec<PackedValue>,
+    // Indices of constants that can be shared by multiple instructions
+    constant_indices: HashMap<ConstantKey, usize>,
+
+    pub spans: Vec<(Span, usize)>,
+
+    // Folds constant expressions and fuses instructions while they are emitted
+    optimize: bool,
+    // Offsets of the instructions emitted since the last jump target,
+    // only these can be rewritten by the optimizer
+    recent: Vec<usize>,
+    // Constants added for the recent constant loads, with the offsets of the loads.
+    // Nothing else refers to them, so they can be dropped when the loads are folded
+    added_constants: Vec<(usize, usize)>,
+}
 
-    pub lines: Vec<(usize, usize)>,
-    line_index: usize,
-    current_line: usize,
+/*
+Numbers are compared bitwise, so 0 and -0 stay separate constants.
+Strings are interned, so equal contents mean equal pointers.
+*/
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    String(*mut StringObj),
 }
 
 impl Chunk {
@@ -17,236 +45,905 @@ impl Chunk {
         Chunk {
             code: Vec::new(),
             constants: Vec::new(),
+            constant_indices: HashMap::new(),
+
+            spans: Vec::new(),
 
-            lines: Vec::new(),
-            current_line: 0,
-            line_index: 0,
+            optimize: true,
+            recent: Vec::new(),
+            added_constants: Vec::new(),
         }
     }
 
-    pub fn emit_constant(&mut self, val: RuntimeValue, line: usize) {
+    pub fn disable_optimizations(&mut self) {
+        self.optimize = false;
+    }
+
+    /// Frees the state only needed while emitting code
+    pub fn finish(&mut self) {
+        self.constant_indices = HashMap::new();
+        self.recent = Vec::new();
+        self.added_constants = Vec::new();
+    }
+
+    /// Returns the offset of the next instruction, which is going to be
+    /// the target of a backward jump
+    pub fn jump_target(&mut self) -> usize {
+        self.recent.clear();
+        self.added_constants.clear();
+        self.code.len()
+    }
+
+    pub fn emit_constant(&mut self, val: RuntimeValue, span: Span) -> Result<(), ChunkErr> {
+        let count = self.constants.len();
+        let index = self.add_constant(val)?;
+        self.emit_constant_load(index, index == count, span);
+        Ok(())
+    }
+
+    fn emit_constant_load(&mut self, index: usize, added: bool, span: Span) {
+        if added && self.optimize {
+            self.added_constants.push((self.code.len(), index));
+        }
+        self.emit_indexed(opcodes::CONSTANT, opcodes::CONSTANT_LONG, index, span);
+    }
+
+    pub fn emit_opcode(&mut self, opcode: Bytecode, span: Span) {
+        if self.optimize && self.optimize_opcode(opcode, span) {
+            return;
+        }
+
+        self.code.push(opcode);
+        self.at_span(span, 1);
+    }
+    fn constant_key(val: RuntimeValue) -> Option<ConstantKey> {
+        match val {
+            RuntimeValue::Number(n) => Some(ConstantKey::Number(n.to_bits())),
+            RuntimeValue::String(s) => Some(ConstantKey::String(s)),
+            _ => None,
+        }
+    }
+
+    fn add_constant(&mut self, val: RuntimeValue) -> Result<usize, ChunkErr> {
+        let key = Self::constant_key(val);
+        if let Some(index) = key.and_then(|k| self.constant_indices.get(&k)) {
+            return Ok(*index);
+        }
+
         let index = self.constants.len();
-        self.constants.push(val);
+        if index > MAX_LONG_OPERAND {
+            return Err(ChunkErr::TooManyConstants);
+        }
+
+        self.constants.push(PackedValue::pack(val));
+        if let Some(key) = key {
+            self.constant_indices.insert(key, index);
+        }
+        Ok(index)
+    }
+
+    fn identifier_constant(&mut self, name: *mut StringObj) -> Result<usize, ChunkErr> {
+        self.add_constant(RuntimeValue::String(name))
+    }
 
-        if index < 0xFF {
-            self.code.push(opcodes::CONSTANT);
+    /*
+    Instructions referring to constants or locals have two variants,
+    the long one is used when the index doesn't fit into a single byte.
+    Long operands are 3 bytes wide, little-endian.
+    */
+    fn emit_indexed(&mut self, opcode: Bytecode, long_opcode: Bytecode, index: usize, span: Span) {
+        if index <= 0xFF {
+            self.code.push(opcode);
             self.code.push(index as u8);
-            self.at_line(line, 2);
-        } else if index < 0xFF_FFFF {
-            self.code.push(opcodes::CONSTANT_LONG);
-            let bytes = index.to_le_bytes();
-            self.code.push(bytes[0]);
-            self.code.push(bytes[1]);
-            self.code.push(bytes[2]);
-            self.at_line(line, 4);
+            self.at_span(span, 2);
         } else {
-            // TODO: error handling
-            panic!("Too many constants");
+            self.code.push(long_opcode);
+            self.push_long_operand(index);
+            self.at_span(span, 4);
         }
     }
 
-    pub fn emit_constant_string(&mut self, slice: &str, line: usize) {
-        let string_ptr = StringObj::new(slice);
-        let string_val = RuntimeValue::String(string_ptr);
-        self.emit_constant(string_val, line);
+    fn push_long_operand(&mut self, operand: usize) {
+        let bytes = operand.to_le_bytes();
+        self.code.extend_from_slice(&bytes[..3]);
     }
 
-    pub fn emit_opcode(&mut self, opcode: Bytecode, line: usize) {
-        self.code.push(opcode);
-        self.at_line(line, 1);
+    pub fn emit_declare_global(
+        &mut self,
+        name: *mut StringObj,
+        span: Span,
+    ) -> Result<(), ChunkErr> {
+        let index = self.identifier_constant(name)?;
+        self.emit_indexed(
+            opcodes::DEFINE_GLOBAL,
+            opcodes::DEFINE_GLOBAL_LONG,
+            index,
+            span,
+        );
+        Ok(())
     }
-    fn identifier_constant(&mut self, name: &str) -> usize {
-        let string_ptr = StringObj::new(name);
-        let string_val = RuntimeValue::String(string_ptr);
 
-        let index = self.constants.len();
-        self.constants.push(string_val);
+    pub fn emit_get_global(&mut self, name: *mut StringObj, span: Span) -> Result<(), ChunkErr> {
+        let index = self.identifier_constant(name)?;
+        self.emit_indexed(opcodes::GET_GLOBAL, opcodes::GET_GLOBAL_LONG, index, span);
+        Ok(())
+    }
 
-        index
+    pub fn emit_set_global(&mut self, name: *mut StringObj, span: Span) -> Result<(), ChunkErr> {
+        let index = self.identifier_constant(name)?;
+        self.emit_indexed(opcodes::SET_GLOBAL, opcodes::SET_GLOBAL_LONG, index, span);
+        Ok(())
     }
 
-    pub fn emit_declare_global(&mut self, name: &str, line: usize) {
-        let index = self.identifier_constant(name);
+    pub fn emit_get_local(&mut self, index: usize, span: Span) {
+        self.emit_indexed(opcodes::GET_LOCAL, opcodes::GET_LOCAL_LONG, index, span);
+    }
 
-        // TODO: this would eventually require DECLARE_GLOBAL_LONG etc...
-        self.code.push(opcodes::DEFINE_GLOBAL);
-        self.code.push(index as u8);
-        self.at_line(line, 2);
+    pub fn emit_set_local(&mut self, index: usize, span: Span) {
+        self.emit_indexed(opcodes::SET_LOCAL, opcodes::SET_LOCAL_LONG, index, span);
     }
 
-    pub fn emit_get_global(&mut self, name: &str, line: usize) {
-        let index = self.identifier_constant(name);
+    pub fn emit_get_upvalue(&mut self, index: usize, span: Span) {
+        self.code.push(opcodes::GET_UPVALUE);
+        self.code.push(index as u8);
+        self.at_span(span, 2);
+    }
 
-        self.code.push(opcodes::GET_GLOBAL);
+    pub fn emit_set_upvalue(&mut self, index: usize, span: Span) {
+        self.code.push(opcodes::SET_UPVALUE);
         self.code.push(index as u8);
-        self.at_line(line, 2);
+        self.at_span(span, 2);
     }
 
-    pub fn emit_set_global(&mut self, name: &str, line: usize) {
-        let index = self.identifier_constant(name);
+    /// Emits an instruction whose operand is the name of a class, method or property
+    pub fn emit_named(
+        &mut self,
+        opcode: Bytecode,
+        long_opcode: Bytecode,
+        name: *mut StringObj,
+        span: Span,
+    ) -> Result<(), ChunkErr> {
+        let index = self.identifier_constant(name)?;
+        self.emit_indexed(opcode, long_opcode, index, span);
+        Ok(())
+    }
 
-        self.code.push(opcodes::SET_GLOBAL);
-        self.code.push(index as u8);
-        self.at_line(line, 2);
+    /// Calls a method directly, without creating a bound method first.
+    /// The argument count follows the name, which can be a long operand.
+    pub fn emit_invoke(
+        &mut self,
+        opcode: Bytecode,
+        long_opcode: Bytecode,
+        name: *mut StringObj,
+        arg_count: u8,
+        span: Span,
+    ) -> Result<(), ChunkErr> {
+        let index = self.identifier_constant(name)?;
+
+        let start = self.code.len();
+        if index <= 0xFF {
+            self.code.push(opcode);
+            self.code.push(index as u8);
+        } else {
+            self.code.push(long_opcode);
+            self.push_long_operand(index);
+        }
+        self.code.push(arg_count);
+        self.at_span(span, self.code.len() - start);
+        Ok(())
     }
 
-    pub fn emit_get_local(&mut self, index: usize, line: usize) {
-        self.code.push(opcodes::GET_LOCAL);
-        self.code.push(index as u8);
-        self.at_line(line, 2);
+    /// Every captured variable is encoded as a pair of bytes following the
+    /// function constant: whether it's a local of the enclosing function
+    /// (or an upvalue of it), and its index.
+    /// Locals past the first 256 are marked with LONG_LOCAL and a long index.
+    pub fn emit_closure(
+        &mut self,
+        function: *mut FunctionObj,
+        upvalues: &[(bool, usize)],
+        span: Span,
+    ) -> Result<(), ChunkErr> {
+        let index = self.add_constant(RuntimeValue::Function(function))?;
+
+        let start = self.code.len();
+        if index <= 0xFF {
+            self.code.push(opcodes::CLOSURE);
+            self.code.push(index as u8);
+        } else {
+            self.code.push(opcodes::CLOSURE_LONG);
+            self.push_long_operand(index);
+        }
+
+        for (is_local, index) in upvalues {
+            if *index <= 0xFF {
+                self.code.push(*is_local as u8);
+                self.code.push(*index as u8);
+            } else {
+                // Upvalues are limited to 256, only locals can have a long index
+                self.code.push(LONG_LOCAL);
+                self.push_long_operand(*index);
+            }
+        }
+
+        self.at_span(span, self.code.len() - start);
+        Ok(())
     }
 
-    pub fn emit_set_local(&mut self, index: usize, line: usize) {
-        self.code.push(opcodes::SET_LOCAL);
-        self.code.push(index as u8);
-        self.at_line(line, 2);
+    /// Emits a forward jump with a placeholder offset, which has to be
+    /// backpatched later using the returned operand position
+    pub fn emit_jump(&mut self, opcode: Bytecode, span: Span) -> usize {
+        self.code.push(opcode);
+        self.code.push(0xFF);
+        self.code.push(0xFF);
+        self.at_span(span, 3);
+
+        self.code.len() - 2
     }
 
-    pub fn disassemble(&self) {
+    pub fn patch_jump(&mut self, operand_pos: usize) -> Result<(), ChunkErr> {
+        // The code emitted so far can't be rewritten anymore, the jump lands after it
+        self.recent.clear();
+        self.added_constants.clear();
+
+        // Jump over the operand itself too
+        let jump = self.code.len() - operand_pos - 2;
+        if jump > u16::MAX as usize {
+            return Err(ChunkErr::JumpTooLarge);
+        }
+
+        let bytes = (jump as u16).to_le_bytes();
+        self.code[operand_pos] = bytes[0];
+        self.code[operand_pos + 1] = bytes[1];
+
+        Ok(())
+    }
+
+    pub fn emit_loop(&mut self, loop_start: usize, span: Span) -> Result<(), ChunkErr> {
+        // Jump over the LOOP instruction as well
+        let jump = self.code.len() - loop_start + 3;
+        if jump > u16::MAX as usize {
+            return Err(ChunkErr::JumpTooLarge);
+        }
+
+        let bytes = (jump as u16).to_le_bytes();
+        self.code.push(opcodes::LOOP);
+        self.code.push(bytes[0]);
+        self.code.push(bytes[1]);
+        self.at_span(span, 3);
+
+        Ok(())
+    }
+
+    pub fn emit_call(&mut self, arg_count: u8, span: Span) {
+        self.code.push(opcodes::CALL);
+        self.code.push(arg_count);
+        self.at_span(span, 2);
+    }
+
+    pub fn disassemble(&self, out: &mut dyn Write) -> io::Result<()> {
         let mut opcodes = self.code.iter().enumerate();
 
-        println!("OFFSET     LINE     OPCODE      OTHER INFO");
-        println!("==========================================");
+        writeln!(out, "OFFSET     LINE:COL     OPCODE      OTHER INFO")?;
+        writeln!(out, "==============================================")?;
 
         while let Some((offset, opcode)) = opcodes.next() {
-            print!("0x{:4X}     {:4}     ", offset, self.get_line_at_ip(offset));
+            let span = self.get_span_at_ip(offset);
+            let position = format!("{}:{}", span.line, span.column);
+            write!(out, "0x{:4X}     {:8}     ", offset, position)?;
             match *opcode {
-                opcodes::RETURN => println!("RETURN"),
-                opcodes::CONSTANT => self.disas_constant(&mut opcodes),
-                opcodes::NIL => println!("NIL"),
-                opcodes::TRUE => println!("TRUE"),
-                opcodes::FALSE => println!("FALSE"),
-                opcodes::POP => println!("POP"),
-                opcodes::GET_LOCAL => self.disas_get_local(&mut opcodes),
-                opcodes::SET_LOCAL => self.disas_set_local(&mut opcodes),
-                opcodes::GET_GLOBAL => self.disas_get_global(&mut opcodes),
-                opcodes::DEFINE_GLOBAL => self.disas_define_global(&mut opcodes),
-                opcodes::SET_GLOBAL => self.disas_set_global(&mut opcodes),
-                opcodes::EQUAL => println!("EQUAL"),
-                opcodes::GREATER => println!("GREATER"),
-                opcodes::LESS => println!("LESS"),
-                opcodes::ADD => println!("ADD"),
-                opcodes::SUBTRACT => println!("SUBTRACT"),
-                opcodes::MULTIPLY => println!("MULTIPLY"),
-                opcodes::DIVIDE => println!("DIVIDE"),
-                opcodes::NOT => println!("NOT"),
-                opcodes::NEGATE => println!("NEGATE"),
-                opcodes::PRINT => println!("PRINT"),
-
-                opcodes::CONSTANT_LONG => self.disas_constant_long(&mut opcodes),
+                opcodes::RETURN => writeln!(out, "RETURN")?,
+                opcodes::CONSTANT => self.disas_constant(out, &mut opcodes)?,
+                opcodes::NIL => writeln!(out, "NIL")?,
+                opcodes::TRUE => writeln!(out, "TRUE")?,
+                opcodes::FALSE => writeln!(out, "FALSE")?,
+                opcodes::POP => writeln!(out, "POP")?,
+                opcodes::GET_LOCAL => self.disas_get_local(out, &mut opcodes)?,
+                opcodes::SET_LOCAL => self.disas_set_local(out, &mut opcodes)?,
+                opcodes::GET_GLOBAL => self.disas_get_global(out, &mut opcodes)?,
+                opcodes::DEFINE_GLOBAL => self.disas_define_global(out, &mut opcodes)?,
+                opcodes::SET_GLOBAL => self.disas_set_global(out, &mut opcodes)?,
+                opcodes::EQUAL => writeln!(out, "EQUAL")?,
+                opcodes::GREATER => writeln!(out, "GREATER")?,
+                opcodes::LESS => writeln!(out, "LESS")?,
+                opcodes::ADD => writeln!(out, "ADD")?,
+                opcodes::SUBTRACT => writeln!(out, "SUBTRACT")?,
+                opcodes::MULTIPLY => writeln!(out, "MULTIPLY")?,
+                opcodes::DIVIDE => writeln!(out, "DIVIDE")?,
+                opcodes::NOT => writeln!(out, "NOT")?,
+                opcodes::NEGATE => writeln!(out, "NEGATE")?,
+                opcodes::PRINT => writeln!(out, "PRINT")?,
+                opcodes::JUMP => self.disas_jump(out, "JUMP", true, offset, &mut opcodes)?,
+                opcodes::JUMP_IF_FALSE => {
+                    self.disas_jump(out, "JUMP IF FALSE", true, offset, &mut opcodes)?
+                }
+                opcodes::LOOP => self.disas_jump(out, "LOOP", false, offset, &mut opcodes)?,
+                opcodes::CALL => self.disas_call(out, &mut opcodes)?,
+                opcodes::GET_UPVALUE => self.disas_get_upvalue(out, &mut opcodes)?,
+                opcodes::SET_UPVALUE => self.disas_set_upvalue(out, &mut opcodes)?,
+                opcodes::CLOSURE => self.disas_closure(out, false, &mut opcodes)?,
+                opcodes::CLOSE_UPVALUE => writeln!(out, "CLOSE UPVALUE")?,
+                opcodes::CLASS => self.disas_named(out, "CLASS", &mut opcodes)?,
+                opcodes::METHOD => self.disas_named(out, "METHOD", &mut opcodes)?,
+                opcodes::GET_PROPERTY => self.disas_named(out, "GET PROPERTY", &mut opcodes)?,
+                opcodes::SET_PROPERTY => self.disas_named(out, "SET PROPERTY", &mut opcodes)?,
+                opcodes::INVOKE => self.disas_invoke(out, "INVOKE", false, &mut opcodes)?,
+                opcodes::INHERIT => writeln!(out, "INHERIT")?,
+                opcodes::GET_SUPER => self.disas_named(out, "GET SUPER", &mut opcodes)?,
+                opcodes::SUPER_INVOKE => {
+                    self.disas_invoke(out, "SUPER INVOKE", false, &mut opcodes)?
+                }
+
+                opcodes::CONSTANT_LONG => self.disas_constant_long(out, &mut opcodes)?,
+                opcodes::STRINGIFY => writeln!(out, "STRINGIFY")?,
+                opcodes::GET_LOCAL_LONG => writeln!(
+                    out,
+                    "GET LOCAL LONG    {}",
+                    Chunk::long_operand(&mut opcodes)
+                )?,
+                opcodes::SET_LOCAL_LONG => writeln!(
+                    out,
+                    "SET LOCAL LONG    {}",
+                    Chunk::long_operand(&mut opcodes)
+                )?,
+                opcodes::GET_GLOBAL_LONG => {
+                    self.disas_named_long(out, "GET GLOBAL LONG", &mut opcodes)?
+                }
+                opcodes::DEFINE_GLOBAL_LONG => {
+                    self.disas_named_long(out, "DEFINE GLOBAL LONG", &mut opcodes)?
+                }
+                opcodes::SET_GLOBAL_LONG => {
+                    self.disas_named_long(out, "SET GLOBAL LONG", &mut opcodes)?
+                }
+                opcodes::CLOSURE_LONG => self.disas_closure(out, true, &mut opcodes)?,
+                opcodes::NOT_EQUAL => writeln!(out, "NOT EQUAL")?,
+                opcodes::GREATER_EQUAL => writeln!(out, "GREATER EQUAL")?,
+                opcodes::LESS_EQUAL => writeln!(out, "LESS EQUAL")?,
+                opcodes::GET_PROPERTY_LONG => {
+                    self.disas_named_long(out, "GET PROPERTY LONG", &mut opcodes)?
+                }
+                opcodes::SET_PROPERTY_LONG => {
+                    self.disas_named_long(out, "SET PROPERTY LONG", &mut opcodes)?
+                }
+                opcodes::GET_SUPER_LONG => {
+                    self.disas_named_long(out, "GET SUPER LONG", &mut opcodes)?
+                }
+                opcodes::INVOKE_LONG => {
+                    self.disas_invoke(out, "INVOKE LONG", true, &mut opcodes)?
+                }
+                opcodes::SUPER_INVOKE_LONG => {
+                    self.disas_invoke(out, "SUPER INVOKE LONG", true, &mut opcodes)?
+                }
+                opcodes::CLASS_LONG => self.disas_named_long(out, "CLASS LONG", &mut opcodes)?,
+                opcodes::METHOD_LONG => self.disas_named_long(out, "METHOD LONG", &mut opcodes)?,
                 _ => unreachable!(),
             }
         }
+
+        Ok(())
     }
 
-    fn disas_constant(&self, code: &mut Enumerate<Iter<u8>>) {
+    fn disas_constant(
+        &self,
+        out: &mut dyn Write,
+        code: &mut Enumerate<Iter<u8>>,
+    ) -> io::Result<()> {
         if let Some((_, index)) = code.next() {
             let val = self.constants[*index as usize];
 
-            println!("CONSTANT    c[{}] = {}", index, val);
+            writeln!(out, "CONSTANT    c[{}] = {}", index, val)?;
         } else {
             panic!("COMPILER ERROR: constant is missing the index");
         }
+
+        Ok(())
+    }
+
+    fn disas_constant_long(
+        &self,
+        out: &mut dyn Write,
+        code: &mut Enumerate<Iter<u8>>,
+    ) -> io::Result<()> {
+        let index = Chunk::long_operand(code);
+        let val = self.constants[index];
+
+        writeln!(out, "CONSTANT_LONG    c[{}] = {}", index, val)?;
+
+        Ok(())
     }
 
-    fn disas_constant_long(&self, code: &mut Enumerate<Iter<u8>>) {
+    fn long_operand(code: &mut Enumerate<Iter<u8>>) -> usize {
         let mut bytes = [0; 4];
 
-        for i in 0..3 {
-            if let Some((_, index_byte)) = code.next() {
-                bytes[i] = *index_byte;
+        for byte in bytes.iter_mut().take(3) {
+            if let Some((_, operand_byte)) = code.next() {
+                *byte = *operand_byte;
             } else {
-                panic!("COMPILER ERROR: constant long is missing the index");
+                panic!("COMPILER ERROR: long operand is missing");
             }
         }
 
-        let index = u32::from_le_bytes(bytes) as usize;
-        let val = self.constants[index];
+        u32::from_le_bytes(bytes) as usize
+    }
+
+    fn disas_named_long(
+        &self,
+        out: &mut dyn Write,
+        name: &str,
+        code: &mut Enumerate<Iter<u8>>,
+    ) -> io::Result<()> {
+        let val = self.constants[Chunk::long_operand(code)];
+        writeln!(out, "{}    '{}'", name, val)?;
 
-        println!("CONSTANT_LONG    c[{}] = {}", index, val);
+        Ok(())
     }
 
-    fn disas_get_local(&self, code: &mut Enumerate<Iter<u8>>) {
+    fn disas_get_local(
+        &self,
+        out: &mut dyn Write,
+        code: &mut Enumerate<Iter<u8>>,
+    ) -> io::Result<()> {
         if let Some((_, index)) = code.next() {
-            println!("GET LOCAL    {}", index);
+            writeln!(out, "GET LOCAL    {}", index)?;
         } else {
             panic!("COMPILER ERROR: local variable expression operand missing");
         }
+
+        Ok(())
     }
 
-    fn disas_set_local(&self, code: &mut Enumerate<Iter<u8>>) {
+    fn disas_set_local(
+        &self,
+        out: &mut dyn Write,
+        code: &mut Enumerate<Iter<u8>>,
+    ) -> io::Result<()> {
         if let Some((_, index)) = code.next() {
-            println!("SET LOCAL    {}", index);
+            writeln!(out, "SET LOCAL    {}", index)?;
         } else {
             panic!("COMPILER ERROR: local variable expression operand missing");
         }
+
+        Ok(())
     }
 
-    fn disas_get_global(&self, code: &mut Enumerate<Iter<u8>>) {
+    fn disas_get_upvalue(
+        &self,
+        out: &mut dyn Write,
+        code: &mut Enumerate<Iter<u8>>,
+    ) -> io::Result<()> {
+        if let Some((_, index)) = code.next() {
+            writeln!(out, "GET UPVALUE    {}", index)?;
+        } else {
+            panic!("COMPILER ERROR: upvalue expression operand missing");
+        }
+
+        Ok(())
+    }
+
+    fn disas_set_upvalue(
+        &self,
+        out: &mut dyn Write,
+        code: &mut Enumerate<Iter<u8>>,
+    ) -> io::Result<()> {
+        if let Some((_, index)) = code.next() {
+            writeln!(out, "SET UPVALUE    {}", index)?;
+        } else {
+            panic!("COMPILER ERROR: upvalue expression operand missing");
+        }
+
+        Ok(())
+    }
+
+    fn disas_closure(
+        &self,
+        out: &mut dyn Write,
+        long: bool,
+        code: &mut Enumerate<Iter<u8>>,
+    ) -> io::Result<()> {
+        let index = if long {
+            Chunk::long_operand(code)
+        } else {
+            match code.next() {
+                Some((_, index)) => *index as usize,
+                None => panic!("COMPILER ERROR: closure is missing the function index"),
+            }
+        };
+
+        let function = match self.constants[index].unpack() {
+            RuntimeValue::Function(function) => function,
+            _ => panic!("COMPILER ERROR: closure constant isn't a function"),
+        };
+
+        writeln!(out, "CLOSURE    c[{}] = {}", index, self.constants[index])?;
+
+        for _ in 0..unsafe { (*function).upvalue_count } {
+            let (offset, kind) = match code.next() {
+                Some((offset, kind)) => (offset, *kind),
+                None => panic!("COMPILER ERROR: closure is missing captured variables"),
+            };
+
+            let index = if kind == LONG_LOCAL {
+                Chunk::long_operand(code)
+            } else {
+                match code.next() {
+                    Some((_, index)) => *index as usize,
+                    None => panic!("COMPILER ERROR: closure is missing captured variables"),
+                }
+            };
+
+            let kind = if kind == 0 { "upvalue" } else { "local" };
+            writeln!(out, "0x{:4X}        |     {} {}", offset, kind, index)?;
+        }
+
+        Ok(())
+    }
+
+    fn disas_get_global(
+        &self,
+        out: &mut dyn Write,
+        code: &mut Enumerate<Iter<u8>>,
+    ) -> io::Result<()> {
         if let Some((_, index)) = code.next() {
             let val = self.constants[*index as usize];
 
-            println!("GET GLOBAL    '{}'", val);
+            writeln!(out, "GET GLOBAL    '{}'", val)?;
         } else {
             panic!("COMPILER ERROR: global variable expression operand missing");
         }
+
+        Ok(())
     }
 
-    fn disas_define_global(&self, code: &mut Enumerate<Iter<u8>>) {
+    fn disas_define_global(
+        &self,
+        out: &mut dyn Write,
+        code: &mut Enumerate<Iter<u8>>,
+    ) -> io::Result<()> {
         if let Some((_, index)) = code.next() {
             let val = self.constants[*index as usize];
 
-            println!("DEFINE GLOBAL    '{}'", val);
+            writeln!(out, "DEFINE GLOBAL    '{}'", val)?;
         } else {
             panic!("COMPILER ERROR: global variable definition operand missing");
         }
+
+        Ok(())
     }
 
-    fn disas_set_global(&self, code: &mut Enumerate<Iter<u8>>) {
+    fn disas_set_global(
+        &self,
+        out: &mut dyn Write,
+        code: &mut Enumerate<Iter<u8>>,
+    ) -> io::Result<()> {
         if let Some((_, index)) = code.next() {
             let val = self.constants[*index as usize];
 
-            println!("SET GLOBAL    '{}'", val);
+            writeln!(out, "SET GLOBAL    '{}'", val)?;
         } else {
             panic!("COMPILER ERROR: global variable assignment operand missing");
         }
+
+        Ok(())
+    }
+
+    fn disas_call(&self, out: &mut dyn Write, code: &mut Enumerate<Iter<u8>>) -> io::Result<()> {
+        if let Some((_, arg_count)) = code.next() {
+            writeln!(out, "CALL    {} args", arg_count)?;
+        } else {
+            panic!("COMPILER ERROR: call is missing the argument count");
+        }
+
+        Ok(())
+    }
+
+    fn disas_named(
+        &self,
+        out: &mut dyn Write,
+        name: &str,
+        code: &mut Enumerate<Iter<u8>>,
+    ) -> io::Result<()> {
+        if let Some((_, index)) = code.next() {
+            let val = self.constants[*index as usize];
+
+            writeln!(out, "{}    '{}'", name, val)?;
+        } else {
+            panic!("COMPILER ERROR: {} is missing the name operand", name);
+        }
+
+        Ok(())
+    }
+
+    fn disas_invoke(
+        &self,
+        out: &mut dyn Write,
+        name: &str,
+        long: bool,
+        code: &mut Enumerate<Iter<u8>>,
+    ) -> io::Result<()> {
+        let index = if long {
+            Chunk::long_operand(code)
+        } else {
+            match code.next() {
+                Some((_, index)) => *index as usize,
+                None => panic!("COMPILER ERROR: {} is missing its operands", name),
+            }
+        };
+
+        match code.next() {
+            Some((_, arg_count)) => {
+                let val = self.constants[index];
+
+                writeln!(out, "{}    '{}' {} args", name, val, arg_count)?;
+            }
+            None => panic!("COMPILER ERROR: {} is missing its operands", name),
+        }
+
+        Ok(())
+    }
+
+    fn disas_jump(
+        &self,
+        out: &mut dyn Write,
+        name: &str,
+        forward: bool,
+        offset: usize,
+        code: &mut Enumerate<Iter<u8>>,
+    ) -> io::Result<()> {
+        let mut bytes = [0; 2];
+
+        for byte in bytes.iter_mut() {
+            if let Some((_, jump_byte)) = code.next() {
+                *byte = *jump_byte;
+            } else {
+                panic!("COMPILER ERROR: jump is missing the offset");
+            }
+        }
+
+        let jump = u16::from_le_bytes(bytes) as usize;
+        let target = if forward {
+            offset + 3 + jump
+        } else {
+            offset + 3 - jump
+        };
+
+        writeln!(out, "{}    0x{:4X}", name, target)?;
+
+        Ok(())
     }
 
     /*
-    Lines are encoded using run-length encoding.
-    Every tuple in Chunk.lines is a tuple of two entries.
-    The first one is the line number, the second one is the amount of
-    instructions ("Bytecodes") in that line.
+    Peephole optimizations, done instead of emitting the opcode:
+
+        CONSTANT a  CONSTANT b  ADD     →  CONSTANT a+b     (same for other arithmetic,
+                                                            comparisons and unary operators)
+        EQUAL NOT                       →  NOT_EQUAL
+        LESS NOT                        →  GREATER_EQUAL    (and the other way around,
+        GREATER NOT                     →  LESS_EQUAL        same for the other pairs)
+        NOT NOT NOT                     →  NOT
+
+    The fused comparisons negate the inverse comparison, so 'a >= b' keeps
+    evaluating to the same value as '!(a < b)', even for NaN.
+    Operations that would fail at runtime aren't folded, so the error stays.
     */
-    fn at_line(&mut self, line: usize, bytes: usize) {
-        if self.current_line == line {
-            // FIXME: fix subtract with overflow
-            self.lines[self.line_index - 1].1 += bytes;
-        } else {
-            self.current_line = line;
-            self.line_index += 1;
-            self.lines.push((line, bytes));
+    fn optimize_opcode(&mut self, opcode: Bytecode, span: Span) -> bool {
+        match opcode {
+            opcodes::NEGATE => self.fold_unary(opcode, span),
+            opcodes::NOT => self.fold_unary(opcode, span) || self.fuse_not(),
+            opcodes::ADD
+            | opcodes::SUBTRACT
+            | opcodes::MULTIPLY
+            | opcodes::DIVIDE
+            | opcodes::GREATER
+            | opcodes::LESS
+            | opcodes::EQUAL => self.fold_binary(opcode, span),
+            _ => false,
         }
     }
 
-    pub fn get_line_at_ip(&self, mut ip: usize) -> usize {
-        for l in &self.lines {
-            let len = l.1;
-            let res = ip.overflowing_sub(len);
+    fn fold_unary(&mut self, opcode: Bytecode, span: Span) -> bool {
+        let start = match self.recent.last() {
+            Some(start) => *start,
+            None => return false,
+        };
+
+        let result = match (opcode, self.constant_at(start)) {
+            (opcodes::NOT, Some(val)) => RuntimeValue::Bool(Vm::is_falsy(val)),
+            (opcodes::NEGATE, Some(RuntimeValue::Number(n))) => RuntimeValue::Number(-n),
+            _ => return false,
+        };
+
+        self.replace_with_constant(start, result, span)
+    }
+
+    fn fold_binary(&mut self, opcode: Bytecode, span: Span) -> bool {
+        let (first_start, second_start) = match self.recent[..] {
+            [.., first, second] => (first, second),
+            _ => return false,
+        };
+
+        let (first, second) = match (
+            self.constant_at(first_start),
+            self.constant_at(second_start),
+        ) {
+            (Some(first), Some(second)) => (first, second),
+            _ => return false,
+        };
+
+        let result = match (opcode, first, second) {
+            (opcodes::EQUAL, _, _) => RuntimeValue::Bool(Vm::values_equal(first, second)),
+            (_, RuntimeValue::Number(n1), RuntimeValue::Number(n2)) => match opcode {
+                opcodes::ADD => RuntimeValue::Number(n1 + n2),
+                opcodes::SUBTRACT => RuntimeValue::Number(n1 - n2),
+                opcodes::MULTIPLY => RuntimeValue::Number(n1 * n2),
+                opcodes::DIVIDE => RuntimeValue::Number(n1 / n2),
+                opcodes::GREATER => RuntimeValue::Bool(n1 > n2),
+                opcodes::LESS => RuntimeValue::Bool(n1 < n2),
+                _ => return false,
+            },
+            // Concatenating strings would need the heap
+            _ => return false,
+        };
+
+        self.replace_with_constant(first_start, result, span)
+    }
+
+    fn fuse_not(&mut self) -> bool {
+        let (before_last, last) = match self.recent[..] {
+            [.., before_last, last] => (Some(before_last), last),
+            [last] => (None, last),
+            _ => return false,
+        };
+
+        let fused = match self.code[last] {
+            opcodes::EQUAL => opcodes::NOT_EQUAL,
+            opcodes::NOT_EQUAL => opcodes::EQUAL,
+            opcodes::LESS => opcodes::GREATER_EQUAL,
+            opcodes::GREATER_EQUAL => opcodes::LESS,
+            opcodes::GREATER => opcodes::LESS_EQUAL,
+            opcodes::LESS_EQUAL => opcodes::GREATER,
+            // The result of the first NOT is already a bool, so the other two cancel out
+            opcodes::NOT if before_last.map(|i| self.code[i]) == Some(opcodes::NOT) => {
+                self.truncate(last);
+                return true;
+            }
+            _ => return false,
+        };
+
+        self.code[last] = fused;
+        true
+    }
+
+    /// Returns the value loaded by the instruction, if it loads a constant
+    fn constant_at(&self, offset: usize) -> Option<RuntimeValue> {
+        match self.code[offset] {
+            opcodes::NIL => Some(RuntimeValue::Nil),
+            opcodes::TRUE => Some(RuntimeValue::Bool(true)),
+            opcodes::FALSE => Some(RuntimeValue::Bool(false)),
+            opcodes::CONSTANT => {
+                let index = self.code[offset + 1] as usize;
+                Some(self.constants[index].unpack())
+            }
+            opcodes::CONSTANT_LONG => {
+                let bytes = [
+                    self.code[offset + 1],
+                    self.code[offset + 2],
+                    self.code[offset + 3],
+                    0,
+                ];
+                Some(self.constants[u32::from_le_bytes(bytes) as usize].unpack())
+            }
+            _ => None,
+        }
+    }
+
+    /// Replaces all code from the offset with an instruction loading the value
+    fn replace_with_constant(&mut self, offset: usize, val: RuntimeValue, span: Span) -> bool {
+        let opcode = match val {
+            RuntimeValue::Nil => opcodes::NIL,
+            RuntimeValue::Bool(true) => opcodes::TRUE,
+            RuntimeValue::Bool(false) => opcodes::FALSE,
+            _ => {
+                // Dropping the old constants first makes room for the result,
+                // keeping the original code is fine when there's still none
+                self.release_constants(offset);
+                let count = self.constants.len();
+                let index = match self.add_constant(val) {
+                    Ok(index) => index,
+                    Err(_) => return false,
+                };
+
+                self.truncate(offset);
+                self.emit_constant_load(index, index == count, span);
+                return true;
+            }
+        };
+
+        self.release_constants(offset);
+        self.truncate(offset);
+        self.code.push(opcode);
+        self.at_span(span, 1);
+        true
+    }
+
+    /// Drops the trailing constants added only for the code from the offset
+    fn release_constants(&mut self, offset: usize) {
+        while let Some(&(start, index)) = self.added_constants.last() {
+            if start < offset {
+                break;
+            }
+
+            self.added_constants.pop();
+            if index + 1 == self.constants.len() {
+                let val = self.constants.pop().unwrap().unpack();
+                if let Some(key) = Self::constant_key(val) {
+                    self.constant_indices.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Removes the code from the offset, along with its spans
+    fn truncate(&mut self, offset: usize) {
+        let mut removed = self.code.len() - offset;
+        self.code.truncate(offset);
+
+        while removed > 0 {
+            let (_, len) = self.spans.last_mut().unwrap();
+            if *len > removed {
+                *len -= removed;
+                removed = 0;
+            } else {
+                removed -= *len;
+                self.spans.pop();
+            }
+        }
+
+        while matches!(self.recent.last(), Some(start) if *start >= offset) {
+            self.recent.pop();
+        }
+    }
+
+    /*
+    Source spans are encoded using run-length encoding.
+    Every tuple in Chunk.spans is a tuple of two entries.
+    The first one is the span of the source code, the second one is the amount of
+    instructions ("Bytecodes") generated from that span.
+    */
+    fn at_span(&mut self, span: Span, bytes: usize) {
+        self.recent.push(self.code.len() - bytes);
+
+        match self.spans.last_mut() {
+            Some((last_span, len)) if *last_span == span => *len += bytes,
+            _ => self.spans.push((span, bytes)),
+        }
+    }
+
+    pub fn get_span_at_ip(&self, mut ip: usize) -> Span {
+        for (span, len) in &self.spans {
+            let res = ip.overflowing_sub(*len);
             ip = res.0;
             if res.1 {
-                return l.0;
-            } else {
-                continue;
+                return *span;
             }
         }
 
-        0
+        Span::default()
     }
 }
 
 pub type Bytecode = u8;
 
+// Largest index that fits into a long operand
+pub const MAX_LONG_OPERAND: usize = 0xFF_FFFF;
+
+// Marks a captured local whose index is a long operand, see Chunk::emit_closure
+pub const LONG_LOCAL: u8 = 2;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ChunkErr {
+    JumpTooLarge,
+    TooManyConstants,
+}
+
 pub(crate) mod opcodes {
     use super::Bytecode;
 
@@ -260,11 +957,11 @@ pub(crate) mod opcodes {
     pub const GET_GLOBAL: Bytecode = 7;
     pub const DEFINE_GLOBAL: Bytecode = 8;
     pub const SET_GLOBAL: Bytecode = 9;
-    //pub const GET_UPVALUE: Bytecode = 10;
-    //pub const SET_UPVALUE: Bytecode = 11;
-    //pub const GET_PROPERTY: Bytecode = 12;
-    //pub const SET_PROPERTY: Bytecode = 13;
-    //pub const GET_SUPER: Bytecode = 14;
+    pub const GET_UPVALUE: Bytecode = 10;
+    pub const SET_UPVALUE: Bytecode = 11;
+    pub const GET_PROPERTY: Bytecode = 12;
+    pub const SET_PROPERTY: Bytecode = 13;
+    pub const GET_SUPER: Bytecode = 14;
     pub const EQUAL: Bytecode = 15;
     pub const GREATER: Bytecode = 16;
     pub const LESS: Bytecode = 17;
@@ -275,18 +972,39 @@ pub(crate) mod opcodes {
     pub const NOT: Bytecode = 22;
     pub const NEGATE: Bytecode = 23;
     pub const PRINT: Bytecode = 24;
-    //pub const JUMP: Bytecode = 25;
-    //pub const JUMP_IF_FALSE: Bytecode = 26;
-    //pub const LOOP: Bytecode = 27;
-    //pub const CALL: Bytecode = 28;
-    //pub const INVOKE: Bytecode = 29;
-    //pub const SUPER_INVOKE: Bytecode = 30;
-    //pub const CLOSURE: Bytecode = 31;
-    //pub const CLOSE_UPVALUE: Bytecode = 32;
+    pub const JUMP: Bytecode = 25;
+    pub const JUMP_IF_FALSE: Bytecode = 26;
+    pub const LOOP: Bytecode = 27;
+    pub const CALL: Bytecode = 28;
+    pub const INVOKE: Bytecode = 29;
+    pub const SUPER_INVOKE: Bytecode = 30;
+    pub const CLOSURE: Bytecode = 31;
+    pub const CLOSE_UPVALUE: Bytecode = 32;
     pub const RETURN: Bytecode = 33;
-    //pub const CLASS: Bytecode = 34;
-    //pub const INHERIT: Bytecode = 35;
-    //pub const METHOD: Bytecode = 36;
+    pub const CLASS: Bytecode = 34;
+    pub const INHERIT: Bytecode = 35;
+    pub const METHOD: Bytecode = 36;
 
     pub const CONSTANT_LONG: Bytecode = 37;
+    // Converts the value on the top of the stack to a string, used by string interpolation
+    pub const STRINGIFY: Bytecode = 38;
+    // Same as the instructions without the suffix, with a long operand
+    pub const GET_LOCAL_LONG: Bytecode = 39;
+    pub const SET_LOCAL_LONG: Bytecode = 40;
+    pub const GET_GLOBAL_LONG: Bytecode = 41;
+    pub const DEFINE_GLOBAL_LONG: Bytecode = 42;
+    pub const SET_GLOBAL_LONG: Bytecode = 43;
+    pub const CLOSURE_LONG: Bytecode = 44;
+    // Fused comparisons, same as the inverse comparison followed by NOT
+    pub const NOT_EQUAL: Bytecode = 45;
+    pub const GREATER_EQUAL: Bytecode = 46;
+    pub const LESS_EQUAL: Bytecode = 47;
+    // Same as the instructions without the suffix, with a long name operand
+    pub const GET_PROPERTY_LONG: Bytecode = 48;
+    pub const SET_PROPERTY_LONG: Bytecode = 49;
+    pub const GET_SUPER_LONG: Bytecode = 50;
+    pub const INVOKE_LONG: Bytecode = 51;
+    pub const SUPER_INVOKE_LONG: Bytecode = 52;
+    pub const CLASS_LONG: Bytecode = 53;
+    pub const METHOD_LONG: Bytecode = 54;
 }
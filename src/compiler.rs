@@ -1,74 +1,128 @@
+use std::{mem, ptr};
+
 use crate::{
-    bytecode::{opcodes, Chunk},
-    lexer::{LexError, Lexer},
-    runtime_val::{RuntimeValue, StringObj},
-    token::{Token, TokenType},
+    bytecode::{opcodes, Bytecode, Chunk, ChunkErr, MAX_LONG_OPERAND},
+    diagnostic::Diagnostic,
+    heap::Heap,
+    lexer::{self, LexError, Lexer},
+    runtime_val::{FunctionObj, RuntimeValue},
+    table::Table,
+    token::{Span, Token, TokenType},
 };
 
 // TODO: challenge - better understand the Pratt parser
-// TODO: challenge - implement the ternary operator
 
 type CompileResult = Result<(), CompileErr>;
 
 pub struct Compiler<'t> {
-    //text: &'t str,
+    text: &'t str,
     lexer: Lexer<'t>,
     peeked_tok: Option<Token<'t>>,
+    // Location of the last consumed token
+    previous: Span,
+
+    heap: &'t mut Heap,
 
+    // State of the function that is currently being compiled
+    function_kind: FunctionKind,
     locals: Vec<Local<'t>>,
+    upvalues: Vec<Upvalue>,
     scope_depth: usize,
-
-    pub bytecode: Chunk,
-
-    last_error: CompileResult,
+    bytecode: Chunk,
+
+    // Saved states of the functions enclosing the current one
+    enclosing: Vec<FunctionState<'t>>,
+    // Class declarations enclosing the current code
+    classes: Vec<ClassState>,
+
+    diagnostics: Vec<Diagnostic>,
+
+    // Objects owned by someone else that must survive collections done while compiling
+    roots: Vec<RuntimeValue>,
+    root_tables: Vec<&'t Table>,
+    // Top-level expression statements print their value instead of discarding it
+    echo_expressions: bool,
+    // Constant folding and fusing of instructions, see Chunk::optimize_opcode
+    optimize: bool,
 }
 
 impl<'t> Compiler<'t> {
-    pub fn new(text: &str) -> Compiler {
+    pub fn new(text: &'t str, heap: &'t mut Heap) -> Compiler<'t> {
         Compiler {
-            //text,
+            text,
             lexer: Lexer::new(text),
             peeked_tok: None,
+            previous: Span::default(),
 
-            locals: Vec::new(),
-            scope_depth: 0,
+            heap,
 
+            function_kind: FunctionKind::Script,
+            // The first slot is reserved for the function being called
+            locals: vec![Local::new("", 0)],
+            upvalues: Vec::new(),
+            scope_depth: 0,
             bytecode: Chunk::new(),
 
-            last_error: Ok(()),
+            enclosing: Vec::new(),
+            classes: Vec::new(),
+
+            diagnostics: Vec::new(),
+
+            roots: Vec::new(),
+            root_tables: Vec::new(),
+            echo_expressions: false,
+            optimize: true,
         }
     }
 
-    pub fn dump_bytecode(&mut self) {
-        self.bytecode.disassemble()
+    /// Keeps the value alive during garbage collections done by the compiler
+    pub fn add_root(&mut self, value: RuntimeValue) {
+        self.roots.push(value);
+    }
+
+    /// Keeps all keys and values of the table alive during garbage collections done by the compiler
+    pub fn add_root_table(&mut self, table: &'t Table) {
+        self.root_tables.push(table);
+    }
+
+    /// Makes top-level expression statements print their value, used by the REPL
+    pub fn echo_expressions(&mut self) {
+        self.echo_expressions = true;
+    }
+
+    /// Emits the code exactly as written, without folding constants or fusing instructions
+    pub fn disable_optimizations(&mut self) {
+        self.optimize = false;
+        self.bytecode.disable_optimizations();
     }
 
     /*
         program        → declaration* EOF ;
     */
 
-    pub fn compile(&mut self) -> CompileResult {
+    /// Compiles the whole script, or returns all errors found in it
+    pub fn compile(&mut self) -> Result<*mut FunctionObj, Vec<Diagnostic>> {
         loop {
             match self.next_token() {
-                Err(e) => {
-                    self.last_error = Err(e);
-                    self.synchronize();
-                }
+                Err(_) => self.recover(0),
                 Ok(tok) => {
                     if tok.typ == TokenType::Eof {
                         break;
-                    } else {
-                        if let Err(e) = self.declaration(&tok) {
-                            self.last_error = Err(e);
-                            self.synchronize();
-                        }
+                    } else if self.declaration(&tok).is_err() {
+                        self.recover(0);
                     }
                 }
             }
         }
 
-        self.bytecode.emit_opcode(opcodes::RETURN, 0);
-        self.last_error
+        self.emit_return(self.previous);
+        if !self.diagnostics.is_empty() {
+            return Err(mem::take(&mut self.diagnostics));
+        }
+
+        let mut chunk = mem::replace(&mut self.bytecode, Chunk::new());
+        chunk.finish();
+        Ok(self.heap.new_function(0, 0, chunk, ptr::null_mut()))
     }
 
     /*
@@ -87,29 +141,229 @@ impl<'t> Compiler<'t> {
     */
 
     fn declaration(&mut self, next_tok: &Token) -> CompileResult {
+        // Between declarations, all objects created so far are constants
+        // of the compiled functions
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+
         match next_tok.typ {
-            TokenType::Class => self.class_declaration(),
-            TokenType::Fun => self.function_declaration(),
+            TokenType::Class => self.class_declaration(next_tok),
+            TokenType::Fun => self.function_declaration(next_tok),
             TokenType::Var => self.variable_declaration(),
-            _ => self.statement(&next_tok),
+            _ => self.statement(next_tok),
+        }
+    }
+
+    fn class_declaration(&mut self, class_tok: &Token) -> CompileResult {
+        let ident_tok = self.expect_token(
+            TokenType::Identifier,
+            "expected class name after 'class' keyword",
+        )?;
+
+        if self.scope_depth > 0 {
+            self.add_local(&ident_tok)?;
+        }
+
+        let name = self.heap.intern(ident_tok.lexeme);
+        let result =
+            self.bytecode
+                .emit_named(opcodes::CLASS, opcodes::CLASS_LONG, name, class_tok.span());
+        self.chunk_result(result, class_tok)?;
+
+        if self.scope_depth == 0 {
+            let result = self.bytecode.emit_declare_global(name, class_tok.span());
+            self.chunk_result(result, class_tok)?;
+        }
+
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+        let class = self.class(&ident_tok);
+        let class_state = self.classes.pop().unwrap();
+
+        if class_state.has_superclass {
+            // Discard the 'super' local even if the class body has errors
+            self.end_scope();
+        }
+
+        class?;
+        Ok(())
+    }
+
+    fn class(&mut self, ident_tok: &Token) -> CompileResult {
+        if self.peek_token().typ == TokenType::Less {
+            self.next_token().unwrap();
+            self.superclass(ident_tok)?;
+        }
+
+        // The class has to be on the stack while its methods are bound to it
+        self.variable(ident_tok, false)?;
+        self.class_body()?;
+        self.bytecode.emit_opcode(opcodes::POP, ident_tok.span());
+
+        Ok(())
+    }
+
+    fn superclass(&mut self, ident_tok: &Token) -> CompileResult {
+        let super_tok =
+            self.expect_token(TokenType::Identifier, "expected superclass name after '<'")?;
+
+        if super_tok.lexeme == ident_tok.lexeme {
+            return Err(self.error(
+                &super_tok,
+                CompileErr::InheritFromSelf,
+                format!("class '{}' can't inherit from itself", super_tok.lexeme),
+            ));
+        }
+
+        self.variable(&super_tok, false)?;
+
+        // The superclass is stored in a local scoped to the class body,
+        // so that methods can capture it as 'super'
+        self.begin_scope();
+        self.add_local(&Token::synthetic(TokenType::Super, "super", &super_tok))?;
+        self.classes.last_mut().unwrap().has_superclass = true;
+
+        self.variable(ident_tok, false)?;
+        self.bytecode
+            .emit_opcode(opcodes::INHERIT, super_tok.span());
+
+        Ok(())
+    }
+
+    fn class_body(&mut self) -> CompileResult {
+        self.expect_token(TokenType::LeftBrace, "expected '{' before class body")?;
+
+        loop {
+            match self.peek_token().typ {
+                TokenType::RightBrace => {
+                    self.next_token().unwrap();
+                    return Ok(());
+                }
+                TokenType::Eof => {
+                    let tok = *self.peek_token();
+                    return Err(self.error(
+                        &tok,
+                        CompileErr::UnclosedBlock,
+                        "expected a closing '}' after class body".to_string(),
+                    ));
+                }
+                _ => self.method()?,
+            }
+        }
+    }
+
+    fn method(&mut self) -> CompileResult {
+        let ident_tok = self.expect_token(TokenType::Identifier, "expected method name")?;
+
+        let kind = if ident_tok.lexeme == "init" {
+            FunctionKind::Initializer
+        } else {
+            FunctionKind::Method
+        };
+        self.function(kind, &ident_tok)?;
+
+        let name = self.heap.intern(ident_tok.lexeme);
+        let result = self.bytecode.emit_named(
+            opcodes::METHOD,
+            opcodes::METHOD_LONG,
+            name,
+            ident_tok.span(),
+        );
+        self.chunk_result(result, &ident_tok)?;
+
+        Ok(())
+    }
+
+    fn function_declaration(&mut self, fun_tok: &Token) -> CompileResult {
+        let ident_tok = self.expect_token(
+            TokenType::Identifier,
+            "expected function name after 'fun' keyword",
+        )?;
+
+        // Local functions are declared before compiling the body,
+        // so that they can refer to themselves recursively
+        if self.scope_depth > 0 {
+            self.add_local(&ident_tok)?;
+        }
+
+        self.function(FunctionKind::Function, &ident_tok)?;
+
+        if self.scope_depth == 0 {
+            let name = self.heap.intern(ident_tok.lexeme);
+            let result = self.bytecode.emit_declare_global(name, fun_tok.span());
+            self.chunk_result(result, fun_tok)?;
         }
+
+        Ok(())
     }
 
-    fn class_declaration(&mut self) -> CompileResult {
-        unimplemented!();
+    /// Compiles the parameters and body of a function
+    /// and emits a closure over the resulting function object
+    fn function(&mut self, kind: FunctionKind, name_tok: &Token<'t>) -> CompileResult {
+        self.begin_function(kind);
+
+        // The function state has to be restored even if the body has errors
+        let params = self.function_body();
+        let (chunk, upvalues) = self.end_function(name_tok.span());
+        let arity = params?;
+
+        let name = self.heap.intern(name_tok.lexeme);
+        let function = self.heap.new_function(arity, upvalues.len(), chunk, name);
+
+        let upvalues: Vec<(bool, usize)> = upvalues.iter().map(|u| (u.is_local, u.index)).collect();
+        let result = self
+            .bytecode
+            .emit_closure(function, &upvalues, name_tok.span());
+        self.chunk_result(result, name_tok)
     }
 
-    fn function_declaration(&mut self) -> CompileResult {
-        unimplemented!();
+    fn function_body(&mut self) -> Result<usize, CompileErr> {
+        self.begin_scope();
+
+        self.expect_token(TokenType::LeftParen, "expected '(' after function name")?;
+
+        let mut arity = 0;
+        if self.peek_token().typ != TokenType::RightParen {
+            loop {
+                let param_tok =
+                    self.expect_token(TokenType::Identifier, "expected parameter name")?;
+
+                arity += 1;
+                if arity > MAX_ARGS {
+                    return Err(self.error(
+                        &param_tok,
+                        CompileErr::TooManyArguments,
+                        format!("can't have more than {} parameters", MAX_ARGS),
+                    ));
+                }
+
+                self.add_local(&param_tok)?;
+
+                if self.peek_token().typ == TokenType::Comma {
+                    self.next_token().unwrap();
+                } else {
+                    break;
+                }
+            }
+        }
+
+        self.expect_token(TokenType::RightParen, "expected ')' after parameters")?;
+
+        self.expect_token(TokenType::LeftBrace, "expected '{' before function body")?;
+
+        // No need to end the scope, the locals are discarded with the call frame
+        self.block_stmt()?;
+
+        Ok(arity)
     }
 
     fn variable_declaration(&mut self) -> CompileResult {
-        let ident_tok = self.expect_token(TokenType::Identifier, |line, typ| {
-            eprintln!(
-                "Parse error at line {}: expected identifier after 'var' keyword, got '{:?}'",
-                line, typ
-            )
-        })?;
+        let ident_tok = self.expect_token(
+            TokenType::Identifier,
+            "expected identifier after 'var' keyword",
+        )?;
 
         let tok = self.peek_token();
         if let TokenType::Equal = tok.typ {
@@ -119,43 +373,57 @@ impl<'t> Compiler<'t> {
             let expr_tok = self.next_token()?;
             self.expression(&expr_tok)?;
         } else {
-            self.bytecode.emit_opcode(opcodes::NIL, ident_tok.line);
+            self.bytecode.emit_opcode(opcodes::NIL, ident_tok.span());
         }
 
-        self.expect_token(TokenType::Semicolon, |line, typ| {
-            eprintln!(
-                "Parse error at line {}: expected 'semicolon' after variable declaration, got: '{:?}'",
-                line, typ
-            )
-        })?;
+        self.expect_token(
+            TokenType::Semicolon,
+            "expected ';' after variable declaration",
+        )?;
 
         if self.scope_depth == 0 {
             // Only globals need explicit declaration
-            self.bytecode
-                .emit_declare_global(ident_tok.lexeme, ident_tok.line);
+            let name = self.heap.intern(ident_tok.lexeme);
+            let result = self.bytecode.emit_declare_global(name, ident_tok.span());
+            self.chunk_result(result, &ident_tok)?;
         } else {
-            if self.scope_depth > 255 {
-                // TODO: challenge - support more than 255 local variables
-                panic!("only 255 local variables are currently supported");
-            }
+            self.add_local(&ident_tok)?;
+        }
 
-            for l in self
-                .locals
-                .iter()
-                .rev()
-                .filter(|l| l.depth == self.scope_depth)
-            {
-                if l.name == ident_tok.lexeme {
-                    eprintln!("Parse error at line {}: local variable '{}' delcared multiple times in the same scope", ident_tok.line, ident_tok.lexeme);
+        Ok(())
+    }
 
-                    return Err(CompileErr::VariableRedeclaration);
-                }
-            }
+    fn add_local(&mut self, ident_tok: &Token<'t>) -> CompileResult {
+        // Slot indices have to fit into a long operand
+        if self.locals.len() > MAX_LONG_OPERAND {
+            return Err(self.error(
+                ident_tok,
+                CompileErr::TooManyLocals,
+                "too many local variables in function".to_string(),
+            ));
+        }
 
-            let local = Local::new(ident_tok.lexeme, self.scope_depth);
-            self.locals.push(local);
+        for l in self
+            .locals
+            .iter()
+            .rev()
+            .filter(|l| l.depth == self.scope_depth)
+        {
+            if l.name == ident_tok.lexeme {
+                return Err(self.error(
+                    ident_tok,
+                    CompileErr::VariableRedeclaration,
+                    format!(
+                        "local variable '{}' delcared multiple times in the same scope",
+                        ident_tok.lexeme
+                    ),
+                ));
+            }
         }
 
+        let local = Local::new(ident_tok.lexeme, self.scope_depth);
+        self.locals.push(local);
+
         Ok(())
     }
 
@@ -180,11 +448,11 @@ impl<'t> Compiler<'t> {
 
     fn statement(&mut self, tok: &Token) -> CompileResult {
         match tok.typ {
-            TokenType::For => self.for_stmt(),
-            TokenType::If => self.if_stmt(),
-            TokenType::Print => self.print_stmt(&tok),
-            TokenType::Return => self.return_stmt(),
-            TokenType::While => self.while_stmt(),
+            TokenType::For => self.for_stmt(tok),
+            TokenType::If => self.if_stmt(tok),
+            TokenType::Print => self.print_stmt(tok),
+            TokenType::Return => self.return_stmt(tok),
+            TokenType::While => self.while_stmt(tok),
             TokenType::LeftBrace => {
                 self.begin_scope();
                 self.block_stmt()?;
@@ -192,68 +460,209 @@ impl<'t> Compiler<'t> {
                 Ok(())
             }
             typ if Compiler::is_expr_start(typ) => self.expr_stmt(tok),
-            _ => {
-                eprintln!(
-                    "Parse error at line {}: expected declaration or statement, got: '{}'",
-                    tok.line, tok.lexeme
-                );
-                Err(CompileErr::ExpectedDeclOrStmt)
-            }
+            _ => Err(self.error(
+                tok,
+                CompileErr::ExpectedDeclOrStmt,
+                format!(
+                    "expected declaration or statement, got {}",
+                    Compiler::describe(tok)
+                ),
+            )),
         }
     }
 
     fn expr_stmt(&mut self, tok: &Token) -> CompileResult {
         self.expression(tok)?;
-        self.expect_token(TokenType::Semicolon, |line, typ| {
-            eprintln!(
-                "Parse error at line {}: expected 'semicolon' after expression, got: '{:?}'",
-                line, typ
-            )
-        })?;
-        self.bytecode.emit_opcode(opcodes::POP, tok.line);
+        self.expect_token(TokenType::Semicolon, "expected ';' after expression")?;
+
+        let top_level = self.enclosing.is_empty() && self.scope_depth == 0;
+        if self.echo_expressions && top_level {
+            self.bytecode.emit_opcode(opcodes::PRINT, tok.span());
+        } else {
+            self.bytecode.emit_opcode(opcodes::POP, tok.span());
+        }
         Ok(())
     }
 
-    fn for_stmt(&mut self) -> CompileResult {
-        unimplemented!()
+    fn for_stmt(&mut self, for_tok: &Token) -> CompileResult {
+        // The initializer variable is scoped to the loop
+        self.begin_scope();
+
+        self.expect_token(TokenType::LeftParen, "expected '(' after 'for'")?;
+
+        let initializer_tok = self.next_token()?;
+        match initializer_tok.typ {
+            TokenType::Semicolon => (),
+            TokenType::Var => self.variable_declaration()?,
+            _ => self.expr_stmt(&initializer_tok)?,
+        }
+
+        let mut loop_start = self.bytecode.jump_target();
+
+        let mut exit_jump = None;
+        if self.peek_token().typ == TokenType::Semicolon {
+            self.next_token().unwrap();
+        } else {
+            let condition_tok = self.next_token()?;
+            self.expression(&condition_tok)?;
+            self.expect_token(TokenType::Semicolon, "expected ';' after loop condition")?;
+
+            exit_jump = Some(self.emit_jump(opcodes::JUMP_IF_FALSE, for_tok.span()));
+            self.bytecode.emit_opcode(opcodes::POP, for_tok.span());
+        }
+
+        if self.peek_token().typ == TokenType::RightParen {
+            self.next_token().unwrap();
+        } else {
+            // The increment is compiled before the body, so jump over it
+            // and run it after the body by looping back to it
+            let body_jump = self.emit_jump(opcodes::JUMP, for_tok.span());
+            let increment_start = self.bytecode.jump_target();
+
+            let increment_tok = self.next_token()?;
+            self.expression(&increment_tok)?;
+            self.bytecode.emit_opcode(opcodes::POP, for_tok.span());
+            self.expect_token(TokenType::RightParen, "expected ')' after for clauses")?;
+
+            self.emit_loop(loop_start, for_tok)?;
+            loop_start = increment_start;
+            self.patch_jump(body_jump, for_tok)?;
+        }
+
+        let body_tok = self.next_token()?;
+        self.statement(&body_tok)?;
+        self.emit_loop(loop_start, for_tok)?;
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump, for_tok)?;
+            self.bytecode.emit_opcode(opcodes::POP, for_tok.span());
+        }
+
+        self.end_scope();
+        Ok(())
     }
 
-    fn if_stmt(&mut self) -> CompileResult {
-        unimplemented!()
+    fn if_stmt(&mut self, if_tok: &Token) -> CompileResult {
+        self.condition("if")?;
+
+        let then_jump = self.emit_jump(opcodes::JUMP_IF_FALSE, if_tok.span());
+        self.bytecode.emit_opcode(opcodes::POP, if_tok.span());
+
+        let then_tok = self.next_token()?;
+        self.statement(&then_tok)?;
+
+        let else_jump = self.emit_jump(opcodes::JUMP, if_tok.span());
+        self.patch_jump(then_jump, if_tok)?;
+        self.bytecode.emit_opcode(opcodes::POP, if_tok.span());
+
+        if self.peek_token().typ == TokenType::Else {
+            self.next_token().unwrap();
+
+            let else_tok = self.next_token()?;
+            self.statement(&else_tok)?;
+        }
+
+        self.patch_jump(else_jump, if_tok)?;
+        Ok(())
     }
 
     fn print_stmt(&mut self, print_tok: &Token) -> CompileResult {
         let first_expr_tok = self.next_token()?;
         self.expression(&first_expr_tok)?;
-        self.expect_token(TokenType::Semicolon, |line, typ| {
-            eprintln!(
-                "Parse error at line {}: expected 'semicolon' after expression, got: '{:?}'",
-                line, typ
-            )
-        })?;
-        self.bytecode.emit_opcode(opcodes::PRINT, print_tok.line);
+        self.expect_token(TokenType::Semicolon, "expected ';' after expression")?;
+        self.bytecode.emit_opcode(opcodes::PRINT, print_tok.span());
+        Ok(())
+    }
+
+    fn return_stmt(&mut self, return_tok: &Token) -> CompileResult {
+        if let FunctionKind::Script = self.function_kind {
+            return Err(self.error(
+                return_tok,
+                CompileErr::ReturnFromTopLevel,
+                "can't return from top-level code".to_string(),
+            ));
+        }
+
+        if self.peek_token().typ == TokenType::Semicolon {
+            self.next_token().unwrap();
+            self.emit_return(return_tok.span());
+        } else {
+            if let FunctionKind::Initializer = self.function_kind {
+                return Err(self.error(
+                    return_tok,
+                    CompileErr::ReturnFromInitializer,
+                    "can't return a value from an initializer".to_string(),
+                ));
+            }
+
+            let expr_tok = self.next_token()?;
+            self.expression(&expr_tok)?;
+            self.expect_token(TokenType::Semicolon, "expected ';' after return value")?;
+            self.bytecode
+                .emit_opcode(opcodes::RETURN, return_tok.span());
+        }
+
         Ok(())
     }
 
-    fn return_stmt(&mut self) -> CompileResult {
-        unimplemented!()
+    fn while_stmt(&mut self, while_tok: &Token) -> CompileResult {
+        let loop_start = self.bytecode.jump_target();
+        self.condition("while")?;
+
+        let exit_jump = self.emit_jump(opcodes::JUMP_IF_FALSE, while_tok.span());
+        self.bytecode.emit_opcode(opcodes::POP, while_tok.span());
+
+        let body_tok = self.next_token()?;
+        self.statement(&body_tok)?;
+        self.emit_loop(loop_start, while_tok)?;
+
+        self.patch_jump(exit_jump, while_tok)?;
+        self.bytecode.emit_opcode(opcodes::POP, while_tok.span());
+        Ok(())
     }
 
-    fn while_stmt(&mut self) -> CompileResult {
-        unimplemented!()
+    /// Parenthesized condition of 'if' and 'while' statements
+    fn condition(&mut self, keyword: &str) -> CompileResult {
+        self.expect_token(
+            TokenType::LeftParen,
+            &format!("expected '(' after '{}'", keyword),
+        )?;
+
+        let expr_tok = self.next_token()?;
+        self.expression(&expr_tok)?;
+
+        self.expect_token(TokenType::RightParen, "expected ')' after condition")?;
+
+        Ok(())
     }
 
     fn block_stmt(&mut self) -> CompileResult {
+        let scope_depth = self.scope_depth;
+
         loop {
-            let tok = self.next_token()?;
+            let tok = match self.next_token() {
+                Ok(tok) => tok,
+                Err(_) => {
+                    self.recover(scope_depth);
+                    continue;
+                }
+            };
+
             match tok.typ {
                 TokenType::RightBrace => return Ok(()),
                 TokenType::Eof => {
-                    eprintln!("Parse error at line {}: expected a closing '}}'", tok.line);
-
-                    return Err(CompileErr::UnclosedBlock);
+                    return Err(self.error(
+                        &tok,
+                        CompileErr::UnclosedBlock,
+                        "expected a closing '}'".to_string(),
+                    ));
+                }
+                _ => {
+                    // The error is already recorded, continue with the next statement
+                    if self.declaration(&tok).is_err() {
+                        self.recover(scope_depth);
+                    }
                 }
-                _ => self.declaration(&tok)?,
             }
         }
     }
@@ -283,12 +692,14 @@ impl<'t> Compiler<'t> {
             self.parse_precedence(parse_precedence::ASSIGNMENT, tok)?;
             Ok(())
         } else {
-            eprintln!(
-                "Parse error at line {}: expected start of expression, got '{:?}'",
-                tok.line, tok.typ
-            );
-
-            Err(CompileErr::ExpectedExpr)
+            Err(self.error(
+                tok,
+                CompileErr::ExpectedExpr,
+                format!(
+                    "expected start of expression, got {}",
+                    Compiler::describe(tok)
+                ),
+            ))
         }
     }
 
@@ -301,7 +712,7 @@ impl<'t> Compiler<'t> {
             if prec <= Compiler::precedence_rule(peeked.typ) {
                 // Here we can unwrap because we peeked already
                 let t = self.next_token().unwrap();
-                self.infix_rule(&t)?;
+                self.infix_rule(&t, is_assign_target)?;
             } else {
                 break;
             }
@@ -309,11 +720,11 @@ impl<'t> Compiler<'t> {
 
         // TODO: I don't understand this
         if is_assign_target && self.peek_token().typ == TokenType::Equal {
-            eprintln!(
-                "Parse error at line {}: invalid assignment target",
-                tok.line
-            );
-            return Err(CompileErr::InvalidAssignmentTarget);
+            return Err(self.error(
+                tok,
+                CompileErr::InvalidAssignmentTarget,
+                "invalid assignment target".to_string(),
+            ));
         }
 
         Ok(())
@@ -322,12 +733,10 @@ impl<'t> Compiler<'t> {
     fn grouping(&mut self) -> CompileResult {
         let next_tok = self.next_token()?;
         self.expression(&next_tok)?;
-        self.expect_token(TokenType::RightParen, |line, typ| {
-            eprintln!(
-                "Parse error at line {}: expected a matching right parentheses ')', got: '{:?}'",
-                line, typ
-            )
-        })?;
+        self.expect_token(
+            TokenType::RightParen,
+            "expected a matching right parentheses ')'",
+        )?;
         Ok(())
     }
 
@@ -336,24 +745,24 @@ impl<'t> Compiler<'t> {
         self.parse_precedence(Compiler::precedence_rule(tok.typ) + 1, &next_tok)?;
 
         match tok.typ {
-            TokenType::Plus => self.bytecode.emit_opcode(opcodes::ADD, tok.line),
-            TokenType::Minus => self.bytecode.emit_opcode(opcodes::SUBTRACT, tok.line),
-            TokenType::Star => self.bytecode.emit_opcode(opcodes::MULTIPLY, tok.line),
-            TokenType::Slash => self.bytecode.emit_opcode(opcodes::DIVIDE, tok.line),
+            TokenType::Plus => self.bytecode.emit_opcode(opcodes::ADD, tok.span()),
+            TokenType::Minus => self.bytecode.emit_opcode(opcodes::SUBTRACT, tok.span()),
+            TokenType::Star => self.bytecode.emit_opcode(opcodes::MULTIPLY, tok.span()),
+            TokenType::Slash => self.bytecode.emit_opcode(opcodes::DIVIDE, tok.span()),
             TokenType::BangEqual => {
-                self.bytecode.emit_opcode(opcodes::EQUAL, tok.line);
-                self.bytecode.emit_opcode(opcodes::NOT, tok.line);
+                self.bytecode.emit_opcode(opcodes::EQUAL, tok.span());
+                self.bytecode.emit_opcode(opcodes::NOT, tok.span());
             }
-            TokenType::EqualEqual => self.bytecode.emit_opcode(opcodes::EQUAL, tok.line),
-            TokenType::Greater => self.bytecode.emit_opcode(opcodes::GREATER, tok.line),
+            TokenType::EqualEqual => self.bytecode.emit_opcode(opcodes::EQUAL, tok.span()),
+            TokenType::Greater => self.bytecode.emit_opcode(opcodes::GREATER, tok.span()),
             TokenType::GreaterEqual => {
-                self.bytecode.emit_opcode(opcodes::LESS, tok.line);
-                self.bytecode.emit_opcode(opcodes::NOT, tok.line);
+                self.bytecode.emit_opcode(opcodes::LESS, tok.span());
+                self.bytecode.emit_opcode(opcodes::NOT, tok.span());
             }
-            TokenType::Less => self.bytecode.emit_opcode(opcodes::LESS, tok.line),
+            TokenType::Less => self.bytecode.emit_opcode(opcodes::LESS, tok.span()),
             TokenType::LessEqual => {
-                self.bytecode.emit_opcode(opcodes::GREATER, tok.line);
-                self.bytecode.emit_opcode(opcodes::NOT, tok.line);
+                self.bytecode.emit_opcode(opcodes::GREATER, tok.span());
+                self.bytecode.emit_opcode(opcodes::NOT, tok.span());
             }
             _ => unreachable!(),
         };
@@ -361,6 +770,206 @@ impl<'t> Compiler<'t> {
         Ok(())
     }
 
+    fn and(&mut self, tok: &Token) -> CompileResult {
+        // If the left operand is falsy, it's the result, skip the right one
+        let end_jump = self.emit_jump(opcodes::JUMP_IF_FALSE, tok.span());
+        self.bytecode.emit_opcode(opcodes::POP, tok.span());
+
+        let next_tok = self.next_token()?;
+        self.parse_precedence(parse_precedence::AND, &next_tok)?;
+
+        self.patch_jump(end_jump, tok)
+    }
+
+    fn or(&mut self, tok: &Token) -> CompileResult {
+        // If the left operand is truthy, it's the result, skip the right one
+        let else_jump = self.emit_jump(opcodes::JUMP_IF_FALSE, tok.span());
+        let end_jump = self.emit_jump(opcodes::JUMP, tok.span());
+
+        self.patch_jump(else_jump, tok)?;
+        self.bytecode.emit_opcode(opcodes::POP, tok.span());
+
+        let next_tok = self.next_token()?;
+        self.parse_precedence(parse_precedence::OR, &next_tok)?;
+
+        self.patch_jump(end_jump, tok)
+    }
+
+    /*
+        conditional    → logic_or ( "?" conditional ":" conditional )? ;
+    */
+
+    fn conditional(&mut self, tok: &Token) -> CompileResult {
+        // Only one of the branches is evaluated
+        let else_jump = self.emit_jump(opcodes::JUMP_IF_FALSE, tok.span());
+        self.bytecode.emit_opcode(opcodes::POP, tok.span());
+
+        let then_tok = self.next_token()?;
+        self.parse_precedence(parse_precedence::CONDITIONAL, &then_tok)?;
+        let colon_tok = self.expect_token(
+            TokenType::Colon,
+            "expected ':' after the first branch of a conditional expression",
+        )?;
+
+        let end_jump = self.emit_jump(opcodes::JUMP, colon_tok.span());
+        self.patch_jump(else_jump, tok)?;
+        self.bytecode.emit_opcode(opcodes::POP, tok.span());
+
+        // The same precedence makes it right-associative, 'a ? b : c ? d : e' nests to the right
+        let else_tok = self.next_token()?;
+        self.parse_precedence(parse_precedence::CONDITIONAL, &else_tok)?;
+
+        self.patch_jump(end_jump, &colon_tok)
+    }
+
+    fn call(&mut self, tok: &Token) -> CompileResult {
+        let arg_count = self.arguments()?;
+        self.bytecode.emit_call(arg_count, tok.span());
+        Ok(())
+    }
+
+    fn dot(&mut self, tok: &Token, is_assign_target: bool) -> CompileResult {
+        let ident_tok =
+            self.expect_token(TokenType::Identifier, "expected property name after '.'")?;
+        let name = self.heap.intern(ident_tok.lexeme);
+
+        let peeked = self.peek_token().typ;
+        if is_assign_target && peeked == TokenType::Equal {
+            self.next_token().unwrap();
+            let next_tok = self.next_token()?;
+            self.expression(&next_tok)?;
+
+            let result = self.bytecode.emit_named(
+                opcodes::SET_PROPERTY,
+                opcodes::SET_PROPERTY_LONG,
+                name,
+                tok.span(),
+            );
+            self.chunk_result(result, tok)?;
+        } else if peeked == TokenType::LeftParen {
+            // Method calls skip creating the bound method
+            self.next_token().unwrap();
+            let arg_count = self.arguments()?;
+            let result = self.bytecode.emit_invoke(
+                opcodes::INVOKE,
+                opcodes::INVOKE_LONG,
+                name,
+                arg_count,
+                tok.span(),
+            );
+            self.chunk_result(result, tok)?;
+        } else {
+            let result = self.bytecode.emit_named(
+                opcodes::GET_PROPERTY,
+                opcodes::GET_PROPERTY_LONG,
+                name,
+                tok.span(),
+            );
+            self.chunk_result(result, tok)?;
+        }
+
+        Ok(())
+    }
+
+    fn this(&mut self, tok: &Token) -> CompileResult {
+        if self.classes.is_empty() {
+            return Err(self.error(
+                tok,
+                CompileErr::ThisOutsideClass,
+                "can't use 'this' outside of a class".to_string(),
+            ));
+        }
+
+        // 'this' is the reserved first local of methods
+        self.variable(tok, false)
+    }
+
+    fn super_(&mut self, tok: &Token) -> CompileResult {
+        match self.classes.last() {
+            None => {
+                return Err(self.error(
+                    tok,
+                    CompileErr::SuperOutsideSubclass,
+                    "can't use 'super' outside of a class".to_string(),
+                ));
+            }
+            Some(ClassState {
+                has_superclass: false,
+            }) => {
+                return Err(self.error(
+                    tok,
+                    CompileErr::SuperOutsideSubclass,
+                    "can't use 'super' in a class with no superclass".to_string(),
+                ));
+            }
+            Some(_) => (),
+        }
+
+        self.expect_token(TokenType::Dot, "expected '.' after 'super'")?;
+        let ident_tok =
+            self.expect_token(TokenType::Identifier, "expected superclass method name")?;
+        let name = self.heap.intern(ident_tok.lexeme);
+
+        // The method is looked up on the superclass, but bound to 'this'
+        self.variable(&Token::synthetic(TokenType::This, "this", tok), false)?;
+
+        if self.peek_token().typ == TokenType::LeftParen {
+            self.next_token().unwrap();
+            let arg_count = self.arguments()?;
+            self.variable(tok, false)?;
+            let result = self.bytecode.emit_invoke(
+                opcodes::SUPER_INVOKE,
+                opcodes::SUPER_INVOKE_LONG,
+                name,
+                arg_count,
+                tok.span(),
+            );
+            self.chunk_result(result, tok)?;
+        } else {
+            self.variable(tok, false)?;
+            let result = self.bytecode.emit_named(
+                opcodes::GET_SUPER,
+                opcodes::GET_SUPER_LONG,
+                name,
+                tok.span(),
+            );
+            self.chunk_result(result, tok)?;
+        }
+
+        Ok(())
+    }
+
+    /// Compiles the argument list of a call after the opening '('
+    fn arguments(&mut self) -> Result<u8, CompileErr> {
+        let mut arg_count = 0;
+
+        if self.peek_token().typ != TokenType::RightParen {
+            loop {
+                let arg_tok = self.next_token()?;
+                self.expression(&arg_tok)?;
+
+                arg_count += 1;
+                if arg_count > MAX_ARGS {
+                    return Err(self.error(
+                        &arg_tok,
+                        CompileErr::TooManyArguments,
+                        format!("can't have more than {} arguments", MAX_ARGS),
+                    ));
+                }
+
+                if self.peek_token().typ == TokenType::Comma {
+                    self.next_token().unwrap();
+                } else {
+                    break;
+                }
+            }
+        }
+
+        self.expect_token(TokenType::RightParen, "expected ')' after arguments")?;
+
+        Ok(arg_count as u8)
+    }
+
     fn variable(&mut self, tok: &Token, is_assign_target: bool) -> CompileResult {
         // TODO: challenge 1 & 2 - optimize ways of accessing and defining GLOBAL variables
 
@@ -371,15 +980,23 @@ impl<'t> Compiler<'t> {
             let next_tok = self.next_token()?;
             self.expression(&next_tok)?;
 
-            match self.resolve_local(tok) {
-                Some(i) => self.bytecode.emit_set_local(i, tok.line),
-                None => self.bytecode.emit_set_global(tok.lexeme, tok.line),
+            if let Some(i) = self.resolve_local(tok) {
+                self.bytecode.emit_set_local(i, tok.span());
+            } else if let Some(i) = self.resolve_upvalue(tok, self.enclosing.len())? {
+                self.bytecode.emit_set_upvalue(i, tok.span());
+            } else {
+                let name = self.heap.intern(tok.lexeme);
+                let result = self.bytecode.emit_set_global(name, tok.span());
+                self.chunk_result(result, tok)?;
             }
+        } else if let Some(i) = self.resolve_local(tok) {
+            self.bytecode.emit_get_local(i, tok.span());
+        } else if let Some(i) = self.resolve_upvalue(tok, self.enclosing.len())? {
+            self.bytecode.emit_get_upvalue(i, tok.span());
         } else {
-            match self.resolve_local(tok) {
-                Some(i) => self.bytecode.emit_get_local(i, tok.line),
-                None => self.bytecode.emit_get_global(tok.lexeme, tok.line),
-            }
+            let name = self.heap.intern(tok.lexeme);
+            let result = self.bytecode.emit_get_global(name, tok.span());
+            self.chunk_result(result, tok)?;
         }
 
         Ok(())
@@ -392,8 +1009,8 @@ impl<'t> Compiler<'t> {
         self.parse_precedence(parse_precedence::UNARY, &next_tok)?;
 
         match operator_type {
-            TokenType::Minus => self.bytecode.emit_opcode(opcodes::NEGATE, tok.line),
-            TokenType::Bang => self.bytecode.emit_opcode(opcodes::NOT, tok.line),
+            TokenType::Minus => self.bytecode.emit_opcode(opcodes::NEGATE, tok.span()),
+            TokenType::Bang => self.bytecode.emit_opcode(opcodes::NOT, tok.span()),
             _ => unreachable!(),
         }
 
@@ -402,46 +1019,105 @@ impl<'t> Compiler<'t> {
 
     fn number(&mut self, tok: &Token) -> CompileResult {
         match tok.lexeme.parse::<f64>() {
-            Ok(num) => {
-                self.bytecode
-                    .emit_constant(RuntimeValue::Number(num), tok.line);
-                Ok(())
-            }
-            Err(_) => {
-                eprintln!(
-                    "Parse error at line {}: couldn't parse '{}' as a number",
-                    tok.line, tok.lexeme
-                );
-                Err(CompileErr::DoubleParse)
-            }
+            Ok(num) => self.emit_constant(RuntimeValue::Number(num), tok),
+            Err(_) => Err(self.error(
+                tok,
+                CompileErr::DoubleParse,
+                format!("couldn't parse '{}' as a number", tok.lexeme),
+            )),
         }
     }
 
     fn literal(&mut self, tok: &Token) {
         match tok.typ {
-            TokenType::Nil => self.bytecode.emit_opcode(opcodes::NIL, tok.line),
-            TokenType::True => self.bytecode.emit_opcode(opcodes::TRUE, tok.line),
-            TokenType::False => self.bytecode.emit_opcode(opcodes::FALSE, tok.line),
+            TokenType::Nil => self.bytecode.emit_opcode(opcodes::NIL, tok.span()),
+            TokenType::True => self.bytecode.emit_opcode(opcodes::TRUE, tok.span()),
+            TokenType::False => self.bytecode.emit_opcode(opcodes::FALSE, tok.span()),
             _ => unreachable!(),
         }
     }
 
-    fn string(&mut self, tok: &Token) {
+    fn string(&mut self, tok: &Token) -> CompileResult {
         // Srings should always start and end with a ", if not,
         // something has gone wrong in the lexer
         let slice = &tok.lexeme[1..tok.lexeme.len() - 1];
-        self.bytecode.emit_constant_string(slice, tok.line);
+        // Escape sequences were validated by the lexer
+        let contents = lexer::unescape(slice).unwrap_or_default();
+        let string_ptr = self.heap.intern(&contents);
+        self.emit_constant(RuntimeValue::String(string_ptr), tok)
     }
 
-    fn precedence_rule(typ: TokenType) -> ParsePrecedence {
-        match typ {
-            TokenType::Minus | TokenType::Plus => parse_precedence::TERM,
-            TokenType::Slash | TokenType::Star => parse_precedence::FACTOR,
-            TokenType::BangEqual | TokenType::EqualEqual => parse_precedence::EQUALITY,
+    /*
+        interpolation  → INTERPOLATION_START expression
+                         ( INTERPOLATION_MIDDLE expression )* INTERPOLATION_END ;
+    */
+
+    /// Concatenates the string parts and the stringified values of the expressions
+    fn interpolation(&mut self, start_tok: &Token) -> CompileResult {
+        self.string_part(start_tok, true)?;
+
+        loop {
+            let expr_tok = self.next_token()?;
+            self.expression(&expr_tok)?;
+            self.bytecode
+                .emit_opcode(opcodes::STRINGIFY, expr_tok.span());
+            self.bytecode.emit_opcode(opcodes::ADD, expr_tok.span());
+
+            let part_tok = *self.peek_token();
+            match part_tok.typ {
+                TokenType::InterpolationMiddle | TokenType::InterpolationEnd => {
+                    self.next_token()?;
+                    if self.string_part(&part_tok, false)? {
+                        self.bytecode.emit_opcode(opcodes::ADD, part_tok.span());
+                    }
+                    if part_tok.typ == TokenType::InterpolationEnd {
+                        return Ok(());
+                    }
+                }
+                _ => {
+                    let message = format!(
+                        "expected '}}' after interpolated expression, got {}",
+                        Compiler::describe(&part_tok)
+                    );
+                    return Err(self.error(&part_tok, CompileErr::UnexpectedToken, message));
+                }
+            }
+        }
+    }
+
+    /// Emits the text of a part of an interpolated string,
+    /// empty parts are skipped unless required. Returns true if anything was emitted.
+    fn string_part(&mut self, tok: &Token, required: bool) -> Result<bool, CompileErr> {
+        // Strips the '"' or '}' at the start and the '${' or '"' at the end
+        let end = match tok.typ {
+            TokenType::InterpolationEnd => tok.lexeme.len() - 1,
+            _ => tok.lexeme.len() - 2,
+        };
+        let slice = &tok.lexeme[1..end];
+        if slice.is_empty() && !required {
+            return Ok(false);
+        }
+
+        // Escape sequences were validated by the lexer
+        let contents = lexer::unescape(slice).unwrap_or_default();
+        let string_ptr = self.heap.intern(&contents);
+        self.emit_constant(RuntimeValue::String(string_ptr), tok)?;
+        Ok(true)
+    }
+
+    fn precedence_rule(typ: TokenType) -> ParsePrecedence {
+        match typ {
+            TokenType::Minus | TokenType::Plus => parse_precedence::TERM,
+            TokenType::Slash | TokenType::Star => parse_precedence::FACTOR,
+            TokenType::BangEqual | TokenType::EqualEqual => parse_precedence::EQUALITY,
             TokenType::Greater
             | TokenType::GreaterEqual
             | TokenType::Less
             | TokenType::LessEqual => parse_precedence::COMPARISON,
+            TokenType::And => parse_precedence::AND,
+            TokenType::Or => parse_precedence::OR,
+            TokenType::Question => parse_precedence::CONDITIONAL,
+            TokenType::LeftParen | TokenType::Dot => parse_precedence::CALL,
             _ => parse_precedence::NONE,
         }
     }
@@ -451,16 +1127,25 @@ impl<'t> Compiler<'t> {
             TokenType::Identifier => self.variable(tok, is_assign_target)?,
             TokenType::LeftParen => self.grouping()?,
             TokenType::Number => self.number(tok)?,
-            TokenType::String => self.string(tok),
+            TokenType::String => self.string(tok)?,
+            TokenType::InterpolationStart => self.interpolation(tok)?,
             TokenType::Minus | TokenType::Bang => self.unary(tok)?,
             TokenType::Nil | TokenType::False | TokenType::True => self.literal(tok),
-            _ => (),
+            TokenType::This => self.this(tok)?,
+            TokenType::Super => self.super_(tok)?,
+            _ => {
+                return Err(self.error(
+                    tok,
+                    CompileErr::ExpectedExpr,
+                    format!("expected expression, got {}", Compiler::describe(tok)),
+                ))
+            }
         };
 
         Ok(())
     }
 
-    fn infix_rule(&mut self, tok: &Token) -> CompileResult {
+    fn infix_rule(&mut self, tok: &Token, is_assign_target: bool) -> CompileResult {
         match tok.typ {
             TokenType::Minus
             | TokenType::Plus
@@ -474,16 +1159,34 @@ impl<'t> Compiler<'t> {
             | TokenType::LessEqual => {
                 self.binary(tok)?;
             }
+            TokenType::And => self.and(tok)?,
+            TokenType::Or => self.or(tok)?,
+            TokenType::Question => self.conditional(tok)?,
+            TokenType::LeftParen => self.call(tok)?,
+            TokenType::Dot => self.dot(tok, is_assign_target)?,
             _ => (),
         };
 
         Ok(())
     }
 
+    /// Skips to the next statement after an error, discarding the scopes
+    /// that the failed statement didn't close
+    fn recover(&mut self, scope_depth: usize) {
+        while self.scope_depth > scope_depth {
+            self.end_scope();
+        }
+        self.synchronize();
+    }
+
     fn synchronize(&mut self) {
         loop {
             let tok = self.peek_token();
             match tok.typ {
+                TokenType::Semicolon => {
+                    self.next_token().ok();
+                    break;
+                }
                 TokenType::Eof
                 | TokenType::Class
                 | TokenType::Fun
@@ -493,7 +1196,8 @@ impl<'t> Compiler<'t> {
                 | TokenType::While
                 | TokenType::Print
                 | TokenType::Return
-                | TokenType::LeftBrace => break,
+                | TokenType::LeftBrace
+                | TokenType::RightBrace => break,
                 _ => {
                     self.next_token().ok();
                 }
@@ -502,41 +1206,56 @@ impl<'t> Compiler<'t> {
     }
 
     fn is_expr_start(typ: TokenType) -> bool {
-        match typ {
+        matches!(
+            typ,
             TokenType::Bang
-            | TokenType::Minus
-            | TokenType::True
-            | TokenType::False
-            | TokenType::Nil
-            | TokenType::This
-            | TokenType::Number
-            | TokenType::String
-            | TokenType::Identifier
-            | TokenType::LeftParen
-            | TokenType::Super => true,
-            _ => false,
-        }
+                | TokenType::Minus
+                | TokenType::True
+                | TokenType::False
+                | TokenType::Nil
+                | TokenType::This
+                | TokenType::Number
+                | TokenType::String
+                | TokenType::InterpolationStart
+                | TokenType::Identifier
+                | TokenType::LeftParen
+                | TokenType::Super
+        )
     }
 
     fn next_token(&mut self) -> Result<Token<'t>, CompileErr> {
-        match self.peeked_tok.take() {
-            None => loop {
-                let next_tok = self.lexer.next_token();
-                match next_tok.typ {
-                    TokenType::Whitespace | TokenType::Newline | TokenType::Comment => continue,
-                    TokenType::Error(err) => {
-                        eprintln!("Lexing error at line {}: '{:?}'", next_tok.line, err);
+        self.peek_token();
+        let next_tok = self.peeked_tok.take().unwrap();
+        self.previous = next_tok.span();
 
-                        return Err(CompileErr::LexError);
+        match next_tok.typ {
+            TokenType::Error(LexError::InvalidEscape) => {
+                let body = &next_tok.lexeme[1..next_tok.lexeme.len() - 1];
+                // Points at the escape sequence, the quote is skipped
+                let range = lexer::unescape(body).unwrap_err();
+                let escape_tok = Token {
+                    offset: next_tok.offset + 1 + range.start,
+                    len: range.len(),
+                    ..next_tok
+                };
+                let message = format!("invalid escape sequence '{}'", &body[range]);
+                Err(self.error(&escape_tok, CompileErr::LexError, message))
+            }
+            TokenType::Error(err) => {
+                let message = match err {
+                    LexError::InvalidCharacter => {
+                        format!("invalid character '{}'", next_tok.lexeme)
                     }
-                    _ => return Ok(next_tok),
-                }
-            },
-            Some(t) => Ok(t),
+                    LexError::UnterminatedString => "unterminated string".to_string(),
+                    LexError::InvalidEscape => unreachable!(),
+                };
+                Err(self.error(&next_tok, CompileErr::LexError, message))
+            }
+            _ => Ok(next_tok),
         }
     }
 
-    fn peek_token(&mut self) -> &Token {
+    fn peek_token(&mut self) -> &Token<'t> {
         if self.peeked_tok.is_none() {
             loop {
                 let next_tok = self.lexer.next_token();
@@ -553,19 +1272,151 @@ impl<'t> Compiler<'t> {
         self.peeked_tok.as_ref().unwrap()
     }
 
-    fn expect_token<F: Fn(usize, TokenType)>(
+    /// Consumes the next token, 'expected' describes what should have been there.
+    /// An unexpected token isn't consumed, it may be the start of the statement
+    /// where compilation recovers.
+    fn expect_token(
         &mut self,
         expected_tok: TokenType,
-        error_msg: F,
+        expected: &str,
     ) -> Result<Token<'t>, CompileErr> {
-        let tok = self.next_token()?;
+        let tok = *self.peek_token();
+
+        if let TokenType::Error(_) = tok.typ {
+            // Reports the lexing error
+            self.next_token()?;
+        }
 
         if expected_tok != tok.typ {
-            error_msg(tok.line, tok.typ);
-            return Err(CompileErr::UnexpectedToken);
+            let message = format!("{}, got {}", expected, Compiler::describe(&tok));
+            return Err(self.error(&tok, CompileErr::UnexpectedToken, message));
+        }
+
+        self.next_token()
+    }
+
+    fn describe(tok: &Token) -> String {
+        match tok.typ {
+            TokenType::Eof => "end of file".to_string(),
+            _ => format!("'{}'", tok.lexeme),
+        }
+    }
+
+    /// Records a diagnostic pointing at the token
+    fn error(&mut self, tok: &Token, kind: CompileErr, message: String) -> CompileErr {
+        self.diagnostics.push(Diagnostic::new(
+            self.text, kind, message, tok.offset, tok.len,
+        ));
+        kind
+    }
+
+    /// Turns an error of the chunk into a diagnostic pointing at the token
+    fn chunk_result(&mut self, result: Result<(), ChunkErr>, tok: &Token) -> CompileResult {
+        result.map_err(|e| {
+            let message = match e {
+                ChunkErr::JumpTooLarge => "too much code to jump over",
+                ChunkErr::TooManyConstants => "too many constants in one function",
+            };
+            self.error(tok, CompileErr::from(e), message.to_string())
+        })
+    }
+
+    fn emit_constant(&mut self, val: RuntimeValue, tok: &Token) -> CompileResult {
+        let result = self.bytecode.emit_constant(val, tok.span());
+        self.chunk_result(result, tok)
+    }
+
+    fn emit_jump(&mut self, opcode: Bytecode, span: Span) -> usize {
+        self.bytecode.emit_jump(opcode, span)
+    }
+
+    fn patch_jump(&mut self, operand_pos: usize, tok: &Token) -> CompileResult {
+        match self.bytecode.patch_jump(operand_pos) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(self.error(
+                tok,
+                CompileErr::from(e),
+                "too much code to jump over".to_string(),
+            )),
         }
+    }
+
+    fn emit_loop(&mut self, loop_start: usize, tok: &Token) -> CompileResult {
+        match self.bytecode.emit_loop(loop_start, tok.span()) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(self.error(tok, CompileErr::from(e), "loop body too large".to_string())),
+        }
+    }
 
-        Ok(tok)
+    fn emit_return(&mut self, span: Span) {
+        if let FunctionKind::Initializer = self.function_kind {
+            // Initializers always return the new instance
+            self.bytecode.emit_get_local(0, span);
+        } else {
+            // Functions without an explicit return value return nil
+            self.bytecode.emit_opcode(opcodes::NIL, span);
+        }
+        self.bytecode.emit_opcode(opcodes::RETURN, span);
+    }
+
+    fn begin_function(&mut self, kind: FunctionKind) {
+        // Methods receive the instance in the reserved slot
+        let reserved = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => "this",
+            FunctionKind::Script | FunctionKind::Function => "",
+        };
+
+        let enclosing = FunctionState {
+            function_kind: mem::replace(&mut self.function_kind, kind),
+            locals: mem::replace(&mut self.locals, vec![Local::new(reserved, 0)]),
+            upvalues: mem::take(&mut self.upvalues),
+            scope_depth: mem::replace(&mut self.scope_depth, 0),
+            bytecode: mem::replace(&mut self.bytecode, Chunk::new()),
+        };
+        if !self.optimize {
+            self.bytecode.disable_optimizations();
+        }
+
+        self.enclosing.push(enclosing);
+    }
+
+    /// Restores the state of the enclosing function
+    /// and returns the chunk and captured variables of the finished one
+    fn end_function(&mut self, span: Span) -> (Chunk, Vec<Upvalue>) {
+        self.emit_return(span);
+
+        let enclosing = self.enclosing.pop().unwrap();
+        self.function_kind = enclosing.function_kind;
+        self.locals = enclosing.locals;
+        self.scope_depth = enclosing.scope_depth;
+
+        let upvalues = mem::replace(&mut self.upvalues, enclosing.upvalues);
+        let mut chunk = mem::replace(&mut self.bytecode, enclosing.bytecode);
+        chunk.finish();
+        (chunk, upvalues)
+    }
+
+    fn collect_garbage(&mut self) {
+        let chunks = self
+            .enclosing
+            .iter()
+            .map(|f| &f.bytecode)
+            .chain([&self.bytecode]);
+
+        for chunk in chunks {
+            for constant in &chunk.constants {
+                self.heap.mark_value(constant.unpack());
+            }
+        }
+
+        for root in &self.roots {
+            self.heap.mark_value(*root);
+        }
+        for table in &self.root_tables {
+            self.heap.mark_table(table);
+        }
+
+        self.heap.collect();
     }
 
     fn begin_scope(&mut self) {
@@ -582,34 +1433,132 @@ impl<'t> Compiler<'t> {
             .count();
 
         for _ in 0..c {
-            self.bytecode.emit_opcode(opcodes::POP, 0);
-            self.locals.pop();
+            let local = self.locals.pop().unwrap();
+            // Captured variables are moved off the stack into their upvalue
+            if local.is_captured {
+                self.bytecode
+                    .emit_opcode(opcodes::CLOSE_UPVALUE, self.previous);
+            } else {
+                self.bytecode.emit_opcode(opcodes::POP, self.previous);
+            }
         }
 
         self.scope_depth -= 1;
     }
 
     fn resolve_local(&mut self, tok: &Token) -> Option<usize> {
-        self.locals
+        Compiler::find_local(&self.locals, tok)
+    }
+
+    fn find_local(locals: &[Local], tok: &Token) -> Option<usize> {
+        locals
             .iter()
             .enumerate()
             .rev()
             .find(|l| l.1.name == tok.lexeme)
-            .and_then(|o| Some(o.0))
+            .map(|o| o.0)
+    }
+
+    /*
+    Functions are numbered by their nesting level, the top-level script is 0
+    and the currently compiled function is 'self.enclosing.len()'.
+    A variable is looked up in the locals of the directly enclosing function,
+    and then recursively in its upvalues, adding it as an upvalue to every
+    function on the way.
+    */
+    fn resolve_upvalue(&mut self, tok: &Token, level: usize) -> Result<Option<usize>, CompileErr> {
+        if level == 0 {
+            return Ok(None);
+        }
+
+        let enclosing = &mut self.enclosing[level - 1];
+        if let Some(index) = Compiler::find_local(&enclosing.locals, tok) {
+            enclosing.locals[index].is_captured = true;
+            return self.add_upvalue(tok, level, index, true).map(Some);
+        }
+
+        match self.resolve_upvalue(tok, level - 1)? {
+            Some(index) => self.add_upvalue(tok, level, index, false).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn add_upvalue(
+        &mut self,
+        tok: &Token,
+        level: usize,
+        index: usize,
+        is_local: bool,
+    ) -> Result<usize, CompileErr> {
+        let upvalues = if level == self.enclosing.len() {
+            &mut self.upvalues
+        } else {
+            &mut self.enclosing[level].upvalues
+        };
+
+        // A variable is captured only once by the same function
+        if let Some(i) = upvalues
+            .iter()
+            .position(|u| u.index == index && u.is_local == is_local)
+        {
+            return Ok(i);
+        }
+
+        if upvalues.len() > MAX_UPVALUES {
+            return Err(self.error(
+                tok,
+                CompileErr::TooManyUpvalues,
+                "too many closure variables in function".to_string(),
+            ));
+        }
+
+        upvalues.push(Upvalue { index, is_local });
+        Ok(upvalues.len() - 1)
     }
 }
 
+#[derive(Clone, Copy)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+struct FunctionState<'t> {
+    function_kind: FunctionKind,
+    locals: Vec<Local<'t>>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+    bytecode: Chunk,
+}
+
+struct ClassState {
+    has_superclass: bool,
+}
+
 struct Local<'n> {
     name: &'n str,
     depth: usize,
+    is_captured: bool,
 }
 
 impl<'n> Local<'n> {
-    pub fn new(name: &str, depth: usize) -> Local {
-        Local { name, depth }
+    pub fn new(name: &'n str, depth: usize) -> Local<'n> {
+        Local {
+            name,
+            depth,
+            is_captured: false,
+        }
     }
 }
 
+struct Upvalue {
+    // Index of the local or upvalue in the enclosing function
+    index: usize,
+    is_local: bool,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum CompileErr {
     ExpectedDeclOrStmt,
@@ -620,8 +1569,56 @@ pub enum CompileErr {
     InvalidAssignmentTarget,
     UnclosedBlock,
     VariableRedeclaration,
+    JumpTooLarge,
+    TooManyArguments,
+    TooManyUpvalues,
+    ReturnFromTopLevel,
+    ReturnFromInitializer,
+    ThisOutsideClass,
+    SuperOutsideSubclass,
+    InheritFromSelf,
+    TooManyConstants,
+    TooManyLocals,
 }
 
+impl CompileErr {
+    /// Error codes are part of the output format, never renumber them
+    pub fn code(&self) -> &'static str {
+        match self {
+            CompileErr::ExpectedDeclOrStmt => "E0001",
+            CompileErr::ExpectedExpr => "E0002",
+            CompileErr::UnexpectedToken => "E0003",
+            CompileErr::DoubleParse => "E0004",
+            CompileErr::LexError => "E0005",
+            CompileErr::InvalidAssignmentTarget => "E0006",
+            CompileErr::UnclosedBlock => "E0007",
+            CompileErr::VariableRedeclaration => "E0008",
+            CompileErr::JumpTooLarge => "E0009",
+            CompileErr::TooManyArguments => "E0010",
+            CompileErr::TooManyUpvalues => "E0011",
+            CompileErr::ReturnFromTopLevel => "E0012",
+            CompileErr::ReturnFromInitializer => "E0013",
+            CompileErr::ThisOutsideClass => "E0014",
+            CompileErr::SuperOutsideSubclass => "E0015",
+            CompileErr::InheritFromSelf => "E0016",
+            CompileErr::TooManyConstants => "E0017",
+            CompileErr::TooManyLocals => "E0018",
+        }
+    }
+}
+
+impl From<ChunkErr> for CompileErr {
+    fn from(err: ChunkErr) -> Self {
+        match err {
+            ChunkErr::JumpTooLarge => CompileErr::JumpTooLarge,
+            ChunkErr::TooManyConstants => CompileErr::TooManyConstants,
+        }
+    }
+}
+
+const MAX_ARGS: usize = 255;
+const MAX_UPVALUES: usize = 255;
+
 type ParsePrecedence = u8;
 
 mod parse_precedence {
@@ -629,236 +1626,1002 @@ mod parse_precedence {
 
     pub const NONE: ParsePrecedence = 0;
     pub const ASSIGNMENT: ParsePrecedence = 1;
-    pub const OR: ParsePrecedence = 2;
-    pub const AND: ParsePrecedence = 3;
-    pub const EQUALITY: ParsePrecedence = 4;
-    pub const COMPARISON: ParsePrecedence = 5;
-    pub const TERM: ParsePrecedence = 6;
-    pub const FACTOR: ParsePrecedence = 7;
-    pub const UNARY: ParsePrecedence = 8;
-    pub const CALL: ParsePrecedence = 9;
-    pub const PRIMARY: ParsePrecedence = 10;
+    pub const CONDITIONAL: ParsePrecedence = 2;
+    pub const OR: ParsePrecedence = 3;
+    pub const AND: ParsePrecedence = 4;
+    pub const EQUALITY: ParsePrecedence = 5;
+    pub const COMPARISON: ParsePrecedence = 6;
+    pub const TERM: ParsePrecedence = 7;
+    pub const FACTOR: ParsePrecedence = 8;
+    pub const UNARY: ParsePrecedence = 9;
+    pub const CALL: ParsePrecedence = 10;
+    // Nothing binds tighter than a primary expression, so it's never compared against
+    #[allow(dead_code)]
+    pub const PRIMARY: ParsePrecedence = 11;
 }
 
 #[cfg(test)]
 mod test {
+    use std::{mem, ops::Deref};
+
     use crate::{
-        bytecode::{opcodes::*, Chunk},
+        bytecode::{opcodes::*, Chunk, LONG_LOCAL},
         compiler::Compiler,
+        heap::Heap,
         runtime_val::RuntimeValue,
     };
 
-    fn get_chunk(text: &str) -> Chunk {
-        let mut compiler = Compiler::new(text);
-        compiler.compile();
-        compiler.bytecode
+    // The script's chunk together with the heap owning the objects in its constants
+    struct Compiled {
+        chunk: Chunk,
+        _heap: Heap,
+    }
+
+    impl Deref for Compiled {
+        type Target = Chunk;
+
+        fn deref(&self) -> &Chunk {
+            &self.chunk
+        }
+    }
+
+    // Most tests check the code as written, the optimizer has its own tests
+    fn get_chunk(text: &str) -> Compiled {
+        compile_chunk(text, false)
+    }
+
+    fn compile_chunk(text: &str, optimize: bool) -> Compiled {
+        let mut heap = Heap::new();
+        let mut compiler = Compiler::new(text, &mut heap);
+        if !optimize {
+            compiler.disable_optimizations();
+        }
+        let function = compiler.compile().expect("compile error");
+
+        // The function is freed together with the heap, so take its chunk out
+        let chunk = unsafe { mem::replace(&mut (*function).chunk, Chunk::new()) };
+        Compiled { chunk, _heap: heap }
+    }
+
+    fn assert_number_constants(bytecode: &Chunk, expected: &[f64]) {
+        assert_eq!(bytecode.constants.len(), expected.len());
+
+        for (constant, expected) in bytecode.constants.iter().zip(expected) {
+            match constant.unpack() {
+                RuntimeValue::Number(n) => assert_eq!(n, *expected),
+                _ => panic!("expected a number constant"),
+            }
+        }
     }
 
     #[test]
     fn arithmetic_expression_1() {
         let bytecode = get_chunk("1 + 2 * 5 - 6 / 3;");
 
-        let expected_opcodes = vec![
+        let expected_opcodes = [
             CONSTANT, 0, CONSTANT, 1, CONSTANT, 2, MULTIPLY, ADD, CONSTANT, 3, CONSTANT, 4, DIVIDE,
-            SUBTRACT, POP, RETURN,
+            SUBTRACT, POP, NIL, RETURN,
         ];
 
-        let expected_constants = vec![1.0, 2.0, 5.0, 6.0, 3.0];
+        let expected_constants = [1.0, 2.0, 5.0, 6.0, 3.0];
 
         assert_eq!(bytecode.code, expected_opcodes);
 
-        for i in 0..bytecode.constants.len() {
-            match bytecode.constants[i] {
-                RuntimeValue::Number(n) => assert_eq!(n, expected_constants[i]),
-                _ => assert!(false),
-            }
-        }
+        assert_number_constants(&bytecode, &expected_constants);
+    }
+
+    #[test]
+    fn instruction_spans() {
+        let text = "print 1 +\n  -2;";
+        let bytecode = get_chunk(text);
+
+        assert_eq!(
+            bytecode.code,
+            vec![CONSTANT, 0, CONSTANT, 1, NEGATE, ADD, PRINT, NIL, RETURN]
+        );
+
+        let source_at = |ip: usize| {
+            let span = bytecode.get_span_at_ip(ip);
+            (
+                &text[span.offset..span.offset + span.len],
+                span.line,
+                span.column,
+            )
+        };
+        assert_eq!(source_at(0), ("1", 1, 7));
+        assert_eq!(source_at(2), ("2", 2, 4));
+        assert_eq!(source_at(4), ("-", 2, 3));
+        assert_eq!(source_at(5), ("+", 1, 9));
+        assert_eq!(source_at(6), ("print", 1, 1));
+        // The implicit return is placed at the end of the script
+        assert_eq!(source_at(7), ("", 2, 6));
     }
 
     #[test]
     fn airthmetic_expression_2() {
         let bytecode = get_chunk("0.3 - 1.2 - 100.1;");
 
-        let expected_opcodes = vec![
-            CONSTANT, 0, CONSTANT, 1, SUBTRACT, CONSTANT, 2, SUBTRACT, POP, RETURN,
+        let expected_opcodes = [
+            CONSTANT, 0, CONSTANT, 1, SUBTRACT, CONSTANT, 2, SUBTRACT, POP, NIL, RETURN,
         ];
 
-        let expected_constants = vec![0.3, 1.2, 100.1];
+        let expected_constants = [0.3, 1.2, 100.1];
 
         assert_eq!(bytecode.code, expected_opcodes);
 
-        for i in 0..bytecode.constants.len() {
-            match bytecode.constants[i] {
-                RuntimeValue::Number(n) => assert_eq!(n, expected_constants[i]),
-                _ => assert!(false),
-            }
-        }
+        assert_number_constants(&bytecode, &expected_constants);
     }
 
     #[test]
     fn arithmetic_expression_3() {
         let bytecode = get_chunk("(1 + 2) * (5 - 6) / 3;");
 
-        let expected_opcodes = vec![
+        let expected_opcodes = [
             CONSTANT, 0, CONSTANT, 1, ADD, CONSTANT, 2, CONSTANT, 3, SUBTRACT, MULTIPLY, CONSTANT,
-            4, DIVIDE, POP, RETURN,
+            4, DIVIDE, POP, NIL, RETURN,
         ];
 
-        let expected_constants = vec![1.0, 2.0, 5.0, 6.0, 3.0];
+        let expected_constants = [1.0, 2.0, 5.0, 6.0, 3.0];
 
         assert_eq!(bytecode.code, expected_opcodes);
 
-        for i in 0..bytecode.constants.len() {
-            match bytecode.constants[i] {
-                RuntimeValue::Number(n) => assert_eq!(n, expected_constants[i]),
-                _ => assert!(false),
-            }
-        }
+        assert_number_constants(&bytecode, &expected_constants);
     }
 
     #[test]
     fn arithmetic_expression_4() {
         let bytecode = get_chunk("1 + 2 * 3 - 4 / -5;");
 
-        let expected_opcodes = vec![
+        let expected_opcodes = [
             CONSTANT, 0, CONSTANT, 1, CONSTANT, 2, MULTIPLY, ADD, CONSTANT, 3, CONSTANT, 4, NEGATE,
-            DIVIDE, SUBTRACT, POP, RETURN,
+            DIVIDE, SUBTRACT, POP, NIL, RETURN,
         ];
 
-        let expected_constants = vec![1.0, 2.0, 3.0, 4.0, 5.0];
+        let expected_constants = [1.0, 2.0, 3.0, 4.0, 5.0];
 
         assert_eq!(bytecode.code, expected_opcodes);
 
-        for i in 0..bytecode.constants.len() {
-            match bytecode.constants[i] {
-                RuntimeValue::Number(n) => assert_eq!(n, expected_constants[i]),
-                _ => assert!(false),
-            }
-        }
+        assert_number_constants(&bytecode, &expected_constants);
     }
 
     #[test]
     fn comparison_expression_1() {
         let bytecode = get_chunk("(25 * 8) > -63;");
 
-        let expected_opcodes = vec![
-            CONSTANT, 0, CONSTANT, 1, MULTIPLY, CONSTANT, 2, NEGATE, GREATER, POP, RETURN,
+        let expected_opcodes = [
+            CONSTANT, 0, CONSTANT, 1, MULTIPLY, CONSTANT, 2, NEGATE, GREATER, POP, NIL, RETURN,
         ];
 
-        let expected_constants = vec![25.0, 8.0, 63.0];
+        let expected_constants = [25.0, 8.0, 63.0];
 
         assert_eq!(bytecode.code, expected_opcodes);
 
-        for i in 0..bytecode.constants.len() {
-            match bytecode.constants[i] {
-                RuntimeValue::Number(n) => assert_eq!(n, expected_constants[i]),
-                _ => assert!(false),
-            }
-        }
+        assert_number_constants(&bytecode, &expected_constants);
     }
 
     #[test]
     fn comparison_expression_2() {
         let bytecode = get_chunk("---5 >= --2 * 8 - 50;");
 
-        let expected_opcodes = vec![
+        let expected_opcodes = [
             CONSTANT, 0, NEGATE, NEGATE, NEGATE, CONSTANT, 1, NEGATE, NEGATE, CONSTANT, 2,
-            MULTIPLY, CONSTANT, 3, SUBTRACT, LESS, NOT, POP, RETURN,
+            MULTIPLY, CONSTANT, 3, SUBTRACT, LESS, NOT, POP, NIL, RETURN,
         ];
 
-        let expected_constants = vec![5.0, 2.0, 8.0, 50.0];
+        let expected_constants = [5.0, 2.0, 8.0, 50.0];
 
         assert_eq!(bytecode.code, expected_opcodes);
 
-        for i in 0..bytecode.constants.len() {
-            match bytecode.constants[i] {
-                RuntimeValue::Number(n) => assert_eq!(n, expected_constants[i]),
-                _ => assert!(false),
-            }
-        }
+        assert_number_constants(&bytecode, &expected_constants);
     }
 
     #[test]
     fn comparison_expression_3() {
         let bytecode = get_chunk("2 - 2 - -2 / 2 < -63;");
 
-        let expected_opcodes = vec![
-            CONSTANT, 0, CONSTANT, 1, SUBTRACT, CONSTANT, 2, NEGATE, CONSTANT, 3, DIVIDE, SUBTRACT,
-            CONSTANT, 4, NEGATE, LESS, POP, RETURN,
+        let expected_opcodes = [
+            CONSTANT, 0, CONSTANT, 0, SUBTRACT, CONSTANT, 0, NEGATE, CONSTANT, 0, DIVIDE, SUBTRACT,
+            CONSTANT, 1, NEGATE, LESS, POP, NIL, RETURN,
         ];
 
-        let expected_constants = vec![2.0, 2.0, 2.0, 2.0, 63.0];
+        // Equal numbers share a constant
+        let expected_constants = [2.0, 63.0];
 
         assert_eq!(bytecode.code, expected_opcodes);
 
-        for i in 0..bytecode.constants.len() {
-            match bytecode.constants[i] {
-                RuntimeValue::Number(n) => assert_eq!(n, expected_constants[i]),
-                _ => assert!(false),
-            }
-        }
+        assert_number_constants(&bytecode, &expected_constants);
     }
 
     #[test]
     fn comparison_expression_4() {
         let bytecode = get_chunk("((((0)))) <= 10 * - 5 / 3;");
 
-        let expected_opcodes = vec![
+        let expected_opcodes = [
             CONSTANT, 0, CONSTANT, 1, CONSTANT, 2, NEGATE, MULTIPLY, CONSTANT, 3, DIVIDE, GREATER,
-            NOT, POP, RETURN,
+            NOT, POP, NIL, RETURN,
         ];
 
-        let expected_constants = vec![0.0, 10.0, 5.0, 3.0];
+        let expected_constants = [0.0, 10.0, 5.0, 3.0];
 
         assert_eq!(bytecode.code, expected_opcodes);
 
-        for i in 0..bytecode.constants.len() {
-            match bytecode.constants[i] {
-                RuntimeValue::Number(n) => assert_eq!(n, expected_constants[i]),
-                _ => assert!(false),
-            }
-        }
+        assert_number_constants(&bytecode, &expected_constants);
     }
 
     #[test]
     fn equality_expression_1() {
         let bytecode = get_chunk("0.5 * 10 - 3 < 5 == 50 >= 10;");
 
-        let expected_opcodes = vec![
+        let expected_opcodes = [
             CONSTANT, 0, CONSTANT, 1, MULTIPLY, CONSTANT, 2, SUBTRACT, CONSTANT, 3, LESS, CONSTANT,
-            4, CONSTANT, 5, LESS, NOT, EQUAL, POP, RETURN,
+            4, CONSTANT, 1, LESS, NOT, EQUAL, POP, NIL, RETURN,
         ];
 
-        let expected_constants = vec![0.5, 10.0, 3.0, 5.0, 50.0, 10.0];
+        let expected_constants = [0.5, 10.0, 3.0, 5.0, 50.0];
 
         assert_eq!(bytecode.code, expected_opcodes);
 
-        for i in 0..bytecode.constants.len() {
-            match bytecode.constants[i] {
-                RuntimeValue::Number(n) => assert_eq!(n, expected_constants[i]),
-                _ => assert!(false),
-            }
-        }
+        assert_number_constants(&bytecode, &expected_constants);
     }
 
     #[test]
     fn equality_expression_2() {
         let bytecode = get_chunk("(0 > 10 == true) != (50 >= 10 == true);");
 
-        let expected_opcodes = vec![
-            CONSTANT, 0, CONSTANT, 1, GREATER, TRUE, EQUAL, CONSTANT, 2, CONSTANT, 3, LESS, NOT,
-            TRUE, EQUAL, EQUAL, NOT, POP, RETURN,
+        let expected_opcodes = [
+            CONSTANT, 0, CONSTANT, 1, GREATER, TRUE, EQUAL, CONSTANT, 2, CONSTANT, 1, LESS, NOT,
+            TRUE, EQUAL, EQUAL, NOT, POP, NIL, RETURN,
+        ];
+
+        let expected_constants = [0.0, 10.0, 50.0];
+
+        assert_eq!(bytecode.code, expected_opcodes);
+
+        assert_number_constants(&bytecode, &expected_constants);
+    }
+
+    #[test]
+    fn conditional_expression() {
+        let bytecode = get_chunk("a ? 1 : b ? 2 : 3;");
+
+        let expected_opcodes = [
+            GET_GLOBAL,
+            0,
+            JUMP_IF_FALSE,
+            6,
+            0,
+            POP,
+            CONSTANT,
+            1,
+            JUMP,
+            15,
+            0,
+            POP,
+            GET_GLOBAL,
+            2,
+            JUMP_IF_FALSE,
+            6,
+            0,
+            POP,
+            CONSTANT,
+            3,
+            JUMP,
+            3,
+            0,
+            POP,
+            CONSTANT,
+            4,
+            POP,
+            NIL,
+            RETURN,
+        ];
+
+        assert_eq!(bytecode.code, expected_opcodes);
+    }
+
+    #[test]
+    fn conditional_precedence() {
+        let mut heap = Heap::new();
+        let text = "var x = 1 or 2 ? 3 : 4;\nprint 1 ? 2;\nprint 1 ? 2 : 3 = 4;";
+        let diagnostics = Compiler::new(text, &mut heap)
+            .compile()
+            .expect_err("expected compile errors");
+
+        let messages: Vec<&str> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "expected ':' after the first branch of a conditional expression, got ';'",
+                "invalid assignment target",
+            ]
+        );
+    }
+
+    #[test]
+    fn wide_operands() {
+        let mut text = String::new();
+        for i in 0..300 {
+            text.push_str(&format!("var g{} = nil;\n", i));
+        }
+        text.push_str("g299 = g298;");
+
+        let bytecode = get_chunk(&text);
+        // The 256th global is the first one with a long operand
+        assert_eq!(&bytecode.code[765..769], &[NIL, DEFINE_GLOBAL, 255, NIL]);
+        assert_eq!(&bytecode.code[769..773], &[DEFINE_GLOBAL_LONG, 0, 1, 0]);
+        assert_eq!(
+            &bytecode.code[bytecode.code.len() - 11..],
+            &[
+                GET_GLOBAL_LONG,
+                42,
+                1,
+                0,
+                SET_GLOBAL_LONG,
+                43,
+                1,
+                0,
+                POP,
+                NIL,
+                RETURN
+            ]
+        );
+
+        let mut text = String::from("fun f() {\n");
+        for i in 1..300 {
+            text.push_str(&format!("  var l{} = nil;\n", i));
+        }
+        text.push_str("  l299 = l1;\n  fun g() { return l299; }\n}");
+
+        let bytecode = get_chunk(&text);
+        let f = match bytecode.constants[0].unpack() {
+            RuntimeValue::Function(function) => function,
+            _ => panic!("expected a function constant"),
+        };
+
+        unsafe {
+            let code = &(*f).chunk.code;
+            assert_eq!(
+                &code[code.len() - 15..],
+                &[
+                    GET_LOCAL,
+                    1,
+                    SET_LOCAL_LONG,
+                    43,
+                    1,
+                    0,
+                    POP,
+                    CLOSURE,
+                    0,
+                    LONG_LOCAL,
+                    43,
+                    1,
+                    0,
+                    NIL,
+                    RETURN
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn constant_deduplication() {
+        let bytecode = get_chunk(
+            "var count = 0;\nwhile (count < 10) count = count + 1;\nprint \"a\" + \"a\" + count;",
+        );
+
+        let constants: Vec<String> = bytecode.constants.iter().map(|c| c.to_string()).collect();
+        assert_eq!(constants, vec!["0", "count", "10", "1", "a"]);
+    }
+
+    #[test]
+    fn wide_names() {
+        let mut text = String::new();
+        for i in 0..300 {
+            text.push_str(&format!("var g{} = nil;\n", i));
+        }
+        text.push_str("g0.field = g0.field;\ng0.method();");
+
+        let bytecode = get_chunk(&text);
+        assert_eq!(
+            &bytecode.code[bytecode.code.len() - 23..],
+            &[
+                GET_GLOBAL,
+                0,
+                GET_GLOBAL,
+                0,
+                GET_PROPERTY_LONG,
+                44,
+                1,
+                0,
+                SET_PROPERTY_LONG,
+                44,
+                1,
+                0,
+                POP,
+                GET_GLOBAL,
+                0,
+                INVOKE_LONG,
+                45,
+                1,
+                0,
+                0,
+                POP,
+                NIL,
+                RETURN,
+            ]
+        );
+    }
+
+    #[test]
+    fn logical_expression_1() {
+        let bytecode = get_chunk("1 < 2 and 3 > 4;");
+
+        let expected_opcodes = [
+            CONSTANT,
+            0,
+            CONSTANT,
+            1,
+            LESS,
+            JUMP_IF_FALSE,
+            6,
+            0,
+            POP,
+            CONSTANT,
+            2,
+            CONSTANT,
+            3,
+            GREATER,
+            POP,
+            NIL,
+            RETURN,
+        ];
+
+        let expected_constants = [1.0, 2.0, 3.0, 4.0];
+
+        assert_eq!(bytecode.code, expected_opcodes);
+
+        assert_number_constants(&bytecode, &expected_constants);
+    }
+
+    #[test]
+    fn logical_expression_2() {
+        let bytecode = get_chunk("false or 1 == 2;");
+
+        let expected_opcodes = [
+            FALSE,
+            JUMP_IF_FALSE,
+            3,
+            0,
+            JUMP,
+            6,
+            0,
+            POP,
+            CONSTANT,
+            0,
+            CONSTANT,
+            1,
+            EQUAL,
+            POP,
+            NIL,
+            RETURN,
+        ];
+
+        let expected_constants = [1.0, 2.0];
+
+        assert_eq!(bytecode.code, expected_opcodes);
+
+        assert_number_constants(&bytecode, &expected_constants);
+    }
+
+    #[test]
+    fn logical_expression_3() {
+        let bytecode = get_chunk("1 == 1 or 2 == 2 and false;");
+
+        let expected_opcodes = [
+            CONSTANT,
+            0,
+            CONSTANT,
+            0,
+            EQUAL,
+            JUMP_IF_FALSE,
+            3,
+            0,
+            JUMP,
+            11,
+            0,
+            POP,
+            CONSTANT,
+            1,
+            CONSTANT,
+            1,
+            EQUAL,
+            JUMP_IF_FALSE,
+            2,
+            0,
+            POP,
+            FALSE,
+            POP,
+            NIL,
+            RETURN,
+        ];
+
+        let expected_constants = [1.0, 2.0];
+
+        assert_eq!(bytecode.code, expected_opcodes);
+
+        assert_number_constants(&bytecode, &expected_constants);
+    }
+
+    #[test]
+    fn constant_folding() {
+        let text = "1 + 2 * 5 - 6 / 3; !nil == (1 < 2); 2 + 2; 9 + 1;";
+        let bytecode = compile_chunk(text, true);
+
+        let expected_opcodes = [
+            CONSTANT, 0, POP, TRUE, POP, CONSTANT, 1, POP, CONSTANT, 2, POP, NIL, RETURN,
+        ];
+        assert_eq!(bytecode.code, expected_opcodes);
+
+        // 9 is still loaded by the first statement, so folding 9 + 1 keeps it
+        assert_number_constants(&bytecode, &[9.0, 4.0, 10.0]);
+    }
+
+    #[test]
+    fn no_folding_of_runtime_errors_and_strings() {
+        let bytecode = compile_chunk("1 + nil; -\"a\"; \"a\" + \"b\";", true);
+
+        let expected_opcodes = [
+            CONSTANT, 0, NIL, ADD, POP, CONSTANT, 1, NEGATE, POP, CONSTANT, 1, CONSTANT, 2, ADD,
+            POP, NIL, RETURN,
+        ];
+        assert_eq!(bytecode.code, expected_opcodes);
+    }
+
+    #[test]
+    fn fused_comparisons() {
+        let text = "a != b; a >= b; a <= b; !(a == b); !(a < b); !!!a; !!a;";
+
+        let expected_opcodes = [
+            GET_GLOBAL,
+            0,
+            GET_GLOBAL,
+            1,
+            NOT_EQUAL,
+            POP,
+            GET_GLOBAL,
+            0,
+            GET_GLOBAL,
+            1,
+            GREATER_EQUAL,
+            POP,
+            GET_GLOBAL,
+            0,
+            GET_GLOBAL,
+            1,
+            LESS_EQUAL,
+            POP,
+            GET_GLOBAL,
+            0,
+            GET_GLOBAL,
+            1,
+            NOT_EQUAL,
+            POP,
+            GET_GLOBAL,
+            0,
+            GET_GLOBAL,
+            1,
+            GREATER_EQUAL,
+            POP,
+            GET_GLOBAL,
+            0,
+            NOT,
+            POP,
+            GET_GLOBAL,
+            0,
+            NOT,
+            NOT,
+            POP,
+            NIL,
+            RETURN,
+        ];
+        assert_eq!(compile_chunk(text, true).code, expected_opcodes);
+
+        let expected_opcodes = [
+            GET_GLOBAL, 0, GET_GLOBAL, 1, EQUAL, NOT, POP, GET_GLOBAL, 0, GET_GLOBAL, 1, LESS, NOT,
+            POP, GET_GLOBAL, 0, GET_GLOBAL, 1, GREATER, NOT, POP, GET_GLOBAL, 0, GET_GLOBAL, 1,
+            EQUAL, NOT, POP, GET_GLOBAL, 0, GET_GLOBAL, 1, LESS, NOT, POP, GET_GLOBAL, 0, NOT, NOT,
+            NOT, POP, GET_GLOBAL, 0, NOT, NOT, POP, NIL, RETURN,
+        ];
+        assert_eq!(compile_chunk(text, false).code, expected_opcodes);
+    }
+
+    #[test]
+    fn no_optimization_across_jump_targets() {
+        let bytecode = compile_chunk("!(a or b == c); (a and 1) + 2;", true);
+
+        let expected_opcodes = [
+            GET_GLOBAL,
+            0,
+            JUMP_IF_FALSE,
+            3,
+            0,
+            JUMP,
+            6,
+            0,
+            POP,
+            GET_GLOBAL,
+            1,
+            GET_GLOBAL,
+            2,
+            EQUAL,
+            NOT,
+            POP,
+            GET_GLOBAL,
+            0,
+            JUMP_IF_FALSE,
+            3,
+            0,
+            POP,
+            CONSTANT,
+            3,
+            CONSTANT,
+            4,
+            ADD,
+            POP,
+            NIL,
+            RETURN,
         ];
+        assert_eq!(bytecode.code, expected_opcodes);
+    }
 
-        let expected_constants = vec![0.0, 10.0, 50.0, 10.0];
+    #[test]
+    fn if_else_statement() {
+        let bytecode = get_chunk("if (true) print 1; else print 2;");
+
+        let expected_opcodes = [
+            TRUE,
+            JUMP_IF_FALSE,
+            7,
+            0,
+            POP,
+            CONSTANT,
+            0,
+            PRINT,
+            JUMP,
+            4,
+            0,
+            POP,
+            CONSTANT,
+            1,
+            PRINT,
+            NIL,
+            RETURN,
+        ];
 
         assert_eq!(bytecode.code, expected_opcodes);
+    }
+
+    #[test]
+    fn while_statement() {
+        let bytecode = get_chunk("while (false) print 1;");
+
+        let expected_opcodes = [
+            FALSE,
+            JUMP_IF_FALSE,
+            7,
+            0,
+            POP,
+            CONSTANT,
+            0,
+            PRINT,
+            LOOP,
+            11,
+            0,
+            POP,
+            NIL,
+            RETURN,
+        ];
+
+        assert_eq!(bytecode.code, expected_opcodes);
+    }
+
+    #[test]
+    fn function_declaration() {
+        let mut heap = Heap::new();
+        let mut compiler = Compiler::new("fun f(a) { return a; } f(1);", &mut heap);
+        let script = compiler.compile().expect("compile error");
+        let bytecode = unsafe { &(*script).chunk };
+
+        let expected_opcodes = [
+            CLOSURE,
+            0,
+            DEFINE_GLOBAL,
+            1,
+            GET_GLOBAL,
+            1,
+            CONSTANT,
+            2,
+            CALL,
+            1,
+            POP,
+            NIL,
+            RETURN,
+        ];
+
+        assert_eq!(bytecode.code, expected_opcodes);
+
+        match bytecode.constants[0].unpack() {
+            RuntimeValue::Function(function) => unsafe {
+                assert_eq!((*function).arity, 1);
+                assert_eq!(
+                    (*function).chunk.code,
+                    vec![GET_LOCAL, 1, RETURN, NIL, RETURN]
+                );
+            },
+            _ => panic!("expected a function constant"),
+        }
+    }
+
+    #[test]
+    fn closure_upvalues() {
+        let mut heap = Heap::new();
+        let mut compiler = Compiler::new(
+            "fun outer() { var x = 1; fun inner() { return x; } }",
+            &mut heap,
+        );
+        let script = compiler.compile().expect("compile error");
+        let bytecode = unsafe { &(*script).chunk };
+
+        assert_eq!(
+            bytecode.code,
+            vec![CLOSURE, 0, DEFINE_GLOBAL, 1, NIL, RETURN]
+        );
+
+        let outer = match bytecode.constants[0].unpack() {
+            RuntimeValue::Function(function) => function,
+            _ => panic!("expected a function constant"),
+        };
 
-        for i in 0..bytecode.constants.len() {
-            match bytecode.constants[i] {
-                RuntimeValue::Number(n) => assert_eq!(n, expected_constants[i]),
-                _ => assert!(false),
+        unsafe {
+            // 'x' is captured as the first local of 'outer'
+            assert_eq!(
+                (*outer).chunk.code,
+                vec![CONSTANT, 0, CLOSURE, 1, 1, 1, NIL, RETURN]
+            );
+
+            match (&(*outer).chunk.constants)[1].unpack() {
+                RuntimeValue::Function(inner) => {
+                    assert_eq!((*inner).upvalue_count, 1);
+                    assert_eq!(
+                        (*inner).chunk.code,
+                        vec![GET_UPVALUE, 0, RETURN, NIL, RETURN]
+                    );
+                }
+                _ => panic!("expected a function constant"),
             }
         }
     }
+
+    #[test]
+    fn class_declaration() {
+        let mut heap = Heap::new();
+        let mut compiler = Compiler::new(
+            "class A { init() {} m() { return this.x; } } A().m();",
+            &mut heap,
+        );
+        let script = compiler.compile().expect("compile error");
+        let bytecode = unsafe { &(*script).chunk };
+
+        let expected_opcodes = [
+            CLASS,
+            0,
+            DEFINE_GLOBAL,
+            0,
+            GET_GLOBAL,
+            0,
+            CLOSURE,
+            1,
+            METHOD,
+            2,
+            CLOSURE,
+            3,
+            METHOD,
+            4,
+            POP,
+            GET_GLOBAL,
+            0,
+            CALL,
+            0,
+            INVOKE,
+            4,
+            0,
+            POP,
+            NIL,
+            RETURN,
+        ];
+
+        assert_eq!(bytecode.code, expected_opcodes);
+
+        let method_code = |index: usize| match bytecode.constants[index].unpack() {
+            RuntimeValue::Function(function) => unsafe { (*function).chunk.code.clone() },
+            _ => panic!("expected a function constant"),
+        };
+
+        // Initializers implicitly return 'this'
+        assert_eq!(method_code(1), vec![GET_LOCAL, 0, RETURN]);
+        assert_eq!(
+            method_code(3),
+            vec![GET_LOCAL, 0, GET_PROPERTY, 0, RETURN, NIL, RETURN]
+        );
+    }
+
+    #[test]
+    fn this_outside_class() {
+        let mut heap = Heap::new();
+
+        let mut compiler = Compiler::new("fun f() { return this; }", &mut heap);
+        assert!(compiler.compile().is_err());
+
+        let mut compiler = Compiler::new("class A { init() { return 1; } }", &mut heap);
+        assert!(compiler.compile().is_err());
+    }
+
+    #[test]
+    fn superclass() {
+        let mut heap = Heap::new();
+        let mut compiler = Compiler::new(
+            "class A {} class B < A { m() { return super.m(); } }",
+            &mut heap,
+        );
+        let script = compiler.compile().expect("compile error");
+        let bytecode = unsafe { &(*script).chunk };
+
+        let expected_opcodes = [
+            CLASS,
+            0,
+            DEFINE_GLOBAL,
+            0,
+            GET_GLOBAL,
+            0,
+            POP,
+            CLASS,
+            1,
+            DEFINE_GLOBAL,
+            1,
+            GET_GLOBAL,
+            0,
+            GET_GLOBAL,
+            1,
+            INHERIT,
+            GET_GLOBAL,
+            1,
+            CLOSURE,
+            2,
+            1,
+            1,
+            METHOD,
+            3,
+            POP,
+            CLOSE_UPVALUE,
+            NIL,
+            RETURN,
+        ];
+
+        assert_eq!(bytecode.code, expected_opcodes);
+
+        // 'super' is captured from the local holding the superclass
+        match bytecode.constants[2].unpack() {
+            RuntimeValue::Function(function) => unsafe {
+                assert_eq!(
+                    (*function).chunk.code,
+                    vec![
+                        GET_LOCAL,
+                        0,
+                        GET_UPVALUE,
+                        0,
+                        SUPER_INVOKE,
+                        0,
+                        0,
+                        RETURN,
+                        NIL,
+                        RETURN
+                    ]
+                );
+            },
+            _ => panic!("expected a function constant"),
+        }
+    }
+
+    #[test]
+    fn superclass_errors() {
+        let mut heap = Heap::new();
+
+        let mut compiler = Compiler::new("class A < A {}", &mut heap);
+        assert!(compiler.compile().is_err());
+
+        let mut compiler = Compiler::new("class A { m() { super.m(); } }", &mut heap);
+        assert!(compiler.compile().is_err());
+
+        let mut compiler = Compiler::new("fun f() { super.m(); }", &mut heap);
+        assert!(compiler.compile().is_err());
+    }
+
+    #[test]
+    fn string_escapes() {
+        let bytecode = get_chunk(r#"print "a\tb\n\"\u{10D}\"";"#);
+
+        match bytecode.constants[0].unpack() {
+            RuntimeValue::String(s) => unsafe { assert_eq!((*s).as_str(), "a\tb\n\"č\"") },
+            _ => panic!("expected a string constant"),
+        }
+
+        let mut heap = Heap::new();
+        let text = "print \"č \\x\";";
+        let diagnostics = Compiler::new(text, &mut heap)
+            .compile()
+            .expect_err("expected compile errors");
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "invalid escape sequence '\\x'");
+        assert_eq!(&text[diagnostics[0].span.clone()], "\\x");
+        assert_eq!(diagnostics[0].column, 10);
+    }
+
+    #[test]
+    fn unterminated_string() {
+        let mut heap = Heap::new();
+
+        for (text, lexeme, column) in [("print \"", "\"", 7), ("print \"abc", "\"abc", 7)].iter() {
+            let diagnostics = Compiler::new(text, &mut heap)
+                .compile()
+                .expect_err("expected compile errors");
+
+            assert_eq!(diagnostics.len(), 1);
+            assert_eq!(diagnostics[0].message, "unterminated string");
+            assert_eq!(&text[diagnostics[0].span.clone()], *lexeme);
+            assert_eq!(diagnostics[0].column, *column);
+        }
+    }
+
+    #[test]
+    fn interpolation() {
+        let bytecode = get_chunk(r#"print "${a}, ${b}!";"#);
+
+        let expected_opcodes = [
+            CONSTANT, 0, GET_GLOBAL, 1, STRINGIFY, ADD, CONSTANT, 2, ADD, GET_GLOBAL, 3, STRINGIFY,
+            ADD, CONSTANT, 4, ADD, PRINT, NIL, RETURN,
+        ];
+        assert_eq!(bytecode.code, expected_opcodes);
+
+        let strings: Vec<&str> = [0, 2, 4]
+            .iter()
+            .map(|&i| match bytecode.constants[i].unpack() {
+                RuntimeValue::String(s) => unsafe { (*s).as_str() },
+                _ => panic!("expected a string constant"),
+            })
+            .collect();
+        assert_eq!(strings, vec!["", ", ", "!"]);
+    }
+
+    #[test]
+    fn all_diagnostics() {
+        let mut heap = Heap::new();
+        let text = "var a = 1\nprint a;\nfun f() {\n  return 1 +;\n}\nprint @;";
+        let mut compiler = Compiler::new(text, &mut heap);
+
+        let diagnostics = compiler.compile().expect_err("expected compile errors");
+        let summary: Vec<(&str, usize, usize, &str)> = diagnostics
+            .iter()
+            .map(|d| (d.code(), d.line, d.column, &text[d.span.clone()]))
+            .collect();
+
+        assert_eq!(
+            summary,
+            vec![
+                ("E0003", 2, 1, "print"),
+                ("E0002", 4, 13, ";"),
+                ("E0005", 6, 7, "@"),
+            ]
+        );
+    }
 }
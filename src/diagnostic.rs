@@ -0,0 +1,129 @@
+use std::ops::Range;
+
+use crate::compiler::CompileErr;
+
+/*
+A compile error together with its location in the source text.
+The span is a range of byte offsets, the line and column are 1-based
+and the column is counted in characters, not bytes.
+*/
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub kind: CompileErr,
+    pub message: String,
+    pub span: Range<usize>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Diagnostic {
+    pub fn new(
+        source: &str,
+        kind: CompileErr,
+        message: String,
+        start: usize,
+        len: usize,
+    ) -> Diagnostic {
+        let line_start = Diagnostic::line_start(source, start);
+        let line = source[..start].matches('\n').count() + 1;
+        let column = source[line_start..start].chars().count() + 1;
+
+        Diagnostic {
+            kind,
+            message,
+            span: start..start + len,
+            line,
+            column,
+        }
+    }
+
+    /// Stable identifier of the kind of the error
+    pub fn code(&self) -> &'static str {
+        self.kind.code()
+    }
+
+    /// Formats the diagnostic with the offending source line and a caret
+    /// underline below the span, for example:
+    ///
+    /// error[E0003]: expected ';' after expression, got 'b'
+    ///  --> line 1, column 9
+    ///   |
+    /// 1 | print a b;
+    ///   |         ^
+    pub fn render(&self, source: &str) -> String {
+        let line_start = Diagnostic::line_start(source, self.span.start);
+        let line_end = source[self.span.start..]
+            .find('\n')
+            .map_or(source.len(), |i| self.span.start + i);
+        let source_line = &source[line_start..line_end];
+
+        // Keep tabs in the indentation, so that the caret lines up
+        let indent: String = source[line_start..self.span.start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        // Spans of multi-line tokens are cut at the end of the first line
+        let underlined = &source[self.span.start..self.span.end.min(line_end)];
+        let carets = "^".repeat(underlined.chars().count().max(1));
+
+        let gutter = " ".repeat(self.line.to_string().len());
+
+        format!(
+            "error[{}]: {}\n{} --> line {}, column {}\n{} |\n{} | {}\n{} | {}{}",
+            self.code(),
+            self.message,
+            gutter,
+            self.line,
+            self.column,
+            gutter,
+            self.line,
+            source_line.trim_end_matches('\r'),
+            gutter,
+            indent,
+            carets
+        )
+    }
+
+    fn line_start(source: &str, offset: usize) -> usize {
+        source[..offset].rfind('\n').map_or(0, |i| i + 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{compiler::CompileErr, diagnostic::Diagnostic};
+
+    #[test]
+    fn render() {
+        let source = "var a = 1;\nprint a b;\n";
+        let diagnostic = Diagnostic::new(
+            source,
+            CompileErr::UnexpectedToken,
+            "expected ';' after expression, got 'b'".to_string(),
+            19,
+            1,
+        );
+
+        assert_eq!(diagnostic.column, 9);
+        assert_eq!(
+            diagnostic.render(source),
+            "error[E0003]: expected ';' after expression, got 'b'\n  --> line 2, column 9\n  |\n2 | print a b;\n  |         ^"
+        );
+    }
+
+    #[test]
+    fn column_counts_characters() {
+        let source = "print \"čšž\" x;";
+        let diagnostic = Diagnostic::new(
+            source,
+            CompileErr::UnexpectedToken,
+            String::new(),
+            source.find('x').unwrap(),
+            1,
+        );
+
+        assert_eq!(diagnostic.column, 13);
+        assert!(diagnostic.render(source).ends_with("|             ^"));
+    }
+}
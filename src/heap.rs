@@ -0,0 +1,427 @@
+use std::{mem::size_of, ptr};
+
+use crate::{
+    bytecode::Chunk,
+    packed_val::PackedValue,
+    runtime_val::{
+        BoundMethodObj, ClassObj, ClosureObj, FunctionObj, InstanceObj, NativeFn, NativeObj, Obj,
+        ObjTyp, RuntimeValue, StringObj, UpvalueObj,
+    },
+    table::{hash_str, Table},
+};
+
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const FIRST_GC: usize = 1024 * 1024;
+
+/*
+The heap owns every runtime object.
+Objects are linked into an intrusive list through their 'next_obj' pointers,
+and all strings are interned in the 'strings' table, so two strings with
+the same contents are always the same object.
+
+Unreachable objects are freed by a mark-and-sweep garbage collector.
+The heap doesn't know the roots, so collection is driven by its users:
+they check 'should_collect()' at points where all live objects are reachable,
+mark their roots and then call 'collect()', which traces the rest of the
+object graph and sweeps the object list.
+*/
+pub struct Heap {
+    objects: *mut Obj,
+    strings: Table,
+
+    // Marked objects whose references haven't been traced yet
+    gray: Vec<*mut Obj>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    // Collect as often as possible, for shaking out GC bugs
+    stress_gc: bool,
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            objects: ptr::null_mut(),
+            strings: Table::new(),
+
+            gray: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: FIRST_GC,
+            stress_gc: false,
+        }
+    }
+
+    pub fn set_stress_gc(&mut self, stress_gc: bool) {
+        self.stress_gc = stress_gc;
+    }
+
+    #[inline]
+    pub fn should_collect(&self) -> bool {
+        self.stress_gc || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn mark_value(&mut self, value: RuntimeValue) {
+        if let Some(obj) = value.as_obj() {
+            self.mark_object(obj);
+        }
+    }
+
+    pub fn mark_object(&mut self, obj: *mut Obj) {
+        if obj.is_null() {
+            return;
+        }
+
+        unsafe {
+            if (*obj).is_marked {
+                return;
+            }
+            (*obj).is_marked = true;
+        }
+
+        self.gray.push(obj);
+    }
+
+    pub fn mark_table(&mut self, table: &Table) {
+        for (key, value) in table.iter() {
+            self.mark_object(key as *mut Obj);
+            self.mark_value(value);
+        }
+    }
+
+    /// Frees all objects that weren't reached from the marked roots
+    pub fn collect(&mut self) {
+        while let Some(obj) = self.gray.pop() {
+            unsafe { self.blacken(obj) };
+        }
+
+        // Interned strings are weak references
+        self.strings.remove_unmarked();
+        self.sweep();
+
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(FIRST_GC);
+    }
+
+    /// Marks all objects referenced by an already marked object
+    unsafe fn blacken(&mut self, obj: *mut Obj) {
+        match (*obj).typ {
+            ObjTyp::String => (),
+            ObjTyp::Function => {
+                let function = obj as *mut FunctionObj;
+                self.mark_object((*function).name as *mut Obj);
+                for constant in &(*function).chunk.constants {
+                    self.mark_value(constant.unpack());
+                }
+            }
+            ObjTyp::Closure => {
+                let closure = obj as *mut ClosureObj;
+                self.mark_object((*closure).function as *mut Obj);
+                for upvalue in &(*closure).upvalues {
+                    self.mark_object(*upvalue as *mut Obj);
+                }
+            }
+            ObjTyp::Upvalue => {
+                // Open upvalues refer to the stack, which is a root anyway
+                if let Some(closed) = (*(obj as *mut UpvalueObj)).closed {
+                    self.mark_value(closed);
+                }
+            }
+            ObjTyp::Class => {
+                let class = obj as *mut ClassObj;
+                self.mark_object((*class).name as *mut Obj);
+                self.mark_table(&(*class).methods);
+            }
+            ObjTyp::Instance => {
+                let instance = obj as *mut InstanceObj;
+                self.mark_object((*instance).class as *mut Obj);
+                self.mark_table(&(*instance).fields);
+            }
+            ObjTyp::BoundMethod => {
+                let bound_method = obj as *mut BoundMethodObj;
+                self.mark_value((*bound_method).receiver);
+                self.mark_object((*bound_method).method as *mut Obj);
+            }
+            ObjTyp::Native => self.mark_object((*(obj as *mut NativeObj)).name as *mut Obj),
+        }
+    }
+
+    fn sweep(&mut self) {
+        let mut prev: *mut Obj = ptr::null_mut();
+        let mut obj = self.objects;
+
+        while !obj.is_null() {
+            unsafe {
+                let next = (*obj).next_obj;
+
+                if (*obj).is_marked {
+                    // Unmark for the next collection
+                    (*obj).is_marked = false;
+                    prev = obj;
+                } else {
+                    if prev.is_null() {
+                        self.objects = next;
+                    } else {
+                        (*prev).next_obj = next;
+                    }
+
+                    self.bytes_allocated -= Heap::object_size(obj);
+                    Heap::free_object(obj);
+                }
+
+                obj = next;
+            }
+        }
+    }
+
+    pub fn intern(&mut self, contents: &str) -> *mut StringObj {
+        if let Some(interned) = self.strings.find_string(contents, hash_str(contents)) {
+            return interned;
+        }
+
+        let string_ptr = StringObj::new(contents);
+        self.link_string(string_ptr);
+        string_ptr
+    }
+
+    pub fn concat(&mut self, s1: *mut StringObj, s2: *mut StringObj) -> *mut StringObj {
+        unsafe {
+            let new_str_ptr = (*s1).concat(s2);
+
+            let interned = self
+                .strings
+                .find_string((*new_str_ptr).as_str(), (*new_str_ptr).hash());
+            match interned {
+                Some(interned) => {
+                    ptr::drop_in_place(new_str_ptr);
+                    interned
+                }
+                None => {
+                    self.link_string(new_str_ptr);
+                    new_str_ptr
+                }
+            }
+        }
+    }
+
+    pub fn new_function(
+        &mut self,
+        arity: usize,
+        upvalue_count: usize,
+        chunk: Chunk,
+        name: *mut StringObj,
+    ) -> *mut FunctionObj {
+        let function_ptr = FunctionObj::new(arity, upvalue_count, chunk, name);
+        self.link(function_ptr as *mut Obj);
+        function_ptr
+    }
+
+    pub fn new_closure(&mut self, function: *mut FunctionObj) -> *mut ClosureObj {
+        let closure_ptr = ClosureObj::new(function);
+        self.link(closure_ptr as *mut Obj);
+        closure_ptr
+    }
+
+    pub fn new_upvalue(&mut self, slot: usize) -> *mut UpvalueObj {
+        let upvalue_ptr = UpvalueObj::new(slot);
+        self.link(upvalue_ptr as *mut Obj);
+        upvalue_ptr
+    }
+
+    pub fn new_class(&mut self, name: *mut StringObj) -> *mut ClassObj {
+        let class_ptr = ClassObj::new(name);
+        self.link(class_ptr as *mut Obj);
+        class_ptr
+    }
+
+    pub fn new_instance(&mut self, class: *mut ClassObj) -> *mut InstanceObj {
+        let instance_ptr = InstanceObj::new(class);
+        self.link(instance_ptr as *mut Obj);
+        instance_ptr
+    }
+
+    pub fn new_bound_method(
+        &mut self,
+        receiver: RuntimeValue,
+        method: *mut ClosureObj,
+    ) -> *mut BoundMethodObj {
+        let bound_ptr = BoundMethodObj::new(receiver, method);
+        self.link(bound_ptr as *mut Obj);
+        bound_ptr
+    }
+
+    pub fn new_native(
+        &mut self,
+        name: *mut StringObj,
+        arity: usize,
+        function: NativeFn,
+    ) -> *mut NativeObj {
+        let native_ptr = NativeObj::new(name, arity, function);
+        self.link(native_ptr as *mut Obj);
+        native_ptr
+    }
+
+    fn link_string(&mut self, string_ptr: *mut StringObj) {
+        unsafe { self.link((*string_ptr).as_obj_ptr()) };
+
+        // The table is used as a set, the value doesn't matter
+        self.strings.set(string_ptr, RuntimeValue::Nil);
+    }
+
+    fn link(&mut self, obj: *mut Obj) {
+        unsafe {
+            (*obj).next_obj = self.objects;
+            self.bytes_allocated += Heap::object_size(obj);
+        }
+        self.objects = obj;
+    }
+
+    /// Approximate number of bytes owned by the object
+    unsafe fn object_size(obj: *mut Obj) -> usize {
+        match (*obj).typ {
+            ObjTyp::String => size_of::<StringObj>() + (*(obj as *mut StringObj)).as_str().len(),
+            ObjTyp::Function => {
+                let chunk = &(*(obj as *mut FunctionObj)).chunk;
+                size_of::<FunctionObj>()
+                    + chunk.code.len()
+                    + chunk.constants.len() * size_of::<PackedValue>()
+            }
+            ObjTyp::Closure => {
+                let closure = obj as *mut ClosureObj;
+                size_of::<ClosureObj>() + (*closure).upvalues.capacity() * size_of::<usize>()
+            }
+            ObjTyp::Upvalue => size_of::<UpvalueObj>(),
+            ObjTyp::Class => size_of::<ClassObj>(),
+            ObjTyp::Instance => size_of::<InstanceObj>(),
+            ObjTyp::BoundMethod => size_of::<BoundMethodObj>(),
+            ObjTyp::Native => size_of::<NativeObj>(),
+        }
+    }
+
+    unsafe fn free_object(obj: *mut Obj) {
+        match (*obj).typ {
+            ObjTyp::String => ptr::drop_in_place(obj as *mut StringObj),
+            ObjTyp::Function => drop(Box::from_raw(obj as *mut FunctionObj)),
+            ObjTyp::Closure => drop(Box::from_raw(obj as *mut ClosureObj)),
+            ObjTyp::Upvalue => drop(Box::from_raw(obj as *mut UpvalueObj)),
+            ObjTyp::Class => drop(Box::from_raw(obj as *mut ClassObj)),
+            ObjTyp::Instance => drop(Box::from_raw(obj as *mut InstanceObj)),
+            ObjTyp::BoundMethod => drop(Box::from_raw(obj as *mut BoundMethodObj)),
+            ObjTyp::Native => drop(Box::from_raw(obj as *mut NativeObj)),
+        }
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        // Free the runtime objects
+        let mut next_obj = self.objects;
+        while !next_obj.is_null() {
+            unsafe {
+                let next_ptr = (*next_obj).next_obj;
+                Heap::free_object(next_obj);
+                next_obj = next_ptr;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        bytecode::Chunk,
+        heap::Heap,
+        runtime_val::{Obj, RuntimeValue},
+        table::hash_str,
+        token::Span,
+    };
+
+    fn object_count(heap: &Heap) -> usize {
+        let mut count = 0;
+        let mut obj = heap.objects;
+        while !obj.is_null() {
+            count += 1;
+            obj = unsafe { (*obj).next_obj };
+        }
+        count
+    }
+
+    #[test]
+    fn intern() {
+        let mut heap = Heap::new();
+
+        let s1 = heap.intern("hello");
+        let s2 = heap.intern("hello");
+        let s3 = heap.intern("world");
+
+        assert_eq!(s1, s2);
+        assert_ne!(s1, s3);
+    }
+
+    #[test]
+    fn concat_interned() {
+        let mut heap = Heap::new();
+
+        let s1 = heap.intern("hello ");
+        let s2 = heap.intern("world");
+        let s3 = heap.intern("hello world");
+
+        assert_eq!(heap.concat(s1, s2), s3);
+        assert_eq!(heap.concat(s2, s1), heap.intern("worldhello "));
+    }
+
+    #[test]
+    fn collect_unreachable() {
+        let mut heap = Heap::new();
+
+        let kept = heap.intern("kept");
+        heap.intern("garbage");
+        let allocated = heap.bytes_allocated;
+
+        heap.mark_object(kept as *mut Obj);
+        heap.collect();
+
+        assert_eq!(object_count(&heap), 1);
+        assert!(heap.bytes_allocated < allocated);
+
+        // Freed strings are removed from the intern table
+        assert_eq!(
+            heap.strings.find_string("kept", hash_str("kept")),
+            Some(kept)
+        );
+        assert_eq!(
+            heap.strings.find_string("garbage", hash_str("garbage")),
+            None
+        );
+
+        // Marks are cleared, so the next collection frees everything
+        heap.collect();
+        assert_eq!(object_count(&heap), 0);
+    }
+
+    #[test]
+    fn collect_traces_references() {
+        let mut heap = Heap::new();
+
+        let mut chunk = Chunk::new();
+        chunk
+            .emit_constant(
+                RuntimeValue::String(heap.intern("constant")),
+                Span::default(),
+            )
+            .unwrap();
+        let name = heap.intern("f");
+        let function = heap.new_function(0, 0, chunk, name);
+        let closure = heap.new_closure(function);
+        heap.new_closure(function);
+
+        heap.mark_value(RuntimeValue::Closure(closure));
+        heap.collect();
+
+        // Only the second closure is unreachable
+        assert_eq!(object_count(&heap), 4);
+        assert_eq!(heap.intern("f"), name);
+
+        heap.collect();
+        assert_eq!(object_count(&heap), 0);
+        assert!(heap.strings.find_string("f", hash_str("f")).is_none());
+        assert!(heap.objects.is_null());
+    }
+}
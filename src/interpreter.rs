@@ -0,0 +1,529 @@
+use std::{fs, io, path::Path};
+
+use crate::{
+    diagnostic::Diagnostic,
+    heap::Heap,
+    runtime_val::{FunctionObj, NativeFn},
+    value::Value,
+    vm::{RuntimeError, Vm},
+};
+
+/// Compiles and runs Lox code. Globals are kept between runs.
+pub struct Interpreter {
+    vm: Vm,
+    echo_expressions: bool,
+    optimize: bool,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // The source is kept for rendering the diagnostics
+    Compile {
+        diagnostics: Vec<Diagnostic>,
+        source: String,
+    },
+    Runtime(RuntimeError),
+    // A value from the host program that can't be stored in the VM
+    InvalidValue(String),
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter {
+            vm: Vm::new(Heap::new()),
+            echo_expressions: false,
+            optimize: true,
+        }
+    }
+
+    /// Redirects the output of print statements and disassembly, it goes to stdout by default
+    pub fn set_output(&mut self, out: impl io::Write + 'static) {
+        self.vm.set_output(Box::new(out));
+    }
+
+    /// Collects garbage on every allocation, useful for debugging the GC
+    pub fn set_stress_gc(&mut self, stress_gc: bool) {
+        self.vm.set_stress_gc(stress_gc);
+    }
+
+    /// Limits the number of values on the stack, scripts that need more fail with a stack overflow
+    pub fn set_stack_limit(&mut self, values: usize) {
+        self.vm.set_stack_limit(values);
+    }
+
+    /// Limits the number of nested calls, deeper recursion fails with a stack overflow
+    pub fn set_call_depth_limit(&mut self, calls: usize) {
+        self.vm.set_call_depth_limit(calls);
+    }
+
+    /// Makes top-level expression statements print their value, like in a REPL
+    pub fn set_echo_expressions(&mut self, echo_expressions: bool) {
+        self.echo_expressions = echo_expressions;
+    }
+
+    /// Passes arguments to scripts, they can be read with the 'argc' and 'arg' natives
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.vm.set_args(args);
+    }
+
+    /// Folds constants and fuses instructions of compiled code, enabled by default
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    pub fn eval(&mut self, source: &str) -> Result<(), Error> {
+        let script = self.compile(source)?;
+        self.vm.execute(script).map_err(Error::Runtime)
+    }
+
+    pub fn run_file(&mut self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let source = fs::read_to_string(path).map_err(Error::Io)?;
+        self.eval(&source)
+    }
+
+    /// Only compiles the code, reporting any compile errors
+    pub fn check(&mut self, source: &str) -> Result<(), Error> {
+        self.compile(source).map(|_| ())
+    }
+
+    /// Writes the bytecode of the code and all functions in it to the output
+    pub fn disassemble(&mut self, source: &str) -> Result<(), Error> {
+        let script = self.compile(source)?;
+        unsafe { (*script).disassemble(self.vm.output()) }.map_err(Error::Io)
+    }
+
+    /// Makes the Rust function callable from Lox as a global function.
+    /// The function is called with exactly 'arity' arguments.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        self.vm.define_native(name, arity, function);
+    }
+
+    pub fn get_global(&mut self, name: &str) -> Option<Value> {
+        self.vm.get_global_value(name)
+    }
+
+    /// Objects can only be read from the VM, storing a `Value::Object` is an error
+    pub fn set_global(&mut self, name: &str, value: impl Into<Value>) -> Result<(), Error> {
+        if self.vm.set_global_value(name, &value.into()) {
+            Ok(())
+        } else {
+            Err(Error::InvalidValue(format!(
+                "objects can't be stored in global '{}'",
+                name
+            )))
+        }
+    }
+
+    fn compile(&mut self, source: &str) -> Result<*mut FunctionObj, Error> {
+        let mut compiler = self.vm.compiler(source);
+        if self.echo_expressions {
+            compiler.echo_expressions();
+        }
+        if !self.optimize {
+            compiler.disable_optimizations();
+        }
+
+        compiler.compile().map_err(|diagnostics| Error::Compile {
+            diagnostics,
+            source: source.to_string(),
+        })
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Compile {
+                diagnostics,
+                source,
+            } => {
+                let rendered: Vec<String> = diagnostics.iter().map(|d| d.render(source)).collect();
+                write!(f, "{}", rendered.join("\n"))
+            }
+            Error::Runtime(e) => write!(f, "{}", e),
+            Error::InvalidValue(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, io, rc::Rc};
+
+    use crate::{Error, Interpreter, NativeContext, Value};
+
+    // Output shared between the test and the interpreter
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Output {
+        fn take(&self) -> String {
+            String::from_utf8(self.0.take()).unwrap()
+        }
+    }
+
+    #[test]
+    fn eval_keeps_globals() {
+        let output = Output::default();
+        let mut lox = Interpreter::new();
+        lox.set_output(output.clone());
+
+        lox.eval("var greeting = \"hello\";").unwrap();
+        lox.eval("fun greet(name) { return greeting + \", \" + name; }")
+            .unwrap();
+        lox.eval("print greet(\"world\");").unwrap();
+
+        assert_eq!(output.take(), "hello, world\n");
+    }
+
+    #[test]
+    fn interpolation() {
+        let output = Output::default();
+        let mut lox = Interpreter::new();
+        lox.set_output(output.clone());
+        lox.set_stress_gc(true);
+
+        lox.eval(
+            r#"
+            class Point { init(x, y) { this.x = x; this.y = y; } }
+            fun show(p) { return "(${p.x}, ${p.y})"; }
+            var p = Point(1, 2.5);
+            print "p = ${show(p)}, ${p}, ${nil} ${"\"quoted\""}";
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            output.take(),
+            "p = (1, 2.5), Point instance, nil \"quoted\"\n"
+        );
+    }
+
+    #[test]
+    fn conditional() {
+        let output = Output::default();
+        let mut lox = Interpreter::new();
+        lox.set_output(output.clone());
+
+        lox.eval(
+            r#"
+            fun side(x) { print x; return x; }
+            var a = true ? side("then") : side("else");
+            var b = false ? 1 : nil ? 2 : 3;
+            var c = true ? false ? 1 : 2 : 3;
+            var d = 0 or nil ? "yes" : "no";
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(output.take(), "then\n");
+        assert_eq!(lox.get_global("a"), Some(Value::from("then")));
+        assert_eq!(lox.get_global("b"), Some(Value::Number(3.0)));
+        assert_eq!(lox.get_global("c"), Some(Value::Number(2.0)));
+        assert_eq!(lox.get_global("d"), Some(Value::from("yes")));
+    }
+
+    #[test]
+    fn globals() {
+        let mut lox = Interpreter::new();
+        lox.set_global("n", 2.0).unwrap();
+        lox.set_global("s", "text").unwrap();
+        lox.eval("var doubled = n * 2; var t = s + \"!\"; class C {} var c = C();")
+            .unwrap();
+
+        assert_eq!(lox.get_global("doubled"), Some(Value::Number(4.0)));
+        assert_eq!(lox.get_global("t"), Some(Value::from("text!")));
+        assert_eq!(lox.get_global("missing"), None);
+
+        let object = match lox.get_global("c") {
+            Some(Value::Object(o)) => o,
+            _ => panic!("expected an object"),
+        };
+        assert_eq!(object.type_name(), "instance");
+        assert_eq!(object.to_string(), "C instance");
+
+        assert!(matches!(
+            lox.set_global("d", Value::Object(object)),
+            Err(Error::InvalidValue(_))
+        ));
+        assert_eq!(lox.get_global("d"), None);
+    }
+
+    #[test]
+    fn disassemble_to_output() {
+        let output = Output::default();
+        let mut lox = Interpreter::new();
+        lox.set_output(output.clone());
+        lox.disassemble("fun f() { return 1; } print f();").unwrap();
+
+        let disassembly = output.take();
+        assert!(disassembly.contains("CLOSURE"));
+        assert!(disassembly.contains("== f =="));
+        assert!(disassembly.contains("CONSTANT    c[0] = 1"));
+    }
+
+    #[test]
+    fn echo_expressions() {
+        let output = Output::default();
+        let mut lox = Interpreter::new();
+        lox.set_output(output.clone());
+        lox.set_echo_expressions(true);
+
+        lox.eval("var a = 1; a + 2; { a; }").unwrap();
+
+        assert_eq!(output.take(), "3\n");
+    }
+
+    #[test]
+    fn optimized_code_behaves_the_same() {
+        let source = r#"
+            var nan = 0 / 0;
+            print 1 + 2 * 3 != 7;
+            print !(1 < 2) == (2 <= 1);
+            print nan >= nan;
+            print nan <= 1;
+            print !(nan < 1);
+            print !!!nil;
+            print "a" + "b" != "ab";
+            var i = 0;
+            while (!(i >= 3)) i = i + 1;
+            print i;
+        "#;
+
+        let mut outputs = Vec::new();
+        for &optimize in [false, true].iter() {
+            let output = Output::default();
+            let mut lox = Interpreter::new();
+            lox.set_output(output.clone());
+            lox.set_optimize(optimize);
+            lox.eval(source).unwrap();
+            outputs.push(output.take());
+        }
+
+        assert_eq!(
+            outputs[0],
+            "false\ntrue\ntrue\ntrue\ntrue\ntrue\nfalse\n3\n"
+        );
+        assert_eq!(outputs[0], outputs[1]);
+
+        let errors = [
+            ("print 1 >= nil;", "cannot apply 'less' to number and nil"),
+            (
+                "print nil <= 1;",
+                "cannot apply 'greater' to nil and number",
+            ),
+        ];
+        for &(source, message) in errors.iter() {
+            for &optimize in [false, true].iter() {
+                let mut lox = Interpreter::new();
+                lox.set_optimize(optimize);
+                match lox.eval(source) {
+                    Err(Error::Runtime(e)) => assert_eq!(e.message, message),
+                    _ => panic!("expected a runtime error"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn object_identity() {
+        let output = Output::default();
+        let mut lox = Interpreter::new();
+        lox.set_output(output.clone());
+
+        lox.eval(
+            r#"
+            class A { m() {} }
+            var a = A();
+            var m = a.m;
+            print clock == clock;
+            print clock != sqrt;
+            print m == m;
+            // Every access binds the method again
+            print a.m == a.m;
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(output.take(), "true\ntrue\ntrue\nfalse\n");
+    }
+
+    #[test]
+    fn natives() {
+        fn hypot(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+            match args {
+                [Value::Number(a), Value::Number(b)] => Ok(Value::Number(a.hypot(*b))),
+                _ => Err("hypot expects two numbers".to_string()),
+            }
+        }
+
+        fn greeting(ctx: &mut NativeContext, _: &[Value]) -> Result<Value, String> {
+            let greeting = match ctx.get_global("name") {
+                Some(Value::String(name)) => Value::String(format!("hello, {}", name)),
+                _ => Value::Nil,
+            };
+            ctx.set_global("greeted", true)?;
+            Ok(greeting)
+        }
+
+        fn identity(_: &mut NativeContext, args: &[Value]) -> Result<Value, String> {
+            Ok(args[0].clone())
+        }
+
+        let output = Output::default();
+        let mut lox = Interpreter::new();
+        lox.set_output(output.clone());
+        lox.set_stress_gc(true);
+        lox.define_native("hypot", 2, hypot);
+        lox.define_native("greeting", 0, greeting);
+        lox.define_native("identity", 1, identity);
+
+        lox.eval("var name = \"lox\"; print hypot(3, 4); print greeting(); print hypot;")
+            .unwrap();
+        assert_eq!(output.take(), "5\nhello, lox\n<native fn hypot>\n");
+        assert_eq!(lox.get_global("greeted"), Some(Value::Bool(true)));
+
+        let message = |lox: &mut Interpreter, text: &str| match lox.eval(text) {
+            Err(Error::Runtime(e)) => e.message,
+            _ => panic!("expected a runtime error"),
+        };
+        assert_eq!(
+            message(&mut lox, "hypot(1);"),
+            "expected 2 arguments but got 1"
+        );
+        assert_eq!(
+            message(&mut lox, "fun f() { hypot(1, nil); }\nf();"),
+            "hypot expects two numbers"
+        );
+        assert_eq!(
+            message(&mut lox, "identity(identity);"),
+            "native function 'identity' can't return objects"
+        );
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let mut lox = Interpreter::new();
+        lox.eval(
+            "fun count(n) { if (n == 0) return 0; var one = 1; return one + count(n - 1); }
+             var deep = count(10000);",
+        )
+        .unwrap();
+        assert_eq!(lox.get_global("deep"), Some(Value::Number(10000.0)));
+
+        let err = match lox.eval("fun forever(n) { return forever(n + 1); }\nforever(0);") {
+            Err(Error::Runtime(e)) => e,
+            _ => panic!("expected a runtime error"),
+        };
+        assert_eq!(err.message, "stack overflow");
+        assert_eq!(err.line, 1);
+        assert_eq!(
+            err.trace.last().map(|f| f.function.as_str()),
+            Some("<script>")
+        );
+
+        let message = err.to_string();
+        assert!(message.contains(
+            "\n    in <fn forever> at line 1, column 32\n    ... repeated 65535 more times\n"
+        ));
+        assert_eq!(message.lines().count(), 4);
+
+        // The VM can still be used after the overflow
+        lox.eval("var after = count(100);").unwrap();
+        assert_eq!(lox.get_global("after"), Some(Value::Number(100.0)));
+    }
+
+    #[test]
+    fn stack_limits() {
+        let mut lox = Interpreter::new();
+        lox.eval("fun count(n) { if (n == 0) return 0; return 1 + count(n - 1); }")
+            .unwrap();
+
+        // count(n) nests n + 1 calls
+        lox.set_call_depth_limit(50);
+        lox.eval("count(49);").unwrap();
+        match lox.eval("count(50);") {
+            Err(Error::Runtime(e)) => assert_eq!(e.message, "stack overflow"),
+            _ => panic!("expected a runtime error"),
+        }
+
+        lox.set_call_depth_limit(1000);
+        lox.set_stack_limit(100);
+        match lox.eval("count(60);") {
+            Err(Error::Runtime(e)) => assert_eq!(e.message, "stack overflow"),
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn wide_operands() {
+        let mut text = String::new();
+        for i in 0..300 {
+            text.push_str(&format!("var g{} = {};\n", i, i));
+        }
+        text.push_str("fun f() {\n");
+        for i in 0..300 {
+            text.push_str(&format!("  var l{} = g{};\n", i, i));
+        }
+        text.push_str("  l299 = l299 + l1;\n  fun g() { return l299; }\n  return g;\n}\n");
+        text.push_str("g299 = f()();\n");
+        // Class, method and property names past the first 256 constants
+        text.push_str(
+            "class A { init() { this.x = 1; } get() { return this.x; } }
+             class B < A { get() { return super.get() + 1; } parent() { return super.get; } }
+             var b = B();
+             b.y = 3;
+             var invoked = b.get();
+             var bound = b.parent()();
+             var field = b.y;",
+        );
+
+        let mut lox = Interpreter::new();
+        lox.eval(&text).unwrap();
+        assert_eq!(lox.get_global("g256"), Some(Value::Number(256.0)));
+        assert_eq!(lox.get_global("g299"), Some(Value::Number(300.0)));
+        assert_eq!(lox.get_global("invoked"), Some(Value::Number(2.0)));
+        assert_eq!(lox.get_global("bound"), Some(Value::Number(1.0)));
+        assert_eq!(lox.get_global("field"), Some(Value::Number(3.0)));
+    }
+
+    #[test]
+    fn errors() {
+        let mut lox = Interpreter::new();
+
+        match lox.eval("print 1 +;") {
+            Err(Error::Compile { diagnostics, .. }) => assert_eq!(diagnostics.len(), 1),
+            _ => panic!("expected a compile error"),
+        }
+
+        match lox.eval("print -\"s\";") {
+            Err(Error::Runtime(e)) => assert_eq!(e.message, "cannot apply 'negate' to string"),
+            _ => panic!("expected a runtime error"),
+        }
+
+        assert!(matches!(
+            lox.run_file("does/not/exist.lox"),
+            Err(Error::Io(_))
+        ));
+    }
+}
@@ -1,25 +1,34 @@
-use std::{iter::Peekable, str::Chars};
+use std::{iter::Peekable, ops::Range, str::Chars};
 
 use crate::token::{Token, TokenType};
 
 type Text<'t> = Peekable<Chars<'t>>;
 
-// TODO: challenge - string interpolation
-
 /*
  NUMBER      → DIGIT+ ( "." DIGIT+ )? ;
- STRING         → "\"" <any char except "\"">* "\"" ;
+ STRING         → "\"" ( CHAR | "${" <expression tokens> "}" )* "\"" ;
+ CHAR           → <any char except "\"" and "\\"> | ESCAPE ;
+ ESCAPE         → "\\" ( "\"" | "\\" | "$" | "n" | "t" | "r" | "0" | "u{" HEX_DIGIT{1,6} "}" ) ;
  IDENTIFIER     → ALPHA ( ALPHA | DIGIT )* ;
  ALPHA          → "a" ... "z" | "A" ... "Z" | "_" ;
  DIGIT          → "0" ... "9" ;
+
+ A string with interpolated expressions is split into tokens at "${" and "}":
+ InterpolationStart ("\"...${"), the expression tokens, any number of
+ InterpolationMiddle ("}...${") and expression tokens, InterpolationEnd ("}...\"").
 */
 
 pub struct Lexer<'t> {
     text: &'t str,
 
+    // Position of the next character
     line: usize,
+    offset: usize,
+    column: usize,
 
     token_len: usize,
+    // Unclosed braces of every interpolated expression being lexed
+    interpolations: Vec<usize>,
 }
 
 impl<'t> Lexer<'t> {
@@ -27,7 +36,10 @@ impl<'t> Lexer<'t> {
         Lexer {
             text,
             line: 1,
+            offset: 0,
+            column: 1,
             token_len: 0,
+            interpolations: Vec::new(),
         }
     }
 
@@ -35,6 +47,7 @@ impl<'t> Lexer<'t> {
         let mut text: Text<'t> = self.text.chars().peekable();
 
         self.token_len = 0;
+        let (line, offset, column) = (self.line, self.offset, self.column);
         let typ: TokenType;
 
         match self.next_char(&mut text) {
@@ -55,14 +68,27 @@ impl<'t> Lexer<'t> {
                         typ = TokenType::Whitespace;
                         self.consume_while(&mut text, |c| c == '\t');
                     }
-                    '\n' => {
-                        typ = TokenType::Newline;
-                        self.line += 1;
-                    }
+                    '\n' => typ = TokenType::Newline,
                     '(' => typ = TokenType::LeftParen,
                     ')' => typ = TokenType::RightParen,
-                    '{' => typ = TokenType::LeftBrace,
-                    '}' => typ = TokenType::RightBrace,
+                    '{' => {
+                        typ = TokenType::LeftBrace;
+                        if let Some(depth) = self.interpolations.last_mut() {
+                            *depth += 1;
+                        }
+                    }
+                    '}' => match self.interpolations.last_mut() {
+                        // Closes the interpolated expression, the string continues
+                        Some(0) => {
+                            self.interpolations.pop();
+                            typ = self.string(&mut text, true);
+                        }
+                        Some(depth) => {
+                            *depth -= 1;
+                            typ = TokenType::RightBrace;
+                        }
+                        None => typ = TokenType::RightBrace,
+                    },
                     ',' => typ = TokenType::Comma,
                     '.' => typ = TokenType::Dot,
                     '-' => typ = TokenType::Minus,
@@ -77,6 +103,8 @@ impl<'t> Lexer<'t> {
                         }
                     }
                     '*' => typ = TokenType::Star,
+                    '?' => typ = TokenType::Question,
+                    ':' => typ = TokenType::Colon,
                     '!' => {
                         if self.advance_if(&mut text, '=') {
                             typ = TokenType::BangEqual
@@ -105,22 +133,29 @@ impl<'t> Lexer<'t> {
                             typ = TokenType::Less
                         }
                     }
-                    '\"' => typ = self.string(&mut text),
+                    '\"' => typ = self.string(&mut text, false),
                     _ => typ = TokenType::Error(LexError::InvalidCharacter),
                 };
                 let lexeme: &str = &self.text[..self.token_len];
                 self.text = &self.text[self.token_len..];
-                Token::new(typ, lexeme, self.line)
+                self.offset += self.token_len;
+                Token::new(typ, lexeme, line, offset, column)
             }
-            None => Token::new(TokenType::Eof, "", self.line),
+            // The empty lexeme still points at the end of the source text
+            None => Token::new(TokenType::Eof, &self.text[..0], line, offset, column),
         }
     }
 
     #[inline]
     fn next_char(&mut self, text: &mut Text) -> Option<char> {
-        text.next().and_then(|c| {
+        text.next().inspect(|&c| {
             self.token_len += c.len_utf8();
-            Some(c)
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
         })
     }
 
@@ -148,9 +183,6 @@ impl<'t> Lexer<'t> {
         loop {
             match text.peek() {
                 Some(c) if predicate(*c) => {
-                    if *c == '\n' {
-                        self.line += 1;
-                    }
                     self.next_char(text);
                 }
                 _ => return,
@@ -164,13 +196,10 @@ impl<'t> Lexer<'t> {
             'a' => self.check_keyword(text, "nd", TokenType::And),
             'c' => self.check_keyword(text, "lass", TokenType::Class),
             'e' => self.check_keyword(text, "lse", TokenType::Else),
-            'f' => match self.next_char(text) {
-                Some(c) => match c {
-                    'a' => self.check_keyword(text, "lse", TokenType::False),
-                    'o' => self.check_keyword(text, "r", TokenType::For),
-                    'u' => self.check_keyword(text, "n", TokenType::Fun),
-                    _ => self.finish_identifier(text),
-                },
+            'f' => match text.peek() {
+                Some('a') => self.check_keyword(text, "alse", TokenType::False),
+                Some('o') => self.check_keyword(text, "or", TokenType::For),
+                Some('u') => self.check_keyword(text, "un", TokenType::Fun),
                 _ => self.finish_identifier(text),
             },
             'i' => self.check_keyword(text, "f", TokenType::If),
@@ -179,12 +208,9 @@ impl<'t> Lexer<'t> {
             'p' => self.check_keyword(text, "rint", TokenType::Print),
             'r' => self.check_keyword(text, "eturn", TokenType::Return),
             's' => self.check_keyword(text, "uper", TokenType::Super),
-            't' => match self.next_char(text) {
-                Some(c) => match c {
-                    'h' => self.check_keyword(text, "is", TokenType::This),
-                    'r' => self.check_keyword(text, "ue", TokenType::True),
-                    _ => self.finish_identifier(text),
-                },
+            't' => match text.peek() {
+                Some('h') => self.check_keyword(text, "his", TokenType::This),
+                Some('r') => self.check_keyword(text, "rue", TokenType::True),
                 _ => self.finish_identifier(text),
             },
             'v' => self.check_keyword(text, "ar", TokenType::Var),
@@ -231,14 +257,46 @@ impl<'t> Lexer<'t> {
     }
 
     #[inline]
-    fn string(&mut self, text: &mut Text) -> TokenType {
-        self.consume_while(text, |c| c != '"');
+    /// Lexes a string or its part, the opening '"' or '}' is already consumed
+    fn string(&mut self, text: &mut Text, continued: bool) -> TokenType {
+        loop {
+            match text.peek().copied() {
+                Some('"') | None => break,
+                Some('\\') => {
+                    // The escaped character can't end the string
+                    self.next_char(text);
+                    if text.peek().is_some() {
+                        self.next_char(text);
+                    }
+                }
+                Some('$') if self.peek_2(text) == Some('{') => {
+                    self.next_char(text);
+                    self.next_char(text);
+                    self.interpolations.push(0);
+
+                    let body = &self.text[1..self.token_len - 2];
+                    return match (unescape(body), continued) {
+                        (Err(_), _) => TokenType::Error(LexError::InvalidEscape),
+                        (Ok(_), false) => TokenType::InterpolationStart,
+                        (Ok(_), true) => TokenType::InterpolationMiddle,
+                    };
+                }
+                Some(_) => {
+                    self.next_char(text);
+                }
+            }
+        }
+
         match self.next_char(text) {
-            Some('"') => TokenType::String,
-            _ => {
-                self.token_len -= 1;
-                TokenType::Error(LexError::UnterminatedString)
+            Some('"') => {
+                let body = &self.text[1..self.token_len - 1];
+                match (unescape(body), continued) {
+                    (Err(_), _) => TokenType::Error(LexError::InvalidEscape),
+                    (Ok(_), false) => TokenType::String,
+                    (Ok(_), true) => TokenType::InterpolationEnd,
+                }
             }
+            _ => TokenType::Error(LexError::UnterminatedString),
         }
     }
 
@@ -252,16 +310,73 @@ impl<'t> Lexer<'t> {
 pub enum LexError {
     InvalidCharacter,
     UnterminatedString,
+    InvalidEscape,
+}
+
+/// Decodes the escape sequences in the contents of a string literal.
+/// Returns the byte range of the first invalid escape sequence on error.
+pub fn unescape(body: &str) -> Result<String, Range<usize>> {
+    let mut result = String::with_capacity(body.len());
+    let mut chars = body.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+
+        let escaped = match chars.next() {
+            Some((_, '"')) => '"',
+            Some((_, '\\')) => '\\',
+            Some((_, '$')) => '$',
+            Some((_, 'n')) => '\n',
+            Some((_, 't')) => '\t',
+            Some((_, 'r')) => '\r',
+            Some((_, '0')) => '\0',
+            Some((_, 'u')) => {
+                let mut digits = String::new();
+                let mut closed = false;
+                if let Some((_, '{')) = chars.peek() {
+                    chars.next();
+                    while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_hexdigit()) {
+                        digits.push(c);
+                    }
+                    closed = chars.next_if(|(_, c)| *c == '}').is_some();
+                }
+
+                let end = chars.peek().map_or(body.len(), |(i, _)| *i);
+                let code = u32::from_str_radix(&digits, 16).ok();
+                match code.and_then(char::from_u32) {
+                    Some(c) if closed && digits.len() <= 6 => c,
+                    _ => return Err(start..end),
+                }
+            }
+            other => {
+                let end = other.map_or(body.len(), |(i, c)| i + c.len_utf8());
+                return Err(start..end);
+            }
+        };
+        result.push(escaped);
+    }
+
+    Ok(result)
 }
 
 #[cfg(test)]
 mod test {
     #![allow(dead_code, unused_imports)]
 
-    use crate::lexer::{Lexer, Token};
-    use crate::token::TokenType::*;
+    use crate::lexer::{LexError, Lexer, Token};
+    use crate::token::TokenType::{self, *};
+
+    // Positions are checked separately, the tests compare (type, lexeme, line)
+    type Tok<'t> = (TokenType, &'t str, usize);
 
-    fn get_tokens(text: &str) -> Vec<Token> {
+    fn tok(typ: TokenType, lexeme: &str, line: usize) -> Tok<'_> {
+        (typ, lexeme, line)
+    }
+
+    fn get_tokens(text: &str) -> Vec<Tok<'_>> {
         let mut lexer = Lexer::new(text);
         let mut tokens = Vec::new();
 
@@ -270,13 +385,13 @@ mod test {
             if tok.typ == Eof {
                 break;
             }
-            tokens.push(tok);
+            tokens.push((tok.typ, tok.lexeme, tok.line));
         }
 
         tokens
     }
 
-    fn get_tokens_no_trivia(text: &str) -> Vec<Token> {
+    fn get_tokens_no_trivia(text: &str) -> Vec<Tok<'_>> {
         let mut lexer = Lexer::new(text);
         let mut tokens = Vec::new();
 
@@ -288,7 +403,7 @@ mod test {
 
             match tok.typ {
                 Comment | Whitespace | Newline => continue,
-                _ => tokens.push(tok),
+                _ => tokens.push((tok.typ, tok.lexeme, tok.line)),
             }
         }
 
@@ -297,31 +412,34 @@ mod test {
 
     #[test]
     fn delimeters() {
-        let tokens = get_tokens("(   ) {   } ,   . -   + ;   / *   ");
+        let tokens = get_tokens("(   ) {   } ,   . -   + ;   / *   ? :");
 
         let expected_tokens = vec![
-            Token::new(LeftParen, "(", 1),
-            Token::new(Whitespace, "   ", 1),
-            Token::new(RightParen, ")", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(LeftBrace, "{", 1),
-            Token::new(Whitespace, "   ", 1),
-            Token::new(RightBrace, "}", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Comma, ",", 1),
-            Token::new(Whitespace, "   ", 1),
-            Token::new(Dot, ".", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Minus, "-", 1),
-            Token::new(Whitespace, "   ", 1),
-            Token::new(Plus, "+", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Semicolon, ";", 1),
-            Token::new(Whitespace, "   ", 1),
-            Token::new(Slash, "/", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Star, "*", 1),
-            Token::new(Whitespace, "   ", 1),
+            tok(LeftParen, "(", 1),
+            tok(Whitespace, "   ", 1),
+            tok(RightParen, ")", 1),
+            tok(Whitespace, " ", 1),
+            tok(LeftBrace, "{", 1),
+            tok(Whitespace, "   ", 1),
+            tok(RightBrace, "}", 1),
+            tok(Whitespace, " ", 1),
+            tok(Comma, ",", 1),
+            tok(Whitespace, "   ", 1),
+            tok(Dot, ".", 1),
+            tok(Whitespace, " ", 1),
+            tok(Minus, "-", 1),
+            tok(Whitespace, "   ", 1),
+            tok(Plus, "+", 1),
+            tok(Whitespace, " ", 1),
+            tok(Semicolon, ";", 1),
+            tok(Whitespace, "   ", 1),
+            tok(Slash, "/", 1),
+            tok(Whitespace, " ", 1),
+            tok(Star, "*", 1),
+            tok(Whitespace, "   ", 1),
+            tok(Question, "?", 1),
+            tok(Whitespace, " ", 1),
+            tok(Colon, ":", 1),
         ];
 
         assert_eq!(tokens, expected_tokens);
@@ -332,24 +450,24 @@ mod test {
         let tokens = get_tokens("   ! != = == > >= < <=   \n");
 
         let expected_tokens = vec![
-            Token::new(Whitespace, "   ", 1),
-            Token::new(Bang, "!", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(BangEqual, "!=", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Equal, "=", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(EqualEqual, "==", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Greater, ">", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(GreaterEqual, ">=", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Less, "<", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(LessEqual, "<=", 1),
-            Token::new(Whitespace, "   ", 1),
-            Token::new(Newline, "\n", 2),
+            tok(Whitespace, "   ", 1),
+            tok(Bang, "!", 1),
+            tok(Whitespace, " ", 1),
+            tok(BangEqual, "!=", 1),
+            tok(Whitespace, " ", 1),
+            tok(Equal, "=", 1),
+            tok(Whitespace, " ", 1),
+            tok(EqualEqual, "==", 1),
+            tok(Whitespace, " ", 1),
+            tok(Greater, ">", 1),
+            tok(Whitespace, " ", 1),
+            tok(GreaterEqual, ">=", 1),
+            tok(Whitespace, " ", 1),
+            tok(Less, "<", 1),
+            tok(Whitespace, " ", 1),
+            tok(LessEqual, "<=", 1),
+            tok(Whitespace, "   ", 1),
+            tok(Newline, "\n", 1),
         ];
 
         assert_eq!(tokens, expected_tokens);
@@ -360,24 +478,24 @@ mod test {
         let tokens = get_tokens(" x * 5 - foo   /bar + baz\n");
 
         let expected_tokens = vec![
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "x", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Star, "*", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Number, "5", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Minus, "-", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "foo", 1),
-            Token::new(Whitespace, "   ", 1),
-            Token::new(Slash, "/", 1),
-            Token::new(Identifier, "bar", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Plus, "+", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "baz", 1),
-            Token::new(Newline, "\n", 2),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "x", 1),
+            tok(Whitespace, " ", 1),
+            tok(Star, "*", 1),
+            tok(Whitespace, " ", 1),
+            tok(Number, "5", 1),
+            tok(Whitespace, " ", 1),
+            tok(Minus, "-", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "foo", 1),
+            tok(Whitespace, "   ", 1),
+            tok(Slash, "/", 1),
+            tok(Identifier, "bar", 1),
+            tok(Whitespace, " ", 1),
+            tok(Plus, "+", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "baz", 1),
+            tok(Newline, "\n", 1),
         ];
 
         assert_eq!(tokens, expected_tokens);
@@ -388,22 +506,22 @@ mod test {
         let tokens = get_tokens(" x * 1.1/ 52.68 *654.7 - 7");
 
         let expected_tokens = vec![
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "x", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Star, "*", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Number, "1.1", 1),
-            Token::new(Slash, "/", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Number, "52.68", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Star, "*", 1),
-            Token::new(Number, "654.7", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Minus, "-", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Number, "7", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "x", 1),
+            tok(Whitespace, " ", 1),
+            tok(Star, "*", 1),
+            tok(Whitespace, " ", 1),
+            tok(Number, "1.1", 1),
+            tok(Slash, "/", 1),
+            tok(Whitespace, " ", 1),
+            tok(Number, "52.68", 1),
+            tok(Whitespace, " ", 1),
+            tok(Star, "*", 1),
+            tok(Number, "654.7", 1),
+            tok(Whitespace, " ", 1),
+            tok(Minus, "-", 1),
+            tok(Whitespace, " ", 1),
+            tok(Number, "7", 1),
         ];
 
         assert_eq!(tokens, expected_tokens);
@@ -414,22 +532,98 @@ mod test {
         let tokens = get_tokens("  \"Hello World!\"  ");
 
         let expected_tokens = vec![
-            Token::new(Whitespace, "  ", 1),
-            Token::new(String, "\"Hello World!\"", 1),
-            Token::new(Whitespace, "  ", 1),
+            tok(Whitespace, "  ", 1),
+            tok(String, "\"Hello World!\"", 1),
+            tok(Whitespace, "  ", 1),
+        ];
+
+        assert_eq!(tokens, expected_tokens);
+    }
+
+    #[test]
+    fn unterminated_strings() {
+        let tokens = get_tokens("x \"abc");
+        let expected_tokens = vec![
+            tok(Identifier, "x", 1),
+            tok(Whitespace, " ", 1),
+            tok(Error(LexError::UnterminatedString), "\"abc", 1),
+        ];
+        assert_eq!(tokens, expected_tokens);
+
+        let tokens = get_tokens("\"");
+        let expected_tokens = vec![tok(Error(LexError::UnterminatedString), "\"", 1)];
+        assert_eq!(tokens, expected_tokens);
+    }
+
+    #[test]
+    fn escaped_strings() {
+        let tokens = get_tokens_no_trivia(r#""say \"hi\"" "a\\" "\u{1F600}" "\q" "\u{D800}""#);
+
+        let expected_tokens = vec![
+            tok(String, r#""say \"hi\"""#, 1),
+            tok(String, r#""a\\""#, 1),
+            tok(String, r#""\u{1F600}""#, 1),
+            tok(Error(LexError::InvalidEscape), r#""\q""#, 1),
+            tok(Error(LexError::InvalidEscape), r#""\u{D800}""#, 1),
+        ];
+
+        assert_eq!(tokens, expected_tokens);
+    }
+
+    #[test]
+    fn interpolation() {
+        let tokens = get_tokens_no_trivia(r#""a ${b} c ${f("x ${y}") + {}} \${d}""#);
+
+        let expected_tokens = vec![
+            tok(InterpolationStart, r#""a ${"#, 1),
+            tok(Identifier, "b", 1),
+            tok(InterpolationMiddle, "} c ${", 1),
+            tok(Identifier, "f", 1),
+            tok(LeftParen, "(", 1),
+            tok(InterpolationStart, r#""x ${"#, 1),
+            tok(Identifier, "y", 1),
+            tok(InterpolationEnd, r#"}""#, 1),
+            tok(RightParen, ")", 1),
+            tok(Plus, "+", 1),
+            tok(LeftBrace, "{", 1),
+            tok(RightBrace, "}", 1),
+            tok(InterpolationEnd, r#"} \${d}""#, 1),
         ];
 
         assert_eq!(tokens, expected_tokens);
     }
 
+    #[test]
+    fn unescape() {
+        use crate::lexer::unescape;
+
+        assert_eq!(
+            unescape(r#"\"q\" \\ \n\t\r\0"#),
+            Ok("\"q\" \\ \n\t\r\0".to_string())
+        );
+        assert_eq!(
+            unescape(r"\u{41}\u{10FFFF}č"),
+            Ok("A\u{10FFFF}č".to_string())
+        );
+        assert_eq!(unescape(r"\${x}"), Ok("${x}".to_string()));
+
+        assert_eq!(unescape(r"ab\x"), Err(2..4));
+        assert_eq!(unescape(r"\č"), Err(0..3));
+        assert_eq!(unescape(r"\u{110000}"), Err(0..10));
+        assert_eq!(unescape(r"\u{1234567}"), Err(0..11));
+        assert_eq!(unescape(r"\u{41 x"), Err(0..5));
+        assert_eq!(unescape(r"\u41"), Err(0..2));
+        assert_eq!(unescape(r"\u{}"), Err(0..4));
+    }
+
     #[test]
     fn multiline_strings() {
         let tokens = get_tokens("  \"Hello World! \n after newline \"  ");
 
         let expected_tokens = vec![
-            Token::new(Whitespace, "  ", 1),
-            Token::new(String, "\"Hello World! \n after newline \"", 2),
-            Token::new(Whitespace, "  ", 2),
+            tok(Whitespace, "  ", 1),
+            tok(String, "\"Hello World! \n after newline \"", 1),
+            tok(Whitespace, "  ", 2),
         ];
 
         assert_eq!(tokens, expected_tokens);
@@ -442,37 +636,37 @@ mod test {
         );
 
         let expected_tokens = vec![
-            Token::new(And, "and", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Class, "class", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Else, "else", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(False, "false", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(For, "for", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Fun, "fun", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(If, "if", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Nil, "nil", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Or, "or", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Print, "print", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Return, "return", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Super, "super", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(This, "this", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(True, "true", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Var, "var", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(While, "while", 1),
+            tok(And, "and", 1),
+            tok(Whitespace, " ", 1),
+            tok(Class, "class", 1),
+            tok(Whitespace, " ", 1),
+            tok(Else, "else", 1),
+            tok(Whitespace, " ", 1),
+            tok(False, "false", 1),
+            tok(Whitespace, " ", 1),
+            tok(For, "for", 1),
+            tok(Whitespace, " ", 1),
+            tok(Fun, "fun", 1),
+            tok(Whitespace, " ", 1),
+            tok(If, "if", 1),
+            tok(Whitespace, " ", 1),
+            tok(Nil, "nil", 1),
+            tok(Whitespace, " ", 1),
+            tok(Or, "or", 1),
+            tok(Whitespace, " ", 1),
+            tok(Print, "print", 1),
+            tok(Whitespace, " ", 1),
+            tok(Return, "return", 1),
+            tok(Whitespace, " ", 1),
+            tok(Super, "super", 1),
+            tok(Whitespace, " ", 1),
+            tok(This, "this", 1),
+            tok(Whitespace, " ", 1),
+            tok(True, "true", 1),
+            tok(Whitespace, " ", 1),
+            tok(Var, "var", 1),
+            tok(Whitespace, " ", 1),
+            tok(While, "while", 1),
         ];
 
         assert_eq!(tokens, expected_tokens);
@@ -485,37 +679,37 @@ mod test {
         );
 
         let expected_tokens = vec![
-            Token::new(Identifier, "andy", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "classy", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "elset", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "falset", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "forum", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "funny", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "iffy", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "nilli", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "ores", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "prints", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "returning", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "superstition", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "thisx", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "truex", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "variation", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "whilex", 1),
+            tok(Identifier, "andy", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "classy", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "elset", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "falset", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "forum", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "funny", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "iffy", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "nilli", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "ores", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "prints", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "returning", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "superstition", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "thisx", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "truex", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "variation", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "whilex", 1),
         ];
 
         assert_eq!(tokens, expected_tokens);
@@ -526,37 +720,51 @@ mod test {
         let tokens = get_tokens("an clas els fals fo fu i ni o prin retur supe thi tru va whil");
 
         let expected_tokens = vec![
-            Token::new(Identifier, "an", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "clas", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "els", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "fals", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "fo", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "fu", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "i", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "ni", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "o", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "prin", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "retur", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "supe", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "thi", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "tru", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "va", 1),
-            Token::new(Whitespace, " ", 1),
-            Token::new(Identifier, "whil", 1),
+            tok(Identifier, "an", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "clas", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "els", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "fals", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "fo", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "fu", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "i", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "ni", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "o", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "prin", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "retur", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "supe", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "thi", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "tru", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "va", 1),
+            tok(Whitespace, " ", 1),
+            tok(Identifier, "whil", 1),
+        ];
+
+        assert_eq!(tokens, expected_tokens);
+    }
+
+    #[test]
+    fn single_letter_identifiers() {
+        let tokens = get_tokens("f(t)");
+
+        let expected_tokens = vec![
+            tok(Identifier, "f", 1),
+            tok(LeftParen, "(", 1),
+            tok(Identifier, "t", 1),
+            tok(RightParen, ")", 1),
         ];
 
         assert_eq!(tokens, expected_tokens);
@@ -571,30 +779,30 @@ mod test {
         );
 
         let expected_tokens = vec![
-            Token::new(For, "for", 1),
-            Token::new(LeftParen, "(", 1),
-            Token::new(Var, "var", 1),
-            Token::new(Identifier, "i", 1),
-            Token::new(Equal, "=", 1),
-            Token::new(Number, "1", 1),
-            Token::new(Semicolon, ";", 1),
-            Token::new(Identifier, "i", 1),
-            Token::new(Less, "<", 1),
-            Token::new(Number, "5", 1),
-            Token::new(Semicolon, ";", 1),
-            Token::new(Identifier, "i", 1),
-            Token::new(Equal, "=", 1),
-            Token::new(Identifier, "i", 1),
-            Token::new(Plus, "+", 1),
-            Token::new(Number, "1", 1),
-            Token::new(RightParen, ")", 1),
-            Token::new(LeftBrace, "{", 1),
-            Token::new(Print, "print", 2),
-            Token::new(Identifier, "i", 2),
-            Token::new(Star, "*", 2),
-            Token::new(Identifier, "i", 2),
-            Token::new(Semicolon, ";", 2),
-            Token::new(RightBrace, "}", 3),
+            tok(For, "for", 1),
+            tok(LeftParen, "(", 1),
+            tok(Var, "var", 1),
+            tok(Identifier, "i", 1),
+            tok(Equal, "=", 1),
+            tok(Number, "1", 1),
+            tok(Semicolon, ";", 1),
+            tok(Identifier, "i", 1),
+            tok(Less, "<", 1),
+            tok(Number, "5", 1),
+            tok(Semicolon, ";", 1),
+            tok(Identifier, "i", 1),
+            tok(Equal, "=", 1),
+            tok(Identifier, "i", 1),
+            tok(Plus, "+", 1),
+            tok(Number, "1", 1),
+            tok(RightParen, ")", 1),
+            tok(LeftBrace, "{", 1),
+            tok(Print, "print", 2),
+            tok(Identifier, "i", 2),
+            tok(Star, "*", 2),
+            tok(Identifier, "i", 2),
+            tok(Semicolon, ";", 2),
+            tok(RightBrace, "}", 3),
         ];
 
         assert_eq!(tokens, expected_tokens);
@@ -615,34 +823,34 @@ mod test {
         );
 
         let expected_tokens = vec![
-            Token::new(Class, "class", 1),
-            Token::new(Identifier, "Duck", 1),
-            Token::new(LeftBrace, "{", 1),
-            Token::new(Identifier, "init", 2),
-            Token::new(LeftParen, "(", 2),
-            Token::new(Identifier, "name", 2),
-            Token::new(RightParen, ")", 2),
-            Token::new(LeftBrace, "{", 2),
-            Token::new(This, "this", 3),
-            Token::new(Dot, ".", 3),
-            Token::new(Identifier, "name", 3),
-            Token::new(Equal, "=", 3),
-            Token::new(Identifier, "name", 3),
-            Token::new(Semicolon, ";", 3),
-            Token::new(RightBrace, "}", 4),
-            Token::new(Identifier, "quack", 6),
-            Token::new(LeftParen, "(", 6),
-            Token::new(RightParen, ")", 6),
-            Token::new(LeftBrace, "{", 6),
-            Token::new(Print, "print", 7),
-            Token::new(This, "this", 7),
-            Token::new(Dot, ".", 7),
-            Token::new(Identifier, "name", 7),
-            Token::new(Plus, "+", 7),
-            Token::new(String, "\" quacks\"", 7),
-            Token::new(Semicolon, ";", 7),
-            Token::new(RightBrace, "}", 8),
-            Token::new(RightBrace, "}", 9),
+            tok(Class, "class", 1),
+            tok(Identifier, "Duck", 1),
+            tok(LeftBrace, "{", 1),
+            tok(Identifier, "init", 2),
+            tok(LeftParen, "(", 2),
+            tok(Identifier, "name", 2),
+            tok(RightParen, ")", 2),
+            tok(LeftBrace, "{", 2),
+            tok(This, "this", 3),
+            tok(Dot, ".", 3),
+            tok(Identifier, "name", 3),
+            tok(Equal, "=", 3),
+            tok(Identifier, "name", 3),
+            tok(Semicolon, ";", 3),
+            tok(RightBrace, "}", 4),
+            tok(Identifier, "quack", 6),
+            tok(LeftParen, "(", 6),
+            tok(RightParen, ")", 6),
+            tok(LeftBrace, "{", 6),
+            tok(Print, "print", 7),
+            tok(This, "this", 7),
+            tok(Dot, ".", 7),
+            tok(Identifier, "name", 7),
+            tok(Plus, "+", 7),
+            tok(String, "\" quacks\"", 7),
+            tok(Semicolon, ";", 7),
+            tok(RightBrace, "}", 8),
+            tok(RightBrace, "}", 9),
         ];
 
         assert_eq!(tokens, expected_tokens);
@@ -664,50 +872,95 @@ mod test {
         );
 
         let expected_tokens = vec![
-            Token::new(Fun, "fun", 1),
-            Token::new(Identifier, "make_adder", 1),
-            Token::new(LeftParen, "(", 1),
-            Token::new(Identifier, "n", 1),
-            Token::new(RightParen, ")", 1),
-            Token::new(LeftBrace, "{", 1),
-            Token::new(Fun, "fun", 2),
-            Token::new(Identifier, "adder", 2),
-            Token::new(LeftParen, "(", 2),
-            Token::new(Identifier, "i", 2),
-            Token::new(RightParen, ")", 2),
-            Token::new(LeftBrace, "{", 2),
-            Token::new(Return, "return", 3),
-            Token::new(Identifier, "n", 3),
-            Token::new(Plus, "+", 3),
-            Token::new(Identifier, "i", 3),
-            Token::new(Semicolon, ";", 3),
-            Token::new(RightBrace, "}", 4),
-            Token::new(Return, "return", 5),
-            Token::new(Identifier, "adder", 5),
-            Token::new(Semicolon, ";", 5),
-            Token::new(RightBrace, "}", 6),
-            Token::new(Var, "var", 8),
-            Token::new(Identifier, "add5", 8),
-            Token::new(Equal, "=", 8),
-            Token::new(Identifier, "make_adder", 8),
-            Token::new(LeftParen, "(", 8),
-            Token::new(Number, "5", 8),
-            Token::new(RightParen, ")", 8),
-            Token::new(Semicolon, ";", 8),
-            Token::new(Print, "print", 9),
-            Token::new(Identifier, "add5", 9),
-            Token::new(LeftParen, "(", 9),
-            Token::new(Number, "1", 9),
-            Token::new(RightParen, ")", 9),
-            Token::new(Semicolon, ";", 9),
-            Token::new(Print, "print", 10),
-            Token::new(Identifier, "add5", 10),
-            Token::new(LeftParen, "(", 10),
-            Token::new(Number, "100", 10),
-            Token::new(RightParen, ")", 10),
-            Token::new(Semicolon, ";", 10),
+            tok(Fun, "fun", 1),
+            tok(Identifier, "make_adder", 1),
+            tok(LeftParen, "(", 1),
+            tok(Identifier, "n", 1),
+            tok(RightParen, ")", 1),
+            tok(LeftBrace, "{", 1),
+            tok(Fun, "fun", 2),
+            tok(Identifier, "adder", 2),
+            tok(LeftParen, "(", 2),
+            tok(Identifier, "i", 2),
+            tok(RightParen, ")", 2),
+            tok(LeftBrace, "{", 2),
+            tok(Return, "return", 3),
+            tok(Identifier, "n", 3),
+            tok(Plus, "+", 3),
+            tok(Identifier, "i", 3),
+            tok(Semicolon, ";", 3),
+            tok(RightBrace, "}", 4),
+            tok(Return, "return", 5),
+            tok(Identifier, "adder", 5),
+            tok(Semicolon, ";", 5),
+            tok(RightBrace, "}", 6),
+            tok(Var, "var", 8),
+            tok(Identifier, "add5", 8),
+            tok(Equal, "=", 8),
+            tok(Identifier, "make_adder", 8),
+            tok(LeftParen, "(", 8),
+            tok(Number, "5", 8),
+            tok(RightParen, ")", 8),
+            tok(Semicolon, ";", 8),
+            tok(Print, "print", 9),
+            tok(Identifier, "add5", 9),
+            tok(LeftParen, "(", 9),
+            tok(Number, "1", 9),
+            tok(RightParen, ")", 9),
+            tok(Semicolon, ";", 9),
+            tok(Print, "print", 10),
+            tok(Identifier, "add5", 10),
+            tok(LeftParen, "(", 10),
+            tok(Number, "100", 10),
+            tok(RightParen, ")", 10),
+            tok(Semicolon, ";", 10),
         ];
 
         assert_eq!(tokens, expected_tokens);
     }
+
+    #[test]
+    fn positions() {
+        let mut lexer = Lexer::new("var x =\n  \"a\nb\" + 12;");
+        let mut positions = Vec::new();
+
+        loop {
+            let tok = lexer.next_token();
+            positions.push((tok.lexeme, tok.offset, tok.len, tok.line, tok.column));
+            if tok.typ == Eof {
+                break;
+            }
+        }
+
+        let expected = vec![
+            ("var", 0, 3, 1, 1),
+            (" ", 3, 1, 1, 4),
+            ("x", 4, 1, 1, 5),
+            (" ", 5, 1, 1, 6),
+            ("=", 6, 1, 1, 7),
+            ("\n", 7, 1, 1, 8),
+            ("  ", 8, 2, 2, 1),
+            ("\"a\nb\"", 10, 5, 2, 3),
+            (" ", 15, 1, 3, 3),
+            ("+", 16, 1, 3, 4),
+            (" ", 17, 1, 3, 5),
+            ("12", 18, 2, 3, 6),
+            (";", 20, 1, 3, 8),
+            ("", 21, 0, 3, 9),
+        ];
+
+        assert_eq!(positions, expected);
+    }
+
+    #[test]
+    fn columns_count_characters() {
+        let mut lexer = Lexer::new("\"čau světe\" x");
+
+        let string = lexer.next_token();
+        assert_eq!((string.offset, string.len, string.column), (0, 13, 1));
+
+        lexer.next_token();
+        let x = lexer.next_token();
+        assert_eq!((x.offset, x.len, x.column), (14, 1, 13));
+    }
 }
@@ -0,0 +1,27 @@
+//! A bytecode interpreter of the Lox language.
+//!
+//! The `Interpreter` compiles and runs code, and exchanges values with the host
+//! program using `Value`. Rust functions can be called from Lox after registering them
+//! with `Interpreter::define_native`. The lexer is public for tools working with the source code.
+
+mod bytecode;
+mod compiler;
+mod diagnostic;
+mod heap;
+mod interpreter;
+pub mod lexer;
+mod packed_val;
+mod runtime_val;
+mod stdlib;
+mod table;
+pub mod token;
+mod value;
+mod vm;
+
+pub use compiler::CompileErr;
+pub use diagnostic::Diagnostic;
+pub use interpreter::{Error, Interpreter};
+pub use runtime_val::NativeFn;
+pub use token::Span;
+pub use value::{Object, Value};
+pub use vm::{NativeContext, RuntimeError, TraceFrame};
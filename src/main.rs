@@ -1,44 +1,206 @@
-#![feature(test)]
-#![feature(extern_types)]
+use std::{
+    env, fs,
+    io::{self, Write},
+    process,
+};
 
-use std::{env, fs::File, io::prelude::*};
+use lox::{lexer::Lexer, token::TokenType, Error, Interpreter};
 
-mod bytecode;
-mod compiler;
-mod lexer;
-mod runtime_val;
-mod table;
-mod token;
-mod vm;
+mod repl;
+
+// Exit codes from sysexits.h, same as the reference implementation
+const EX_USAGE: i32 = 64;
+const EX_DATAERR: i32 = 65;
+const EX_SOFTWARE: i32 = 70;
+const EX_IOERR: i32 = 74;
+
+const USAGE: &str = "usage:
+    lox [options]                           start an interactive session
+    lox [options] run <file> [args...]      run a script, it reads the arguments with argc() and arg(i)
+    lox [options] check <file>              report compile errors without running the script
+    lox [options] disasm <file>             print the bytecode of a script
+    lox tokens <file>                       print all tokens of a script
+
+options:
+    -O0                                     compile the code as written, without optimizations
+    --                                      end of options, for files starting with a dash";
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<String> = env::args().skip(1).collect();
+    process::exit(run(&args));
+}
 
-    let file_path = match args.len() {
-        1 => "test.lox",
-        2 => &args[1],
-        _ => panic!("too many arguments"),
-    };
+/// Runs the command given by the arguments, returns the exit code
+fn run(args: &[String]) -> i32 {
+    let mut args = args.iter().map(String::as_str);
+    let mut command = Vec::new();
+    let mut optimize = true;
+    let mut options_ended = false;
 
-    let mut file = File::open(file_path).unwrap();
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).unwrap();
-    let text = contents.as_str();
+    // Options end at the file path or at "--", the rest is passed to the script
+    while command.len() < 2 {
+        match args.next() {
+            Some("--") if !options_ended => options_ended = true,
+            Some("-O0") if !options_ended => optimize = false,
+            Some(option) if option.starts_with('-') && !options_ended => {
+                eprintln!("unknown option '{}'\n{}", option, USAGE);
+                return EX_USAGE;
+            }
+            Some(arg) => command.push(arg),
+            None => break,
+        }
+    }
+    let script_args: Vec<String> = args.map(String::from).collect();
+    let new_interpreter = || new_interpreter(optimize);
 
-    let mut compiler = compiler::Compiler::new(text);
+    match (&command[..], &script_args[..]) {
+        ([], []) => {
+            repl::run(new_interpreter());
+            0
+        }
+        (["run", file_path], _) => with_source(file_path, |text| {
+            let mut lox = new_interpreter();
+            lox.set_args(script_args.clone());
+            lox.eval(text)
+        }),
+        (["check", file_path], []) => with_source(file_path, |text| new_interpreter().check(text)),
+        (["disasm", file_path], []) => {
+            with_source(file_path, |text| new_interpreter().disassemble(text))
+        }
+        (["tokens", file_path], []) => with_source(file_path, tokens),
+        _ => {
+            eprintln!("{}", USAGE);
+            EX_USAGE
+        }
+    }
+}
 
-    match compiler.compile() {
-        Ok(()) => compiler.dump_bytecode(),
-        Err(_) => return,
+fn new_interpreter(optimize: bool) -> Interpreter {
+    let mut lox = Interpreter::new();
+    lox.set_optimize(optimize);
+    // Collect garbage on every allocation, useful for debugging the GC
+    if env::var_os("LOX_STRESS_GC").is_some() {
+        lox.set_stress_gc(true);
     }
+    lox
+}
 
-    let mut vm = vm::Vm::new(compiler.bytecode);
+/// Reads the file and passes its contents to the command, returns the exit code
+fn with_source(file_path: &str, command: impl FnOnce(&str) -> Result<(), Error>) -> i32 {
+    let text = match fs::read_to_string(file_path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("could not read '{}': {}", file_path, e);
+            return EX_IOERR;
+        }
+    };
 
-    match vm.execute() {
-        Ok(()) => return,
+    match command(&text) {
+        Ok(()) => 0,
         Err(e) => {
-            eprintln!("{:?}", e);
-            return;
+            eprintln!("{}", e);
+            match e {
+                Error::Io(_) => EX_IOERR,
+                Error::Compile { .. } => EX_DATAERR,
+                Error::Runtime(_) | Error::InvalidValue(_) => EX_SOFTWARE,
+            }
+        }
+    }
+}
+
+fn tokens(text: &str) -> Result<(), Error> {
+    let mut lexer = Lexer::new(text);
+    // Closed pipes are reported as errors instead of panicking like println!
+    let mut out = io::stdout().lock();
+
+    loop {
+        let tok = lexer.next_token();
+        let position = format!("{}:{}", tok.line, tok.column);
+        writeln!(out, "{:8} {:?} {:?}", position, tok.typ, tok.lexeme).map_err(Error::Io)?;
+
+        if tok.typ == TokenType::Eof {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process};
+
+    use super::{run, EX_DATAERR, EX_IOERR, EX_SOFTWARE, EX_USAGE};
+
+    // Writes the script to a temporary file, returns its path
+    fn script(name: &str, text: &str) -> String {
+        let path = env::temp_dir().join(format!("lox-{}-{}.lox", process::id(), name));
+        fs::write(&path, text).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn run_args(args: &[&str]) -> i32 {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        run(&args)
+    }
+
+    #[test]
+    fn exit_codes() {
+        let ok = script("ok", "var a = 1 + 2;");
+        let compile_error = script("compile-error", "var a = ;");
+        let runtime_error = script("runtime-error", "nil();");
+
+        assert_eq!(run_args(&["run", &ok]), 0);
+        assert_eq!(run_args(&["check", &ok]), 0);
+        assert_eq!(run_args(&["check", &runtime_error]), 0);
+
+        assert_eq!(run_args(&["run", &compile_error]), EX_DATAERR);
+        assert_eq!(run_args(&["check", &compile_error]), EX_DATAERR);
+        assert_eq!(run_args(&["disasm", &compile_error]), EX_DATAERR);
+        assert_eq!(run_args(&["run", &runtime_error]), EX_SOFTWARE);
+
+        let missing = "missing-script.lox";
+        assert_eq!(run_args(&["run", missing]), EX_IOERR);
+        assert_eq!(run_args(&["tokens", missing]), EX_IOERR);
+
+        for path in [ok, compile_error, runtime_error].iter() {
+            fs::remove_file(path).unwrap();
         }
     }
+
+    #[test]
+    fn arguments() {
+        let runtime_error = script("arguments", "print 1 >= nil;");
+
+        assert_eq!(run_args(&["-O0", "run", &runtime_error]), EX_SOFTWARE);
+        assert_eq!(run_args(&["run", "-O0", &runtime_error]), EX_SOFTWARE);
+        assert_eq!(run_args(&["run", "--", &runtime_error]), EX_SOFTWARE);
+
+        assert_eq!(run_args(&["run"]), EX_USAGE);
+        assert_eq!(run_args(&["check", &runtime_error, "extra"]), EX_USAGE);
+        assert_eq!(run_args(&["compile", &runtime_error]), EX_USAGE);
+        assert_eq!(run_args(&["-O2", "run", &runtime_error]), EX_USAGE);
+
+        fs::remove_file(runtime_error).unwrap();
+    }
+
+    #[test]
+    fn script_arguments() {
+        // Fails with a runtime error unless it gets exactly "-O0" and "--"
+        let text = "if (argc() != 2 or arg(0) != \"-O0\" or arg(1) != \"--\") nil();";
+        let checked = script("script-arguments", text);
+
+        assert_eq!(run_args(&["run", &checked, "-O0", "--"]), 0);
+        assert_eq!(run_args(&["-O0", "run", &checked, "-O0", "--"]), 0);
+        assert_eq!(run_args(&["run", &checked, "-O0"]), EX_SOFTWARE);
+
+        fs::remove_file(checked).unwrap();
+
+        // A relative path, so that the file name starts with a dash
+        let dashed = format!("-lox-{}-dashed.lox", process::id());
+        fs::write(&dashed, "var a = 1;").unwrap();
+
+        assert_eq!(run_args(&["run", "--", &dashed]), 0);
+        assert_eq!(run_args(&["run", &dashed]), EX_USAGE);
+
+        fs::remove_file(dashed).unwrap();
+    }
 }
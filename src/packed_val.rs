@@ -0,0 +1,215 @@
+/*
+A RuntimeValue as it's stored on the stack, in chunk constants and in tables.
+
+By default it's the RuntimeValue enum itself, 16 bytes large.
+With the 'nan-boxing' feature every value is packed into 8 bytes:
+
+    number      any double that isn't one of the tagged values below
+    nil         QNAN | 1
+    false       QNAN | 2
+    true        QNAN | 3
+    object      SIGN | QNAN | pointer | kind
+
+Objects are at least 8-byte aligned, so the kind of the object lives
+in the lowest 3 bits of the pointer. Pointers fit into 48 bits on all
+64-bit platforms we run on, packing checks it.
+*/
+
+use crate::runtime_val::RuntimeValue;
+
+#[cfg(not(feature = "nan-boxing"))]
+pub use self::enum_repr::PackedValue;
+#[cfg(feature = "nan-boxing")]
+pub use self::nan_boxing::PackedValue;
+
+impl core::fmt::Display for PackedValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        write!(f, "{}", self.unpack())
+    }
+}
+
+impl From<RuntimeValue> for PackedValue {
+    #[inline]
+    fn from(val: RuntimeValue) -> PackedValue {
+        PackedValue::pack(val)
+    }
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+mod enum_repr {
+    use crate::runtime_val::RuntimeValue;
+
+    #[derive(Clone, Copy)]
+    #[repr(transparent)]
+    pub struct PackedValue(RuntimeValue);
+
+    impl PackedValue {
+        pub const NIL: PackedValue = PackedValue(RuntimeValue::Nil);
+        pub const TRUE: PackedValue = PackedValue(RuntimeValue::Bool(true));
+
+        #[inline]
+        pub fn pack(val: RuntimeValue) -> PackedValue {
+            PackedValue(val)
+        }
+
+        #[inline]
+        pub fn unpack(self) -> RuntimeValue {
+            self.0
+        }
+
+        #[inline]
+        pub fn is_nil(self) -> bool {
+            matches!(self.0, RuntimeValue::Nil)
+        }
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+mod nan_boxing {
+    use crate::runtime_val::RuntimeValue;
+
+    const SIGN: u64 = 0x8000_0000_0000_0000;
+    const QNAN: u64 = 0x7FFC_0000_0000_0000;
+
+    const TAG_NIL: u64 = 1;
+    const TAG_FALSE: u64 = 2;
+    const TAG_TRUE: u64 = 3;
+
+    const OBJ: u64 = SIGN | QNAN;
+    const KIND_MASK: u64 = 0b111;
+    const POINTER_MASK: u64 = 0x0000_FFFF_FFFF_FFF8;
+
+    const KIND_STRING: u64 = 0;
+    const KIND_FUNCTION: u64 = 1;
+    const KIND_CLOSURE: u64 = 2;
+    const KIND_CLASS: u64 = 3;
+    const KIND_INSTANCE: u64 = 4;
+    const KIND_BOUND_METHOD: u64 = 5;
+    const KIND_NATIVE: u64 = 6;
+
+    #[derive(Clone, Copy)]
+    #[repr(transparent)]
+    pub struct PackedValue(u64);
+
+    impl PackedValue {
+        pub const NIL: PackedValue = PackedValue(QNAN | TAG_NIL);
+        pub const TRUE: PackedValue = PackedValue(QNAN | TAG_TRUE);
+
+        #[inline]
+        pub fn pack(val: RuntimeValue) -> PackedValue {
+            match val {
+                RuntimeValue::Nil => PackedValue::NIL,
+                RuntimeValue::Bool(true) => PackedValue::TRUE,
+                RuntimeValue::Bool(false) => PackedValue(QNAN | TAG_FALSE),
+                // NaNs with other payloads could be mistaken for tagged values
+                RuntimeValue::Number(n) if n.is_nan() => PackedValue(f64::NAN.to_bits()),
+                RuntimeValue::Number(n) => PackedValue(n.to_bits()),
+                RuntimeValue::String(ptr) => PackedValue::object(ptr as u64, KIND_STRING),
+                RuntimeValue::Function(ptr) => PackedValue::object(ptr as u64, KIND_FUNCTION),
+                RuntimeValue::Closure(ptr) => PackedValue::object(ptr as u64, KIND_CLOSURE),
+                RuntimeValue::Class(ptr) => PackedValue::object(ptr as u64, KIND_CLASS),
+                RuntimeValue::Instance(ptr) => PackedValue::object(ptr as u64, KIND_INSTANCE),
+                RuntimeValue::BoundMethod(ptr) => {
+                    PackedValue::object(ptr as u64, KIND_BOUND_METHOD)
+                }
+                RuntimeValue::Native(ptr) => PackedValue::object(ptr as u64, KIND_NATIVE),
+            }
+        }
+
+        #[inline]
+        fn object(ptr: u64, kind: u64) -> PackedValue {
+            // A pointer outside the mask would be silently corrupted
+            assert!(
+                ptr & !POINTER_MASK == 0,
+                "object pointer can't be NaN-boxed"
+            );
+            PackedValue(OBJ | ptr | kind)
+        }
+
+        #[inline]
+        pub fn unpack(self) -> RuntimeValue {
+            let bits = self.0;
+
+            if bits & QNAN != QNAN {
+                return RuntimeValue::Number(f64::from_bits(bits));
+            }
+
+            if bits & OBJ != OBJ {
+                return match bits & KIND_MASK {
+                    TAG_NIL => RuntimeValue::Nil,
+                    TAG_FALSE => RuntimeValue::Bool(false),
+                    TAG_TRUE => RuntimeValue::Bool(true),
+                    _ => unreachable!("invalid NaN-boxed value"),
+                };
+            }
+
+            let ptr = bits & POINTER_MASK;
+            match bits & KIND_MASK {
+                KIND_STRING => RuntimeValue::String(ptr as _),
+                KIND_FUNCTION => RuntimeValue::Function(ptr as _),
+                KIND_CLOSURE => RuntimeValue::Closure(ptr as _),
+                KIND_CLASS => RuntimeValue::Class(ptr as _),
+                KIND_INSTANCE => RuntimeValue::Instance(ptr as _),
+                KIND_BOUND_METHOD => RuntimeValue::BoundMethod(ptr as _),
+                KIND_NATIVE => RuntimeValue::Native(ptr as _),
+                _ => unreachable!("invalid NaN-boxed object kind"),
+            }
+        }
+
+        #[inline]
+        pub fn is_nil(self) -> bool {
+            self.0 == PackedValue::NIL.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{mem::size_of, ptr};
+
+    use crate::{
+        packed_val::PackedValue,
+        runtime_val::{RuntimeValue, StringObj},
+    };
+
+    #[test]
+    fn pack_and_unpack() {
+        let string = StringObj::new("packed");
+        let values = [
+            RuntimeValue::Nil,
+            RuntimeValue::Bool(false),
+            RuntimeValue::Bool(true),
+            RuntimeValue::Number(-1.5),
+            RuntimeValue::Number(f64::INFINITY),
+            RuntimeValue::Number(f64::NAN),
+            RuntimeValue::String(string),
+        ];
+
+        for val in values.iter() {
+            let packed = PackedValue::pack(*val);
+            assert_eq!(packed.to_string(), val.to_string());
+            assert_eq!(packed.unpack().type_repr(), val.type_repr());
+        }
+
+        match PackedValue::pack(RuntimeValue::String(string)).unpack() {
+            RuntimeValue::String(s) => assert_eq!(s, string),
+            _ => panic!("expected a string"),
+        }
+        assert!(PackedValue::NIL.is_nil());
+        assert!(!PackedValue::pack(RuntimeValue::Bool(false)).is_nil());
+
+        let expected_size = if cfg!(feature = "nan-boxing") { 8 } else { 16 };
+        assert_eq!(size_of::<PackedValue>(), expected_size);
+
+        unsafe { ptr::drop_in_place(string) };
+    }
+
+    #[test]
+    #[cfg(feature = "nan-boxing")]
+    #[should_panic(expected = "object pointer can't be NaN-boxed")]
+    fn unboxable_pointer() {
+        // Never dereferenced, only packed
+        let pointer = 0x0001_0000_0000_0000 as *mut StringObj;
+        PackedValue::pack(RuntimeValue::String(pointer));
+    }
+}
@@ -0,0 +1,130 @@
+use std::io::{self, prelude::*};
+
+use lox::{
+    lexer::{LexError, Lexer},
+    token::TokenType,
+    Interpreter,
+};
+
+/// Reads code from stdin line by line and runs it on a single VM,
+/// so that globals defined by one input are visible to the next ones
+pub fn run(lox: Interpreter) {
+    let stdin = io::stdin();
+    read_eval_loop(lox, &mut stdin.lock(), &mut io::stdout());
+}
+
+fn read_eval_loop(mut lox: Interpreter, input: &mut impl BufRead, prompts: &mut impl Write) {
+    lox.set_echo_expressions(true);
+
+    let mut text = String::new();
+
+    loop {
+        let prompt = if text.is_empty() { "> " } else { "... " };
+        write!(prompts, "{}", prompt).unwrap();
+        prompts.flush().unwrap();
+
+        let mut line = String::new();
+        let eof = match input.read_line(&mut line) {
+            Ok(read) => read == 0,
+            Err(e) => {
+                eprintln!("error reading input: {}", e);
+                return;
+            }
+        };
+
+        text.push_str(&line);
+        // Incomplete input is still compiled at the end, to report what's missing
+        if is_incomplete(&text) && !eof {
+            continue;
+        }
+
+        if !text.trim().is_empty() {
+            if let Err(e) = lox.eval(&text) {
+                eprintln!("{}", e);
+            }
+        }
+        text.clear();
+
+        if eof {
+            writeln!(prompts).unwrap();
+            return;
+        }
+    }
+}
+
+/// Input is incomplete when it has unclosed braces, parentheses, strings
+/// or interpolated expressions
+fn is_incomplete(text: &str) -> bool {
+    let mut lexer = Lexer::new(text);
+    let mut depth: isize = 0;
+
+    loop {
+        let tok = lexer.next_token();
+        match tok.typ {
+            TokenType::LeftBrace | TokenType::LeftParen | TokenType::InterpolationStart => {
+                depth += 1
+            }
+            TokenType::RightBrace | TokenType::RightParen | TokenType::InterpolationEnd => {
+                depth -= 1
+            }
+            TokenType::Error(LexError::UnterminatedString) => return true,
+            TokenType::Eof => return depth > 0,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, io, rc::Rc};
+
+    use lox::Interpreter;
+
+    use super::{is_incomplete, read_eval_loop};
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn closure_survives_runtime_error() {
+        let output = Output::default();
+        let mut lox = Interpreter::new();
+        lox.set_output(output.clone());
+        lox.set_stress_gc(true);
+
+        // 's' is still on the stack when the error aborts the block
+        let input = "var f;\n\
+            { var s = \"captured\"; fun g() { print s; } f = g; nil(); }\n\
+            { var t = \"overwritten\"; f(); }\n";
+        read_eval_loop(lox, &mut input.as_bytes(), &mut io::sink());
+
+        assert_eq!(String::from_utf8(output.0.take()).unwrap(), "captured\n");
+    }
+
+    #[test]
+    fn incomplete_input() {
+        assert!(!is_incomplete("print 1;"));
+        assert!(!is_incomplete(""));
+        assert!(is_incomplete("fun f() {"));
+        assert!(is_incomplete("fun f() {\n  if (true) {\n  }\n"));
+        assert!(!is_incomplete("fun f() {\n  if (true) {\n  }\n}"));
+        assert!(is_incomplete("print (1 +"));
+        assert!(is_incomplete("print \"abc"));
+        assert!(is_incomplete("print \"a ${b"));
+        assert!(!is_incomplete("print \"a ${ {} } b\";"));
+        // Delimiters in strings and comments don't count
+        assert!(!is_incomplete("print \"{(\"; // {"));
+        // Extra closing delimiters are left for the compiler to report
+        assert!(!is_incomplete("}"));
+    }
+}
@@ -1,11 +1,17 @@
 use std::{
-    alloc::{alloc, dealloc, realloc, Layout},
+    alloc::{alloc, dealloc, Layout},
+    io::{self, Write},
     mem::{size_of, transmute},
     ptr::{copy_nonoverlapping, null_mut},
     slice, str,
 };
 
-use crate::table::hash_str;
+use crate::{
+    bytecode::Chunk,
+    table::{hash_str, Table},
+    value::Value,
+    vm::NativeContext,
+};
 
 // TODO: challenge - add support for "constant" strings
 
@@ -15,36 +21,62 @@ pub enum RuntimeValue {
     Bool(bool),
     Number(f64),
     String(*mut StringObj),
+    Function(*mut FunctionObj),
+    Closure(*mut ClosureObj),
+    Class(*mut ClassObj),
+    Instance(*mut InstanceObj),
+    BoundMethod(*mut BoundMethodObj),
+    Native(*mut NativeObj),
 }
 
 #[derive(Clone, Copy)]
 #[repr(C)]
 pub enum ObjTyp {
     String,
+    Function,
+    Closure,
+    Upvalue,
+    Class,
+    Instance,
+    BoundMethod,
+    Native,
 }
 
+/*
+Common header of all heap objects.
+Every object struct is #[repr(C)] and starts with this header, so a pointer
+to any object can be cast to *mut Obj and back based on the 'typ' field.
+*/
 #[repr(C)]
 pub struct Obj {
     pub typ: ObjTyp,
+    // Set by the garbage collector for objects that are still reachable
+    pub is_marked: bool,
+    pub next_obj: *mut Obj,
 }
 
-extern "C" {
-    pub type opaque_slice;
+impl Obj {
+    fn new(typ: ObjTyp) -> Obj {
+        Obj {
+            typ,
+            is_marked: false,
+            next_obj: null_mut(),
+        }
+    }
 }
 
 #[repr(C)]
 pub struct StringObj {
-    obj_typ: ObjTyp,
+    pub obj: Obj,
     hash: u32,
     len: usize,
-    pub next_obj: *mut Obj,
-    contents: opaque_slice,
+    contents: [u8; 0],
 }
 
 // TODO: consult StringObj implementation with more experienced devs
 
-const BASE_STRING_OBJ_SIZE: usize =
-    size_of::<ObjTyp>() + size_of::<u32>() + size_of::<usize>() + size_of::<*mut Obj>();
+// The contents don't add anything to the size of the struct
+const BASE_STRING_OBJ_SIZE: usize = size_of::<StringObj>();
 
 impl StringObj {
     pub fn new(contents: &str) -> *mut StringObj {
@@ -57,13 +89,12 @@ impl StringObj {
 
             // Initialize fields of the new object
             let string_ptr = transmute::<*mut u8, *mut StringObj>(ptr);
-            (*string_ptr).obj_typ = ObjTyp::String;
+            (*string_ptr).obj = Obj::new(ObjTyp::String);
             (*string_ptr).hash = hash_str(contents);
             (*string_ptr).len = contents.len();
-            (*string_ptr).next_obj = null_mut();
 
             // Copy the string contents
-            let contents_ptr = &mut (*string_ptr).contents as *mut opaque_slice as *mut u8;
+            let contents_ptr = &mut (*string_ptr).contents as *mut [u8; 0] as *mut u8;
             copy_nonoverlapping(contents.as_ptr(), contents_ptr, contents.len());
 
             string_ptr
@@ -82,10 +113,10 @@ impl StringObj {
             let new_ptr = alloc(prev_layout);
             let new_ptr = transmute::<*mut u8, *mut StringObj>(new_ptr);
 
-            let contents_ptr_dst = &mut (*new_ptr).contents as *mut opaque_slice as *mut u8;
+            let contents_ptr_dst = &mut (*new_ptr).contents as *mut [u8; 0] as *mut u8;
 
-            let contents_self_ptr = &mut self.contents as *mut opaque_slice as *mut u8;
-            let contents_other_ptr = &mut (*other).contents as *mut opaque_slice as *mut u8;
+            let contents_self_ptr = &mut self.contents as *mut [u8; 0] as *mut u8;
+            let contents_other_ptr = &mut (*other).contents as *mut [u8; 0] as *mut u8;
 
             // Copy contents of self to the new location
             copy_nonoverlapping(contents_self_ptr, contents_ptr_dst, self.len);
@@ -98,10 +129,9 @@ impl StringObj {
             );
 
             // Initialize the other fields
-            (*new_ptr).obj_typ = ObjTyp::String;
+            (*new_ptr).obj = Obj::new(ObjTyp::String);
             (*new_ptr).len = self.len + (*other).len;
             (*new_ptr).hash = hash_str((*new_ptr).as_str());
-            (*new_ptr).next_obj = null_mut();
 
             new_ptr
         }
@@ -109,12 +139,17 @@ impl StringObj {
 
     pub fn as_str(&self) -> &str {
         unsafe {
-            let data_ptr = &self.contents as *const opaque_slice as *const u8;
+            let data_ptr = &self.contents as *const [u8; 0] as *const u8;
             let slice = slice::from_raw_parts(data_ptr, self.len);
             str::from_utf8_unchecked(slice)
         }
     }
 
+    #[inline]
+    pub fn hash(&self) -> u32 {
+        self.hash
+    }
+
     pub fn as_obj_ptr(&mut self) -> *mut Obj {
         unsafe { transmute::<*mut StringObj, *mut Obj>(self as *mut StringObj) }
     }
@@ -131,6 +166,209 @@ impl Drop for StringObj {
     }
 }
 
+#[repr(C)]
+pub struct FunctionObj {
+    pub obj: Obj,
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    // Null for the top-level script
+    pub name: *mut StringObj,
+}
+
+impl FunctionObj {
+    pub fn new(
+        arity: usize,
+        upvalue_count: usize,
+        chunk: Chunk,
+        name: *mut StringObj,
+    ) -> *mut FunctionObj {
+        let function = FunctionObj {
+            obj: Obj::new(ObjTyp::Function),
+            arity,
+            upvalue_count,
+            chunk,
+            name,
+        };
+
+        Box::into_raw(Box::new(function))
+    }
+
+    pub fn name(&self) -> &str {
+        if self.name.is_null() {
+            "script"
+        } else {
+            unsafe { (*self.name).as_str() }
+        }
+    }
+
+    pub fn disassemble(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "== {} ==", self.name())?;
+        self.chunk.disassemble(out)?;
+
+        for constant in &self.chunk.constants {
+            if let RuntimeValue::Function(function_ptr) = constant.unpack() {
+                writeln!(out)?;
+                unsafe { (*function_ptr).disassemble(out)? };
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl core::fmt::Display for FunctionObj {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        if self.name.is_null() {
+            write!(f, "<script>")
+        } else {
+            write!(f, "<fn {}>", self.name())
+        }
+    }
+}
+
+#[repr(C)]
+pub struct ClosureObj {
+    pub obj: Obj,
+    pub function: *mut FunctionObj,
+    pub upvalues: Vec<*mut UpvalueObj>,
+}
+
+impl ClosureObj {
+    pub fn new(function: *mut FunctionObj) -> *mut ClosureObj {
+        let upvalue_count = unsafe { (*function).upvalue_count };
+        let closure = ClosureObj {
+            obj: Obj::new(ObjTyp::Closure),
+            function,
+            upvalues: Vec::with_capacity(upvalue_count),
+        };
+
+        Box::into_raw(Box::new(closure))
+    }
+}
+
+/*
+A variable captured by a closure.
+While the variable is still on the stack, the upvalue is "open" and refers
+to its stack slot. When the variable goes out of scope, the value is moved
+into the upvalue itself and it becomes "closed".
+Referring to the slot by index instead of a pointer keeps the upvalue valid
+if the stack moves.
+*/
+#[repr(C)]
+pub struct UpvalueObj {
+    pub obj: Obj,
+    pub slot: usize,
+    pub closed: Option<RuntimeValue>,
+}
+
+impl UpvalueObj {
+    pub fn new(slot: usize) -> *mut UpvalueObj {
+        let upvalue = UpvalueObj {
+            obj: Obj::new(ObjTyp::Upvalue),
+            slot,
+            closed: None,
+        };
+
+        Box::into_raw(Box::new(upvalue))
+    }
+}
+
+#[repr(C)]
+pub struct ClassObj {
+    pub obj: Obj,
+    pub name: *mut StringObj,
+    // Closures keyed by the method name
+    pub methods: Table,
+}
+
+impl ClassObj {
+    pub fn new(name: *mut StringObj) -> *mut ClassObj {
+        let class = ClassObj {
+            obj: Obj::new(ObjTyp::Class),
+            name,
+            methods: Table::new(),
+        };
+
+        Box::into_raw(Box::new(class))
+    }
+
+    pub fn name(&self) -> &str {
+        unsafe { (*self.name).as_str() }
+    }
+}
+
+#[repr(C)]
+pub struct InstanceObj {
+    pub obj: Obj,
+    pub class: *mut ClassObj,
+    pub fields: Table,
+}
+
+impl InstanceObj {
+    pub fn new(class: *mut ClassObj) -> *mut InstanceObj {
+        let instance = InstanceObj {
+            obj: Obj::new(ObjTyp::Instance),
+            class,
+            fields: Table::new(),
+        };
+
+        Box::into_raw(Box::new(instance))
+    }
+}
+
+/*
+A method accessed on an instance, for example 'var m = instance.method;'.
+It remembers the instance, so that 'this' is bound to it when the method
+is called later.
+*/
+#[repr(C)]
+pub struct BoundMethodObj {
+    pub obj: Obj,
+    pub receiver: RuntimeValue,
+    pub method: *mut ClosureObj,
+}
+
+impl BoundMethodObj {
+    pub fn new(receiver: RuntimeValue, method: *mut ClosureObj) -> *mut BoundMethodObj {
+        let bound_method = BoundMethodObj {
+            obj: Obj::new(ObjTyp::BoundMethod),
+            receiver,
+            method,
+        };
+
+        Box::into_raw(Box::new(bound_method))
+    }
+}
+
+/// Function implemented in Rust, an error is turned into a Lox runtime error
+pub type NativeFn = fn(&mut NativeContext<'_>, &[Value]) -> Result<Value, String>;
+
+#[repr(C)]
+pub struct NativeObj {
+    pub obj: Obj,
+    pub name: *mut StringObj,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+impl NativeObj {
+    pub fn new(name: *mut StringObj, arity: usize, function: NativeFn) -> *mut NativeObj {
+        let native = NativeObj {
+            obj: Obj::new(ObjTyp::Native),
+            name,
+            arity,
+            function,
+        };
+
+        Box::into_raw(Box::new(native))
+    }
+
+    pub fn name(&self) -> &str {
+        unsafe { (*self.name).as_str() }
+    }
+}
+
 impl core::fmt::Display for RuntimeValue {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
         match self {
@@ -141,17 +379,51 @@ impl core::fmt::Display for RuntimeValue {
             RuntimeValue::Number(n) => write!(f, "{}", n.to_string().as_str()),
             RuntimeValue::Nil => write!(f, "nil"),
             RuntimeValue::String(string_ptr) => unsafe { write!(f, "{}", (**string_ptr).as_str()) },
+            RuntimeValue::Function(function_ptr) => unsafe { write!(f, "{}", **function_ptr) },
+            RuntimeValue::Closure(closure_ptr) => unsafe {
+                write!(f, "{}", *(**closure_ptr).function)
+            },
+            RuntimeValue::Class(class_ptr) => unsafe { write!(f, "{}", (**class_ptr).name()) },
+            RuntimeValue::Instance(instance_ptr) => unsafe {
+                write!(f, "{} instance", (*(**instance_ptr).class).name())
+            },
+            RuntimeValue::BoundMethod(bound_ptr) => unsafe {
+                write!(f, "{}", *(*(**bound_ptr).method).function)
+            },
+            RuntimeValue::Native(native_ptr) => unsafe {
+                write!(f, "<native fn {}>", (**native_ptr).name())
+            },
         }
     }
 }
 
 impl RuntimeValue {
-    pub fn type_repr(&self) -> &str {
+    /// Returns the heap object of the value, if it has one
+    pub fn as_obj(&self) -> Option<*mut Obj> {
+        match *self {
+            RuntimeValue::Nil | RuntimeValue::Bool(_) | RuntimeValue::Number(_) => None,
+            RuntimeValue::String(ptr) => Some(ptr as *mut Obj),
+            RuntimeValue::Function(ptr) => Some(ptr as *mut Obj),
+            RuntimeValue::Closure(ptr) => Some(ptr as *mut Obj),
+            RuntimeValue::Class(ptr) => Some(ptr as *mut Obj),
+            RuntimeValue::Instance(ptr) => Some(ptr as *mut Obj),
+            RuntimeValue::BoundMethod(ptr) => Some(ptr as *mut Obj),
+            RuntimeValue::Native(ptr) => Some(ptr as *mut Obj),
+        }
+    }
+
+    pub fn type_repr(&self) -> &'static str {
         match self {
             RuntimeValue::Nil => "nil",
             RuntimeValue::Bool(_) => "bool",
             RuntimeValue::Number(_) => "number",
             RuntimeValue::String(_) => "string",
+            RuntimeValue::Function(_)
+            | RuntimeValue::Closure(_)
+            | RuntimeValue::BoundMethod(_)
+            | RuntimeValue::Native(_) => "function",
+            RuntimeValue::Class(_) => "class",
+            RuntimeValue::Instance(_) => "instance",
         }
     }
 }
@@ -0,0 +1,352 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    runtime_val::NativeFn,
+    value::Value,
+    vm::{NativeContext, Vm},
+};
+
+/*
+Native functions available to every script.
+Strings are indexed by characters, same as columns in diagnostics.
+*/
+const NATIVES: &[(&str, usize, NativeFn)] = &[
+    ("clock", 0, clock),
+    ("sqrt", 1, sqrt),
+    ("floor", 1, floor),
+    ("pow", 2, pow),
+    ("abs", 1, abs),
+    ("min", 2, min),
+    ("max", 2, max),
+    ("random", 0, random),
+    ("seed", 1, seed),
+    ("str", 1, str),
+    ("num", 1, num),
+    ("len", 1, len),
+    ("substr", 3, substr),
+    ("index_of", 2, index_of),
+    ("upper", 1, upper),
+    ("lower", 1, lower),
+    ("type", 1, type_),
+    ("argc", 0, argc),
+    ("arg", 1, arg),
+];
+
+pub fn install(vm: &mut Vm) {
+    for (name, arity, function) in NATIVES {
+        vm.define_native(name, *arity, *function);
+    }
+}
+
+type NativeResult = Result<Value, String>;
+
+fn number(function: &str, args: &[Value], i: usize) -> Result<f64, String> {
+    match &args[i] {
+        Value::Number(n) => Ok(*n),
+        arg => Err(argument_error(function, i, "a number", arg)),
+    }
+}
+
+fn string<'a>(function: &str, args: &'a [Value], i: usize) -> Result<&'a str, String> {
+    match &args[i] {
+        Value::String(s) => Ok(s),
+        arg => Err(argument_error(function, i, "a string", arg)),
+    }
+}
+
+/// A number that can be used as a character index
+fn index(function: &str, args: &[Value], i: usize) -> Result<usize, String> {
+    match &args[i] {
+        Value::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Ok(*n as usize),
+        arg => Err(argument_error(function, i, "a non-negative integer", arg)),
+    }
+}
+
+fn argument_error(function: &str, i: usize, expected: &str, got: &Value) -> String {
+    format!(
+        "argument {} of '{}' must be {}, got {}",
+        i + 1,
+        function,
+        expected,
+        got.type_name()
+    )
+}
+
+/// Seconds since the Unix epoch
+fn clock(_: &mut NativeContext, _: &[Value]) -> NativeResult {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?;
+    Ok(Value::Number(now.as_secs_f64()))
+}
+
+fn sqrt(_: &mut NativeContext, args: &[Value]) -> NativeResult {
+    Ok(Value::Number(number("sqrt", args, 0)?.sqrt()))
+}
+
+fn floor(_: &mut NativeContext, args: &[Value]) -> NativeResult {
+    Ok(Value::Number(number("floor", args, 0)?.floor()))
+}
+
+fn pow(_: &mut NativeContext, args: &[Value]) -> NativeResult {
+    let base = number("pow", args, 0)?;
+    let exponent = number("pow", args, 1)?;
+    Ok(Value::Number(base.powf(exponent)))
+}
+
+fn abs(_: &mut NativeContext, args: &[Value]) -> NativeResult {
+    Ok(Value::Number(number("abs", args, 0)?.abs()))
+}
+
+fn min(_: &mut NativeContext, args: &[Value]) -> NativeResult {
+    let a = number("min", args, 0)?;
+    let b = number("min", args, 1)?;
+    Ok(Value::Number(a.min(b)))
+}
+
+fn max(_: &mut NativeContext, args: &[Value]) -> NativeResult {
+    let a = number("max", args, 0)?;
+    let b = number("max", args, 1)?;
+    Ok(Value::Number(a.max(b)))
+}
+
+/// A number between 0 (inclusive) and 1 (exclusive)
+fn random(ctx: &mut NativeContext, _: &[Value]) -> NativeResult {
+    Ok(Value::Number(ctx.rng().next_f64()))
+}
+
+/// Makes the following calls of 'random' return the same sequence for the same seed
+fn seed(ctx: &mut NativeContext, args: &[Value]) -> NativeResult {
+    *ctx.rng() = Rng::new(number("seed", args, 0)?.to_bits());
+    Ok(Value::Nil)
+}
+
+fn str(_: &mut NativeContext, args: &[Value]) -> NativeResult {
+    Ok(Value::String(args[0].to_string()))
+}
+
+/// Parses a number, returns nil if the string isn't one
+fn num(_: &mut NativeContext, args: &[Value]) -> NativeResult {
+    match &args[0] {
+        Value::Number(n) => Ok(Value::Number(*n)),
+        Value::String(s) => match s.trim().parse::<f64>() {
+            Ok(n) if n.is_finite() => Ok(Value::Number(n)),
+            _ => Ok(Value::Nil),
+        },
+        arg => Err(argument_error("num", 0, "a string or a number", arg)),
+    }
+}
+
+fn len(_: &mut NativeContext, args: &[Value]) -> NativeResult {
+    let s = string("len", args, 0)?;
+    Ok(Value::Number(s.chars().count() as f64))
+}
+
+/// substr(s, start, length)
+fn substr(_: &mut NativeContext, args: &[Value]) -> NativeResult {
+    let s = string("substr", args, 0)?;
+    let start = index("substr", args, 1)?;
+    let length = index("substr", args, 2)?;
+
+    let char_count = s.chars().count();
+    if !matches!(start.checked_add(length), Some(end) if end <= char_count) {
+        // The end is widened, so that it can't overflow in the message either
+        return Err(format!(
+            "substring {}..{} is out of bounds of a string of length {}",
+            start,
+            start as u128 + length as u128,
+            char_count
+        ));
+    }
+
+    Ok(Value::String(s.chars().skip(start).take(length).collect()))
+}
+
+/// Index of the first occurrence of the substring, or -1
+fn index_of(_: &mut NativeContext, args: &[Value]) -> NativeResult {
+    let s = string("index_of", args, 0)?;
+    let pattern = string("index_of", args, 1)?;
+
+    let index = match s.find(pattern) {
+        Some(byte_index) => s[..byte_index].chars().count() as f64,
+        None => -1.0,
+    };
+    Ok(Value::Number(index))
+}
+
+fn upper(_: &mut NativeContext, args: &[Value]) -> NativeResult {
+    Ok(Value::String(string("upper", args, 0)?.to_uppercase()))
+}
+
+fn lower(_: &mut NativeContext, args: &[Value]) -> NativeResult {
+    Ok(Value::String(string("lower", args, 0)?.to_lowercase()))
+}
+
+fn type_(_: &mut NativeContext, args: &[Value]) -> NativeResult {
+    Ok(Value::from(args[0].type_name()))
+}
+
+/// Number of arguments passed to the script
+fn argc(ctx: &mut NativeContext, _: &[Value]) -> NativeResult {
+    Ok(Value::Number(ctx.args().len() as f64))
+}
+
+/// arg(i), the argument at the index, counting from 0
+fn arg(ctx: &mut NativeContext, args: &[Value]) -> NativeResult {
+    let i = index("arg", args, 0)?;
+
+    match ctx.args().get(i) {
+        Some(arg) => Ok(Value::String(arg.clone())),
+        None => Err(format!(
+            "argument index {} is out of bounds of {} script arguments",
+            i,
+            ctx.args().len()
+        )),
+    }
+}
+
+/// Xorshift64* pseudo-random number generator
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // Spreads similar seeds apart (SplitMix64), the state must not be zero
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+
+        Rng { state: z.max(1) }
+    }
+
+    pub fn from_time() -> Rng {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Rng::new(nanos)
+    }
+
+    pub fn next_f64(&mut self) -> f64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        let bits = self.state.wrapping_mul(0x2545_F491_4F6C_DD1D);
+
+        // The top 53 bits fill the mantissa exactly
+        (bits >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Error, Interpreter, Value};
+
+    fn eval(expression: &str) -> Value {
+        let mut lox = Interpreter::new();
+        lox.eval(&format!("var result = {};", expression)).unwrap();
+        lox.get_global("result").unwrap()
+    }
+
+    fn error(expression: &str) -> String {
+        match Interpreter::new().eval(&format!("{};", expression)) {
+            Err(Error::Runtime(e)) => e.message,
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn math() {
+        assert_eq!(eval("sqrt(16)"), Value::Number(4.0));
+        assert_eq!(eval("floor(-1.5)"), Value::Number(-2.0));
+        assert_eq!(eval("pow(2, 10)"), Value::Number(1024.0));
+        assert_eq!(eval("abs(-3)"), Value::Number(3.0));
+        assert_eq!(eval("min(3, -1)"), Value::Number(-1.0));
+        assert_eq!(eval("max(3, -1)"), Value::Number(3.0));
+        assert_eq!(eval("type(clock())"), Value::from("number"));
+
+        assert_eq!(
+            error("pow(2, \"x\")"),
+            "argument 2 of 'pow' must be a number, got string"
+        );
+    }
+
+    #[test]
+    fn random() {
+        let mut lox = Interpreter::new();
+        lox.eval(
+            "seed(42); var a = random(); var b = random();
+             seed(42); var c = random();
+             var in_range = true;
+             for (var i = 0; i < 1000; i = i + 1) {
+                 var r = random();
+                 if (r < 0 or r >= 1) in_range = false;
+             }",
+        )
+        .unwrap();
+
+        assert_eq!(lox.get_global("a"), lox.get_global("c"));
+        assert_ne!(lox.get_global("a"), lox.get_global("b"));
+        assert_eq!(lox.get_global("in_range"), Some(Value::Bool(true)));
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(eval("str(1.5) + str(nil)"), Value::from("1.5nil"));
+        assert_eq!(eval("num(\" 12.5 \")"), Value::Number(12.5));
+        assert_eq!(eval("num(\"twelve\")"), Value::Nil);
+        assert_eq!(eval("len(\"čau\")"), Value::Number(3.0));
+        assert_eq!(eval("substr(\"čau světe\", 4, 5)"), Value::from("světe"));
+        assert_eq!(eval("index_of(\"čau světe\", \"svě\")"), Value::Number(4.0));
+        assert_eq!(eval("index_of(\"abc\", \"x\")"), Value::Number(-1.0));
+        assert_eq!(
+            eval("upper(\"čau\") + lower(\"ABC\")"),
+            Value::from("ČAUabc")
+        );
+
+        assert_eq!(
+            error("substr(\"abc\", 2, 2)"),
+            "substring 2..4 is out of bounds of a string of length 3"
+        );
+        assert_eq!(
+            error("substr(\"abc\", 18446744073709551615, 1)"),
+            "substring 18446744073709551615..18446744073709551616 is out of bounds of a string of length 3"
+        );
+        assert_eq!(
+            error("substr(\"abc\", 0.5, 1)"),
+            "argument 2 of 'substr' must be a non-negative integer, got number"
+        );
+    }
+
+    #[test]
+    fn script_arguments() {
+        let mut lox = Interpreter::new();
+        lox.set_args(vec!["-x".to_string(), "two".to_string()]);
+        lox.eval("var count = argc(); var first = arg(0); var second = arg(1);")
+            .unwrap();
+
+        assert_eq!(lox.get_global("count"), Some(Value::Number(2.0)));
+        assert_eq!(lox.get_global("first"), Some(Value::from("-x")));
+        assert_eq!(lox.get_global("second"), Some(Value::from("two")));
+
+        assert_eq!(eval("argc()"), Value::Number(0.0));
+        assert_eq!(
+            error("arg(0)"),
+            "argument index 0 is out of bounds of 0 script arguments"
+        );
+    }
+
+    #[test]
+    fn types() {
+        assert_eq!(eval("type(nil)"), Value::from("nil"));
+        assert_eq!(eval("type(true)"), Value::from("bool"));
+        assert_eq!(eval("type(\"\")"), Value::from("string"));
+        assert_eq!(eval("type(type)"), Value::from("function"));
+        assert_eq!(
+            eval("type(clock) + str(clock)"),
+            Value::from("function<native fn clock>")
+        );
+    }
+}
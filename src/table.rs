@@ -1,5 +1,14 @@
-use crate::runtime_val::{RuntimeValue, StringObj};
+use std::ptr;
 
+use crate::{
+    packed_val::PackedValue,
+    runtime_val::{RuntimeValue, StringObj},
+};
+
+const MAX_LOAD: f64 = 0.75;
+const MIN_CAPACITY: usize = 8;
+
+// FNV-1a
 pub fn hash_str(_str: &str) -> u32 {
     let mut hash: u32 = 2166136261;
 
@@ -11,11 +20,296 @@ pub fn hash_str(_str: &str) -> u32 {
     hash
 }
 
-pub struct Table<'s> {
-    entries: Vec<Entry<'s>>,
+/*
+Open-addressing hash table with linear probing, keyed on strings.
+
+Deleted entries are replaced by tombstones (a null key with a 'true' value),
+so that probe sequences running through them aren't cut short.
+The capacity is always a power of two, which lets us wrap the probe index
+with a mask instead of a modulo.
+*/
+pub struct Table {
+    entries: Vec<Entry>,
+    // Live entries + tombstones
+    count: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    key: *mut StringObj,
+    value: PackedValue,
+}
+
+impl Entry {
+    const EMPTY: Entry = Entry {
+        key: ptr::null_mut(),
+        value: PackedValue::NIL,
+    };
+
+    const TOMBSTONE: Entry = Entry {
+        key: ptr::null_mut(),
+        value: PackedValue::TRUE,
+    };
+
+    #[inline]
+    fn is_tombstone(&self) -> bool {
+        self.key.is_null() && !self.value.is_nil()
+    }
 }
 
-pub struct Entry<'s> {
-    key: &'s StringObj,
-    value: RuntimeValue,
+impl Table {
+    pub fn new() -> Table {
+        Table {
+            entries: Vec::new(),
+            count: 0,
+        }
+    }
+
+    pub fn get(&self, key: *mut StringObj) -> Option<RuntimeValue> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let entry = &self.entries[self.find_entry(key)];
+        if entry.key.is_null() {
+            None
+        } else {
+            Some(entry.value.unpack())
+        }
+    }
+
+    /// Returns true if the key wasn't present in the table before
+    pub fn set(&mut self, key: *mut StringObj, value: RuntimeValue) -> bool {
+        if (self.count + 1) as f64 > self.entries.len() as f64 * MAX_LOAD {
+            self.grow();
+        }
+
+        let index = self.find_entry(key);
+        let entry = &mut self.entries[index];
+
+        let is_new = entry.key.is_null();
+        // Reusing a tombstone doesn't change the count
+        if is_new && !entry.is_tombstone() {
+            self.count += 1;
+        }
+
+        entry.key = key;
+        entry.value = PackedValue::pack(value);
+
+        is_new
+    }
+
+    /// Returns true if the key was present in the table
+    pub fn delete(&mut self, key: *mut StringObj) -> bool {
+        if self.count == 0 {
+            return false;
+        }
+
+        let index = self.find_entry(key);
+        if self.entries[index].key.is_null() {
+            return false;
+        }
+
+        self.entries[index] = Entry::TOMBSTONE;
+        true
+    }
+
+    fn find_entry(&self, key: *mut StringObj) -> usize {
+        let mask = self.entries.len() - 1;
+        let mut index = unsafe { (*key).hash() } as usize & mask;
+        let mut tombstone = None;
+
+        loop {
+            let entry = &self.entries[index];
+
+            if entry.key.is_null() {
+                if entry.is_tombstone() {
+                    tombstone.get_or_insert(index);
+                } else {
+                    // Prefer reusing a tombstone we passed on the way
+                    return tombstone.unwrap_or(index);
+                }
+            } else if entry.key == key {
+                // Strings are interned, so comparing the pointers is enough
+                return index;
+            }
+
+            index = (index + 1) & mask;
+        }
+    }
+
+    /// Looks up an interned string by its contents
+    pub fn find_string(&self, contents: &str, hash: u32) -> Option<*mut StringObj> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let mask = self.entries.len() - 1;
+        let mut index = hash as usize & mask;
+
+        loop {
+            let entry = &self.entries[index];
+
+            if entry.key.is_null() {
+                // Stop at an empty entry, skip over tombstones
+                if !entry.is_tombstone() {
+                    return None;
+                }
+            } else if unsafe { (*entry.key).hash() == hash && (*entry.key).as_str() == contents } {
+                return Some(entry.key);
+            }
+
+            index = (index + 1) & mask;
+        }
+    }
+
+    /// Copies all entries into another table, overwriting existing keys
+    pub fn add_all(&self, to: &mut Table) {
+        for (key, value) in self.iter() {
+            to.set(key, value);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (*mut StringObj, RuntimeValue)> + '_ {
+        self.entries
+            .iter()
+            .filter(|e| !e.key.is_null())
+            .map(|e| (e.key, e.value.unpack()))
+    }
+
+    /// Deletes the entries whose keys weren't marked by the garbage collector.
+    /// Used for the interned strings, which the table doesn't keep alive.
+    pub fn remove_unmarked(&mut self) {
+        for entry in self.entries.iter_mut() {
+            if !entry.key.is_null() && unsafe { !(*entry.key).obj.is_marked } {
+                *entry = Entry::TOMBSTONE;
+            }
+        }
+    }
+
+    fn grow(&mut self) {
+        let capacity = (self.entries.len() * 2).max(MIN_CAPACITY);
+        let old_entries = std::mem::replace(&mut self.entries, vec![Entry::EMPTY; capacity]);
+
+        // Tombstones aren't copied over
+        self.count = 0;
+        for entry in old_entries.iter().filter(|e| !e.key.is_null()) {
+            let index = self.find_entry(entry.key);
+            self.entries[index] = *entry;
+            self.count += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ptr;
+
+    use crate::{
+        runtime_val::{RuntimeValue, StringObj},
+        table::{hash_str, Table},
+    };
+
+    fn with_keys<F: FnOnce(&[*mut StringObj])>(names: &[&str], f: F) {
+        let keys: Vec<*mut StringObj> = names.iter().map(|n| StringObj::new(n)).collect();
+        f(&keys);
+
+        for k in keys {
+            unsafe { ptr::drop_in_place(k) };
+        }
+    }
+
+    fn get_number(table: &Table, key: *mut StringObj) -> Option<f64> {
+        match table.get(key) {
+            Some(RuntimeValue::Number(n)) => Some(n),
+            Some(_) => panic!("expected a number"),
+            None => None,
+        }
+    }
+
+    #[test]
+    fn set_and_get() {
+        with_keys(&["foo", "bar", "baz"], |keys| {
+            let mut table = Table::new();
+
+            assert!(table.set(keys[0], RuntimeValue::Number(1.0)));
+            assert!(table.set(keys[1], RuntimeValue::Number(2.0)));
+            assert!(!table.set(keys[0], RuntimeValue::Number(3.0)));
+
+            assert_eq!(get_number(&table, keys[0]), Some(3.0));
+            assert_eq!(get_number(&table, keys[1]), Some(2.0));
+            assert_eq!(get_number(&table, keys[2]), None);
+        });
+    }
+
+    #[test]
+    fn delete() {
+        with_keys(&["foo", "bar"], |keys| {
+            let mut table = Table::new();
+
+            table.set(keys[0], RuntimeValue::Number(1.0));
+            table.set(keys[1], RuntimeValue::Number(2.0));
+
+            assert!(table.delete(keys[0]));
+            assert!(!table.delete(keys[0]));
+
+            assert_eq!(get_number(&table, keys[0]), None);
+            assert_eq!(get_number(&table, keys[1]), Some(2.0));
+
+            // Reinserting reuses the tombstone
+            assert!(table.set(keys[0], RuntimeValue::Number(4.0)));
+            assert_eq!(get_number(&table, keys[0]), Some(4.0));
+        });
+    }
+
+    #[test]
+    fn find_string() {
+        with_keys(&["foo", "bar"], |keys| {
+            let mut table = Table::new();
+
+            table.set(keys[0], RuntimeValue::Nil);
+
+            assert_eq!(table.find_string("foo", hash_str("foo")), Some(keys[0]));
+            assert_eq!(table.find_string("bar", hash_str("bar")), None);
+        });
+    }
+
+    #[test]
+    fn add_all() {
+        with_keys(&["foo", "bar", "baz"], |keys| {
+            let mut from = Table::new();
+            let mut to = Table::new();
+
+            from.set(keys[0], RuntimeValue::Number(1.0));
+            from.set(keys[1], RuntimeValue::Number(2.0));
+            from.delete(keys[1]);
+
+            to.set(keys[0], RuntimeValue::Number(3.0));
+            to.set(keys[2], RuntimeValue::Number(4.0));
+
+            from.add_all(&mut to);
+
+            assert_eq!(get_number(&to, keys[0]), Some(1.0));
+            assert_eq!(get_number(&to, keys[1]), None);
+            assert_eq!(get_number(&to, keys[2]), Some(4.0));
+        });
+    }
+
+    #[test]
+    fn grow() {
+        let names: Vec<String> = (0..100).map(|i| format!("var{}", i)).collect();
+        let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+
+        with_keys(&names, |keys| {
+            let mut table = Table::new();
+
+            for (i, k) in keys.iter().enumerate() {
+                table.set(*k, RuntimeValue::Number(i as f64));
+            }
+
+            for (i, k) in keys.iter().enumerate() {
+                assert_eq!(get_number(&table, *k), Some(i as f64));
+            }
+        });
+    }
 }
@@ -1,15 +1,57 @@
 use crate::lexer::LexError;
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Token<'l> {
     pub typ: TokenType,
     pub lexeme: &'l str,
     pub line: usize,
+    // Byte offset of the start of the token in the source text
+    pub offset: usize,
+    // Length of the token in bytes
+    pub len: usize,
+    // Column of the start of the token, counted in characters from 1
+    pub column: usize,
 }
 
 impl<'l> Token<'l> {
-    pub fn new(typ: TokenType, lexeme: &'l str, line: usize) -> Token<'l> {
-        Token { typ, lexeme, line }
+    pub fn new(
+        typ: TokenType,
+        lexeme: &'l str,
+        line: usize,
+        offset: usize,
+        column: usize,
+    ) -> Token<'l> {
+        Token {
+            typ,
+            lexeme,
+            line,
+            offset,
+            len: lexeme.len(),
+            column,
+        }
     }
+
+    /// A token made up by the compiler, located at an existing token
+    pub fn synthetic(typ: TokenType, lexeme: &'l str, at: &Token) -> Token<'l> {
+        Token { typ, lexeme, ..*at }
+    }
+
+    pub fn span(&self) -> Span {
+        Span {
+            offset: self.offset,
+            len: self.len,
+            line: self.line,
+            column: self.column,
+        }
+    }
+}
+
+/// Location of a piece of source code, see Token for the meaning of the fields
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span {
+    pub offset: usize,
+    pub len: usize,
+    pub line: usize,
+    pub column: usize,
 }
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum TokenType {
@@ -25,6 +67,8 @@ pub enum TokenType {
     Semicolon,
     Slash,
     Star,
+    Question,
+    Colon,
 
     // One Or Two Character Tokens.
     Bang,
@@ -40,6 +84,10 @@ pub enum TokenType {
     Identifier,
     String,
     Number,
+    // Parts of strings with interpolated expressions, see the lexer
+    InterpolationStart,
+    InterpolationMiddle,
+    InterpolationEnd,
 
     // Keywords.
     And,
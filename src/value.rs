@@ -0,0 +1,106 @@
+use crate::{heap::Heap, runtime_val::RuntimeValue};
+
+/// A Lox value that can be safely held by the host program.
+/// Strings are copied out of the VM, other objects are only described.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Object(Object),
+}
+
+/// A function, class or instance living inside the VM
+#[derive(Clone, Debug, PartialEq)]
+pub struct Object {
+    type_name: &'static str,
+    repr: String,
+}
+
+impl Object {
+    /// Same as the 'type' of the value in Lox: "function", "class" or "instance"
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+}
+
+impl core::fmt::Display for Object {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        write!(f, "{}", self.repr)
+    }
+}
+
+impl core::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Object(o) => write!(f, "{}", o),
+        }
+    }
+}
+
+impl Value {
+    /// Same as the 'type' of the value in Lox
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Object(o) => o.type_name,
+        }
+    }
+
+    /// Safety: the value must point to live objects
+    pub(crate) unsafe fn from_runtime(value: RuntimeValue) -> Value {
+        match value {
+            RuntimeValue::Nil => Value::Nil,
+            RuntimeValue::Bool(b) => Value::Bool(b),
+            RuntimeValue::Number(n) => Value::Number(n),
+            RuntimeValue::String(s) => Value::String((*s).as_str().to_string()),
+            _ => Value::Object(Object {
+                type_name: value.type_repr(),
+                repr: value.to_string(),
+            }),
+        }
+    }
+
+    /// Objects can't be moved back into the VM, returns None for them
+    pub(crate) fn to_runtime(&self, heap: &mut Heap) -> Option<RuntimeValue> {
+        match self {
+            Value::Nil => Some(RuntimeValue::Nil),
+            Value::Bool(b) => Some(RuntimeValue::Bool(*b)),
+            Value::Number(n) => Some(RuntimeValue::Number(*n)),
+            Value::String(s) => Some(RuntimeValue::String(heap.intern(s))),
+            Value::Object(_) => None,
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Value {
+        Value::Number(n)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::String(s)
+    }
+}
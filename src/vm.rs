@@ -1,30 +1,113 @@
 use std::{
-    collections::{HashMap, HashSet},
+    io::{self, Write},
     mem, ptr,
 };
 
 use super::{
-    bytecode::{opcodes, Bytecode, Chunk},
-    runtime_val::{Obj, ObjTyp, RuntimeValue, StringObj},
+    bytecode::{opcodes, Bytecode, Chunk, LONG_LOCAL},
+    compiler::Compiler,
+    heap::Heap,
+    packed_val::PackedValue,
+    runtime_val::{
+        ClassObj, ClosureObj, FunctionObj, NativeFn, NativeObj, Obj, RuntimeValue, StringObj,
+        UpvalueObj,
+    },
+    stdlib::{self, Rng},
+    table::Table,
+    token::Span,
+    value::Value,
 };
 
-const STACK_SIZE: usize = 0xFF;
+// The value stack starts small and doubles when it fills up, up to the limit
+const INITIAL_STACK_SIZE: usize = 0x100;
+const DEFAULT_STACK_LIMIT: usize = 0x10_0000;
+const DEFAULT_CALL_DEPTH_LIMIT: usize = 0x1_0000;
 
-type RuntimeResult = Result<(), LoxRuntimeErr>;
+type RuntimeResult = Result<(), RuntimeError>;
 
-pub struct Vm<'s> {
-    chunk: Chunk,
-    ip: usize,
+pub struct Vm {
+    // The currently executing function
+    frame: CallFrame,
+    // Suspended callers of the current function
+    frames: Vec<CallFrame>,
     sp: usize,
 
-    stack: [RuntimeValue; STACK_SIZE],
+    stack: Vec<PackedValue>,
+    // Maximum number of values on the stack
+    stack_limit: usize,
+    // Maximum number of nested calls
+    call_depth_limit: usize,
+    // Upvalues still pointing into the stack, sorted by their slot
+    open_upvalues: Vec<*mut UpvalueObj>,
+
+    globals: Table,
+    heap: Heap,
+    // Name of class initializers, interned once for faster lookups
+    init_string: *mut StringObj,
+
+    // Where PRINT writes to
+    out: Box<dyn Write>,
+    // State of the 'random' native
+    rng: Rng,
+    // Arguments of the script, read by the 'argc' and 'arg' natives
+    args: Vec<String>,
+}
 
-    strings: HashSet<&'s StringObj>,
-    objects: *mut Obj,
+/// The part of the VM a native function can use while it runs.
+/// The function reports errors by returning them, they become Lox runtime errors.
+pub struct NativeContext<'vm> {
+    vm: &'vm mut Vm,
+}
+
+impl NativeContext<'_> {
+    pub fn get_global(&mut self, name: &str) -> Option<Value> {
+        self.vm.get_global_value(name)
+    }
+
+    /// Strings are copied into the VM, objects can't be stored and return an error
+    pub fn set_global(&mut self, name: &str, value: impl Into<Value>) -> Result<(), String> {
+        if self.vm.set_global_value(name, &value.into()) {
+            Ok(())
+        } else {
+            Err(format!("objects can't be stored in global '{}'", name))
+        }
+    }
+
+    pub(crate) fn rng(&mut self) -> &mut Rng {
+        &mut self.vm.rng
+    }
+
+    pub(crate) fn args(&self) -> &[String] {
+        &self.vm.args
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CallFrame {
+    closure: *mut ClosureObj,
+    ip: usize,
+    // Index of the first stack slot belonging to the function
+    slots: usize,
+}
+
+impl CallFrame {
+    fn new(closure: *mut ClosureObj, slots: usize) -> CallFrame {
+        CallFrame {
+            closure,
+            ip: 0,
+            slots,
+        }
+    }
 }
 
 macro_rules! binary_op {
     ($name:ident, $op:tt, $typ:ident) => {
+        binary_op!($name as $name, $op, $typ);
+    };
+    // Errors are reported under the name of the operation as it is written without fusing
+    ($name:ident as $error_name:ident, $op:tt, $typ:ident $(, $negate:tt)?) => {
+        // The negated comparisons must be true for NaN, same as the unfused opcodes
+        #[allow(clippy::neg_cmp_op_on_partial_ord)]
         #[inline]
         fn $name(&mut self) -> RuntimeResult {
             let first = self.peek(2)?;
@@ -32,48 +115,141 @@ macro_rules! binary_op {
 
             match (first, second) {
                 (RuntimeValue::Number(n1), RuntimeValue::Number(n2)) => {
-                    self.stack[self.sp - 2] = RuntimeValue::$typ(n1 $op n2);
+                    self.stack[self.sp - 2] = PackedValue::pack(RuntimeValue::$typ($($negate)? (n1 $op n2)));
                     self.sp -= 1;
                     Ok(())
                 }
                 _ => {
-                    eprintln!(
-                        "runtime error at line {}: cannot apply '{}' to {} and {}",
-                        self.chunk.get_line_at_ip(self.ip),
-                        std::stringify!($name),
+                    Err(self.error(format!(
+                        "cannot apply '{}' to {} and {}",
+                        std::stringify!($error_name),
                         first.type_repr(),
                         second.type_repr()
-                    );
-                    Err(LoxRuntimeErr::InvalidType)
+                    )))
                 }
             }
         }
     };
 }
 
-impl<'s> Vm<'s> {
-    pub fn new(chunk: Chunk) -> Vm<'s> {
+impl Vm {
+    pub fn new(mut heap: Heap) -> Vm {
+        let init_string = heap.intern("init");
+
         let mut vm = Vm {
-            chunk,
-            ip: 0,
+            frame: CallFrame::new(ptr::null_mut(), 0),
+            frames: Vec::new(),
             sp: 0,
 
-            stack: [RuntimeValue::Nil; STACK_SIZE],
+            stack: vec![PackedValue::NIL; INITIAL_STACK_SIZE],
+            stack_limit: DEFAULT_STACK_LIMIT,
+            call_depth_limit: DEFAULT_CALL_DEPTH_LIMIT,
+            open_upvalues: Vec::new(),
 
-            strings: HashSet::new(),
-            objects: ptr::null_mut(),
-        };
+            globals: Table::new(),
+            heap,
+            init_string,
 
-        vm.link_objects();
-        vm.intern_strings();
+            out: Box::new(io::stdout()),
+            rng: Rng::from_time(),
+            args: Vec::new(),
+        };
 
+        stdlib::install(&mut vm);
         vm
     }
 
-    pub fn execute(&mut self) -> RuntimeResult {
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        self.out = out;
+    }
+
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
+    pub(crate) fn output(&mut self) -> &mut dyn Write {
+        &mut *self.out
+    }
+
+    /// Limits the number of values on the stack, exceeding it is a stack overflow
+    pub fn set_stack_limit(&mut self, values: usize) {
+        self.stack_limit = values.max(1);
+        // Values in use are kept, the limit applies to the following pushes
+        self.stack.truncate(self.stack_limit.max(self.sp));
+    }
+
+    /// Limits the number of nested calls, exceeding it is a stack overflow
+    pub fn set_call_depth_limit(&mut self, calls: usize) {
+        self.call_depth_limit = calls;
+    }
+
+    pub fn set_stress_gc(&mut self, stress_gc: bool) {
+        self.heap.set_stress_gc(stress_gc);
+    }
+
+    pub fn get_global_value(&mut self, name: &str) -> Option<Value> {
+        let name = self.heap.intern(name);
+        let value = self.globals.get(name)?;
+        Some(unsafe { Value::from_runtime(value) })
+    }
+
+    /// Makes the Rust function callable from Lox as a global function
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        // No collection happens here, so the new objects can't be freed before they're stored
+        let name = self.heap.intern(name);
+        let native = self.heap.new_native(name, arity, function);
+        self.globals.set(name, RuntimeValue::Native(native));
+    }
+
+    /// Returns false if the value is an object, those can't be moved into the VM
+    pub fn set_global_value(&mut self, name: &str, value: &Value) -> bool {
+        // No collection happens here, so the new strings can't be freed before they're stored
+        let name = self.heap.intern(name);
+        match value.to_runtime(&mut self.heap) {
+            Some(value) => {
+                self.globals.set(name, value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Creates a compiler for code that will run on this VM.
+    /// The globals of the VM survive garbage collections done while compiling.
+    pub(crate) fn compiler<'t>(&'t mut self, text: &'t str) -> Compiler<'t> {
+        let mut compiler = Compiler::new(text, &mut self.heap);
+        compiler.add_root(RuntimeValue::String(self.init_string));
+        compiler.add_root_table(&self.globals);
+        compiler
+    }
+
+    pub(crate) fn execute(&mut self, script: *mut FunctionObj) -> RuntimeResult {
+        // Closures that outlived a failed run still point at its stack slots
+        self.close_upvalues(0);
+        self.sp = 0;
+        // The previous script may have been freed since it finished
+        self.frame = CallFrame::new(ptr::null_mut(), 0);
+        self.frames.clear();
+
+        // The script occupies the first slot, same as any other called function.
+        // It's pushed before wrapping it, so that it's a root during collection.
+        self.push(RuntimeValue::Function(script))?;
+        self.maybe_collect();
+        let closure = self.heap.new_closure(script);
+        self.stack[0] = PackedValue::pack(RuntimeValue::Closure(closure));
+        self.frame = CallFrame::new(closure, 0);
+
+        let result = self.run();
+        if result.is_err() {
+            // Before compiling the next script, the stack isn't a root anymore
+            self.close_upvalues(0);
+        }
+        result
+    }
+
+    fn run(&mut self) -> RuntimeResult {
         loop {
-            let opcode = self.chunk.code[self.ip];
-            self.ip += 1;
+            let opcode = self.read_byte();
 
             match opcode {
                 opcodes::CONSTANT => self.constant()?,
@@ -83,16 +259,35 @@ impl<'s> Vm<'s> {
                 opcodes::POP => {
                     self.pop()?;
                 }
-                opcodes::GET_LOCAL => self.get_local()?,
-                opcodes::SET_LOCAL => self.set_local()?,
+                opcodes::GET_LOCAL => {
+                    let slot = self.read_byte() as usize;
+                    self.get_local(slot)?
+                }
+                opcodes::SET_LOCAL => {
+                    let slot = self.read_byte() as usize;
+                    self.set_local(slot)?
+                }
                 opcodes::GET_GLOBAL => {
-                    unimplemented!("global variable expressions are unimplemented")
+                    let name = self.read_string();
+                    self.get_global(name)?
                 }
                 opcodes::DEFINE_GLOBAL => {
-                    unimplemented!("global variable definitions are unimplemented")
+                    let name = self.read_string();
+                    self.define_global(name)?
                 }
                 opcodes::SET_GLOBAL => {
-                    unimplemented!("global variable expression are unimplemented")
+                    let name = self.read_string();
+                    self.set_global(name)?
+                }
+                opcodes::GET_UPVALUE => self.get_upvalue()?,
+                opcodes::SET_UPVALUE => self.set_upvalue()?,
+                opcodes::GET_PROPERTY => {
+                    let name = self.read_string();
+                    self.get_property(name)?
+                }
+                opcodes::SET_PROPERTY => {
+                    let name = self.read_string();
+                    self.set_property(name)?
                 }
                 opcodes::EQUAL => self.equal()?,
                 opcodes::GREATER => self.greater()?,
@@ -104,9 +299,105 @@ impl<'s> Vm<'s> {
                 opcodes::NOT => self.not()?,
                 opcodes::NEGATE => self.negate()?,
                 opcodes::PRINT => self.print()?,
-                opcodes::RETURN => return Ok(()),
+                opcodes::JUMP => self.jump(),
+                opcodes::JUMP_IF_FALSE => self.jump_if_false()?,
+                opcodes::LOOP => self.loop_back(),
+                opcodes::CALL => {
+                    let arg_count = self.read_byte() as usize;
+                    self.call_value(self.peek(arg_count + 1)?, arg_count)?;
+                }
+                opcodes::INVOKE => {
+                    let name = self.read_string();
+                    self.invoke(name)?
+                }
+                opcodes::CLOSURE => {
+                    let index = self.read_byte() as usize;
+                    self.closure(index)?
+                }
+                opcodes::CLOSE_UPVALUE => {
+                    self.close_upvalues(self.sp - 1);
+                    self.pop()?;
+                }
+                opcodes::CLASS => {
+                    let name = self.read_string();
+                    self.class(name)?
+                }
+                opcodes::INHERIT => self.inherit()?,
+                opcodes::GET_SUPER => {
+                    let name = self.read_string();
+                    self.get_super(name)?
+                }
+                opcodes::SUPER_INVOKE => {
+                    let name = self.read_string();
+                    self.super_invoke(name)?
+                }
+                opcodes::METHOD => {
+                    let name = self.read_string();
+                    self.method(name)?
+                }
+                opcodes::RETURN => {
+                    if self.return_from_function()? {
+                        return Ok(());
+                    }
+                }
 
                 opcodes::CONSTANT_LONG => self.constant_long()?,
+                opcodes::STRINGIFY => self.stringify()?,
+                opcodes::GET_LOCAL_LONG => {
+                    let slot = self.read_long_operand();
+                    self.get_local(slot)?
+                }
+                opcodes::SET_LOCAL_LONG => {
+                    let slot = self.read_long_operand();
+                    self.set_local(slot)?
+                }
+                opcodes::GET_GLOBAL_LONG => {
+                    let name = self.read_string_long();
+                    self.get_global(name)?
+                }
+                opcodes::DEFINE_GLOBAL_LONG => {
+                    let name = self.read_string_long();
+                    self.define_global(name)?
+                }
+                opcodes::SET_GLOBAL_LONG => {
+                    let name = self.read_string_long();
+                    self.set_global(name)?
+                }
+                opcodes::CLOSURE_LONG => {
+                    let index = self.read_long_operand();
+                    self.closure(index)?
+                }
+                opcodes::NOT_EQUAL => self.not_equal()?,
+                opcodes::GREATER_EQUAL => self.greater_equal()?,
+                opcodes::LESS_EQUAL => self.less_equal()?,
+                opcodes::GET_PROPERTY_LONG => {
+                    let name = self.read_string_long();
+                    self.get_property(name)?
+                }
+                opcodes::SET_PROPERTY_LONG => {
+                    let name = self.read_string_long();
+                    self.set_property(name)?
+                }
+                opcodes::GET_SUPER_LONG => {
+                    let name = self.read_string_long();
+                    self.get_super(name)?
+                }
+                opcodes::INVOKE_LONG => {
+                    let name = self.read_string_long();
+                    self.invoke(name)?
+                }
+                opcodes::SUPER_INVOKE_LONG => {
+                    let name = self.read_string_long();
+                    self.super_invoke(name)?
+                }
+                opcodes::CLASS_LONG => {
+                    let name = self.read_string_long();
+                    self.class(name)?
+                }
+                opcodes::METHOD_LONG => {
+                    let name = self.read_string_long();
+                    self.method(name)?
+                }
                 _ => panic!("Invalid or unimplemented opcode: {}", opcode),
             };
         }
@@ -114,73 +405,343 @@ impl<'s> Vm<'s> {
 
     #[inline]
     fn push(&mut self, val: RuntimeValue) -> RuntimeResult {
-        if self.sp < STACK_SIZE {
-            self.stack[self.sp] = val;
-            self.sp += 1;
-            Ok(())
-        } else {
-            Err(LoxRuntimeErr::StackOverflow)
+        if self.sp == self.stack.len() {
+            self.grow_stack()?;
         }
+
+        self.stack[self.sp] = PackedValue::pack(val);
+        self.sp += 1;
+        Ok(())
+    }
+
+    // Upvalues and frames refer to stack slots by index, so moving the stack is fine
+    #[cold]
+    fn grow_stack(&mut self) -> RuntimeResult {
+        if self.stack.len() >= self.stack_limit {
+            return Err(self.error("stack overflow".to_string()));
+        }
+
+        let size = (self.stack.len() * 2).max(1).min(self.stack_limit);
+        self.stack.resize(size, PackedValue::NIL);
+        Ok(())
     }
 
     #[inline]
-    fn pop(&mut self) -> Result<RuntimeValue, LoxRuntimeErr> {
+    fn pop(&mut self) -> Result<RuntimeValue, RuntimeError> {
         if self.sp >= 1 {
             self.sp -= 1;
-            Ok(self.stack[self.sp])
+            Ok(self.stack[self.sp].unpack())
         } else {
-            Err(LoxRuntimeErr::StackUnderflow)
+            Err(self.stack_underflow())
         }
     }
 
     #[inline]
-    fn get_local(&mut self) -> RuntimeResult {
-        let slot = self.read_byte();
-        let val = self.stack[slot as usize];
+    fn get_local(&mut self, slot: usize) -> RuntimeResult {
+        let val = self.stack[self.frame.slots + slot].unpack();
         self.push(val)?;
 
         Ok(())
     }
 
     #[inline]
-    fn set_local(&mut self) -> RuntimeResult {
-        let slot = self.read_byte();
+    fn set_local(&mut self, slot: usize) -> RuntimeResult {
         let val = self.peek(1)?;
 
-        self.stack[slot as usize] = val.clone();
+        self.stack[self.frame.slots + slot] = PackedValue::pack(val);
 
         Ok(())
     }
 
     #[inline]
-    fn peek_mut(&mut self, distance: usize) -> Result<&mut RuntimeValue, LoxRuntimeErr> {
+    fn get_global(&mut self, name: *mut StringObj) -> RuntimeResult {
+        match self.globals.get(name) {
+            Some(val) => self.push(val),
+            None => Err(self.undefined_variable(name)),
+        }
+    }
+
+    #[inline]
+    fn define_global(&mut self, name: *mut StringObj) -> RuntimeResult {
+        let val = self.peek(1)?;
+
+        self.globals.set(name, val);
+        self.pop()?;
+
+        Ok(())
+    }
+
+    #[inline]
+    fn set_global(&mut self, name: *mut StringObj) -> RuntimeResult {
+        let val = self.peek(1)?;
+
+        // Assignment doesn't implicitly declare a variable
+        if self.globals.set(name, val) {
+            self.globals.delete(name);
+            return Err(self.undefined_variable(name));
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn get_upvalue(&mut self) -> RuntimeResult {
+        let index = self.read_byte() as usize;
+        let val = unsafe {
+            let upvalue = (&(*self.frame.closure).upvalues)[index];
+            match (*upvalue).closed {
+                Some(val) => val,
+                None => self.stack[(*upvalue).slot].unpack(),
+            }
+        };
+
+        self.push(val)
+    }
+
+    #[inline]
+    fn set_upvalue(&mut self) -> RuntimeResult {
+        let index = self.read_byte() as usize;
+        let val = self.peek(1)?;
+
+        unsafe {
+            let upvalue = (&(*self.frame.closure).upvalues)[index];
+            match (*upvalue).closed {
+                Some(_) => (*upvalue).closed = Some(val),
+                None => self.stack[(*upvalue).slot] = PackedValue::pack(val),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn get_property(&mut self, name: *mut StringObj) -> RuntimeResult {
+        let instance = match self.peek(1)? {
+            RuntimeValue::Instance(instance) => instance,
+            val => return Err(self.not_an_instance("properties", val)),
+        };
+
+        // Fields shadow methods
+        if let Some(val) = unsafe { (*instance).fields.get(name) } {
+            self.poke(1, val)?;
+            return Ok(());
+        }
+
+        self.bind_method(unsafe { (*instance).class }, name)
+    }
+
+    #[inline]
+    fn set_property(&mut self, name: *mut StringObj) -> RuntimeResult {
+        let instance = match self.peek(2)? {
+            RuntimeValue::Instance(instance) => instance,
+            val => return Err(self.not_an_instance("fields", val)),
+        };
+
+        let val = self.pop()?;
+        unsafe { (*instance).fields.set(name, val) };
+
+        // The assignment expression evaluates to the assigned value
+        self.poke(1, val)?;
+        Ok(())
+    }
+
+    /// Replaces the instance on top of the stack with its method bound to it
+    fn bind_method(&mut self, class: *mut ClassObj, name: *mut StringObj) -> RuntimeResult {
+        let method = match unsafe { (*class).methods.get(name) } {
+            Some(RuntimeValue::Closure(method)) => method,
+            _ => return Err(self.undefined_property(name)),
+        };
+
+        self.maybe_collect();
+        let receiver = self.peek(1)?;
+        let bound_method = self.heap.new_bound_method(receiver, method);
+        self.poke(1, RuntimeValue::BoundMethod(bound_method))?;
+
+        Ok(())
+    }
+
+    #[inline]
+    fn class(&mut self, name: *mut StringObj) -> RuntimeResult {
+        self.maybe_collect();
+        let class = self.heap.new_class(name);
+        self.push(RuntimeValue::Class(class))
+    }
+
+    #[inline]
+    fn method(&mut self, name: *mut StringObj) -> RuntimeResult {
+        let method = self.peek(1)?;
+
+        match self.peek(2)? {
+            RuntimeValue::Class(class) => unsafe { (*class).methods.set(name, method) },
+            _ => panic!("COMPILER ERROR: method isn't defined on a class"),
+        };
+
+        self.pop()?;
+        Ok(())
+    }
+
+    /// Copies the methods of the superclass down into the subclass,
+    /// methods defined later in the subclass body override them
+    #[inline]
+    fn inherit(&mut self) -> RuntimeResult {
+        let superclass = match self.peek(2)? {
+            RuntimeValue::Class(class) => class,
+            val => {
+                return Err(self.error(format!(
+                    "superclass must be a class, got {}",
+                    val.type_repr()
+                )));
+            }
+        };
+
+        match self.peek(1)? {
+            RuntimeValue::Class(subclass) => unsafe {
+                (*superclass).methods.add_all(&mut (*subclass).methods)
+            },
+            _ => panic!("COMPILER ERROR: inheriting into a value that isn't a class"),
+        }
+
+        self.pop()?;
+        Ok(())
+    }
+
+    #[inline]
+    fn pop_superclass(&mut self) -> Result<*mut ClassObj, RuntimeError> {
+        match self.pop()? {
+            RuntimeValue::Class(class) => Ok(class),
+            _ => panic!("COMPILER ERROR: 'super' isn't a class"),
+        }
+    }
+
+    #[inline]
+    fn get_super(&mut self, name: *mut StringObj) -> RuntimeResult {
+        let superclass = self.pop_superclass()?;
+        self.bind_method(superclass, name)
+    }
+
+    #[inline]
+    fn super_invoke(&mut self, name: *mut StringObj) -> RuntimeResult {
+        let arg_count = self.read_byte() as usize;
+        let superclass = self.pop_superclass()?;
+        self.invoke_from_class(superclass, name, arg_count)
+    }
+
+    /// Creates an error at the current instruction, with a trace of the call stack
+    fn error(&self, message: String) -> RuntimeError {
+        let trace: Vec<TraceFrame> = std::iter::once(&self.frame)
+            .chain(self.frames.iter().rev())
+            .map(|frame| unsafe {
+                let function = (*frame.closure).function;
+                // The ip already points past the failed instruction
+                let span = (*function).chunk.get_span_at_ip(frame.ip.saturating_sub(1));
+                TraceFrame {
+                    function: (*function).to_string(),
+                    line: span.line,
+                    span,
+                }
+            })
+            .collect();
+
+        RuntimeError {
+            message,
+            line: trace[0].line,
+            span: trace[0].span,
+            trace,
+        }
+    }
+
+    fn stack_underflow(&self) -> RuntimeError {
+        self.error("stack underflow".to_string())
+    }
+
+    fn not_an_instance(&self, what: &str, val: RuntimeValue) -> RuntimeError {
+        self.error(format!(
+            "only instances have {}, got {}",
+            what,
+            val.type_repr()
+        ))
+    }
+
+    fn undefined_property(&self, name: *mut StringObj) -> RuntimeError {
+        self.error(format!("undefined property '{}'", unsafe {
+            (*name).as_str()
+        }))
+    }
+
+    fn undefined_variable(&self, name: *mut StringObj) -> RuntimeError {
+        self.error(format!("undefined variable '{}'", unsafe {
+            (*name).as_str()
+        }))
+    }
+
+    /// Replaces the value returned by peek with the same distance
+    #[inline]
+    fn poke(&mut self, distance: usize, val: RuntimeValue) -> RuntimeResult {
         if self.sp.checked_sub(distance).is_some() {
-            Ok(&mut self.stack[self.sp - distance])
+            self.stack[self.sp - distance] = PackedValue::pack(val);
+            Ok(())
         } else {
-            Err(LoxRuntimeErr::StackUnderflow)
+            Err(self.stack_underflow())
         }
     }
 
     #[inline]
-    fn peek(&self, distance: usize) -> Result<&RuntimeValue, LoxRuntimeErr> {
+    fn peek(&self, distance: usize) -> Result<RuntimeValue, RuntimeError> {
         if self.sp.checked_sub(distance).is_some() {
-            Ok(&self.stack[self.sp - distance])
+            Ok(self.stack[self.sp - distance].unpack())
         } else {
-            Err(LoxRuntimeErr::StackUnderflow)
+            Err(self.stack_underflow())
         }
     }
 
+    #[inline]
+    fn chunk(&self) -> &Chunk {
+        unsafe { &(*(*self.frame.closure).function).chunk }
+    }
+
     #[inline]
     fn read_byte(&mut self) -> Bytecode {
-        let val = self.chunk.code[self.ip];
-        self.ip += 1;
+        let val = self.chunk().code[self.frame.ip];
+        self.frame.ip += 1;
         val
     }
 
+    #[inline]
+    fn read_u16(&mut self) -> u16 {
+        let bytes = [self.read_byte(), self.read_byte()];
+        u16::from_le_bytes(bytes)
+    }
+
+    #[inline]
+    fn read_long_operand(&mut self) -> usize {
+        let bytes = [self.read_byte(), self.read_byte(), self.read_byte(), 0];
+        u32::from_le_bytes(bytes) as usize
+    }
+
+    #[inline]
+    fn read_string(&mut self) -> *mut StringObj {
+        let index = self.read_byte() as usize;
+        self.string_constant(index)
+    }
+
+    #[inline]
+    fn read_string_long(&mut self) -> *mut StringObj {
+        let index = self.read_long_operand();
+        self.string_constant(index)
+    }
+
+    #[inline]
+    fn string_constant(&self, index: usize) -> *mut StringObj {
+        match self.chunk().constants[index].unpack() {
+            RuntimeValue::String(string_ptr) => string_ptr,
+            _ => panic!("COMPILER ERROR: variable name isn't a string constant"),
+        }
+    }
+
     #[inline]
     fn constant(&mut self) -> RuntimeResult {
         let index = self.read_byte();
-        let value = self.chunk.constants[index as usize];
+        let value = self.chunk().constants[index as usize].unpack();
 
         self.push(value)?;
         Ok(())
@@ -188,14 +749,8 @@ impl<'s> Vm<'s> {
 
     #[inline]
     fn constant_long(&mut self) -> RuntimeResult {
-        let mut bytes = [0; 4];
-
-        for i in 0..3 {
-            bytes[i] = self.read_byte();
-        }
-
-        let index = u32::from_le_bytes(bytes);
-        let value = self.chunk.constants[index as usize];
+        let index = self.read_long_operand();
+        let value = self.chunk().constants[index].unpack();
 
         self.push(value)?;
         Ok(())
@@ -208,31 +763,24 @@ impl<'s> Vm<'s> {
 
         match (first, second) {
             (RuntimeValue::Number(n1), RuntimeValue::Number(n2)) => {
-                self.stack[self.sp - 2] = RuntimeValue::Number(n1 + n2);
+                self.stack[self.sp - 2] = PackedValue::pack(RuntimeValue::Number(n1 + n2));
                 self.sp -= 1;
                 Ok(())
             }
-            (RuntimeValue::String(s1), RuntimeValue::String(s2)) => unsafe {
-                let new_str_ptr = (**s1).concat(*s2);
-                //println!("concated: {}", (*new_str_ptr).as_str());
-                // FIXME: not great, add string to linked list
-                (*new_str_ptr).next_obj = self.objects;
-                self.objects = (*new_str_ptr).as_obj_ptr();
-
-                self.stack[self.sp - 2] = RuntimeValue::String(new_str_ptr);
+            (RuntimeValue::String(s1), RuntimeValue::String(s2)) => {
+                self.maybe_collect();
+                let new_str_ptr = self.heap.concat(s1, s2);
+
+                self.stack[self.sp - 2] = PackedValue::pack(RuntimeValue::String(new_str_ptr));
                 self.sp -= 1;
                 // TODO: string concatenation could return an error
                 Ok(())
-            },
-            _ => {
-                eprintln!(
-                    "Runtime error at line {}: cannot apply 'add' to {} and {}",
-                    self.chunk.get_line_at_ip(self.ip),
-                    first.type_repr(),
-                    second.type_repr()
-                );
-                Err(LoxRuntimeErr::InvalidType)
             }
+            _ => Err(self.error(format!(
+                "cannot apply 'add' to {} and {}",
+                first.type_repr(),
+                second.type_repr()
+            ))),
         }
     }
 
@@ -242,12 +790,14 @@ impl<'s> Vm<'s> {
 
     binary_op!(greater, >, Bool);
     binary_op!(less, <, Bool);
+    // Same as the inverse comparison followed by NOT
+    binary_op!(greater_equal as less, <, Bool, !);
+    binary_op!(less_equal as greater, >, Bool, !);
 
     #[inline]
     fn not(&mut self) -> RuntimeResult {
-        let peeked = self.peek_mut(1)?;
-        *peeked = RuntimeValue::Bool(Vm::is_falsy(*peeked));
-        Ok(())
+        let peeked = self.peek(1)?;
+        self.poke(1, RuntimeValue::Bool(Vm::is_falsy(peeked)))
     }
 
     #[inline]
@@ -259,86 +809,467 @@ impl<'s> Vm<'s> {
     }
 
     #[inline]
-    fn negate(&mut self) -> RuntimeResult {
-        let peeked = self.peek_mut(1)?;
+    fn not_equal(&mut self) -> RuntimeResult {
+        let equal = Vm::values_equal(self.pop()?, self.pop()?);
+        self.push(RuntimeValue::Bool(!equal))
+    }
 
-        match peeked {
-            RuntimeValue::Bool(_) | RuntimeValue::String(_) => Err(LoxRuntimeErr::InvalidType),
+    #[inline]
+    fn negate(&mut self) -> RuntimeResult {
+        match self.peek(1)? {
             RuntimeValue::Number(n) => {
-                *peeked = RuntimeValue::Number(-*n);
+                self.poke(1, RuntimeValue::Number(-n))?;
                 Ok(())
             }
-            RuntimeValue::Nil => Err(LoxRuntimeErr::MissingOperand),
+            RuntimeValue::Nil => Err(self.error("operand of 'negate' is missing".to_string())),
+            val => Err(self.error(format!("cannot apply 'negate' to {}", val.type_repr()))),
         }
     }
 
+    #[inline]
+    fn stringify(&mut self) -> RuntimeResult {
+        let val = self.peek(1)?;
+        if let RuntimeValue::String(_) = val {
+            return Ok(());
+        }
+
+        // The value stays on the stack while the string is allocated
+        self.maybe_collect();
+        let string = self.heap.intern(&val.to_string());
+        self.poke(1, RuntimeValue::String(string))?;
+        Ok(())
+    }
+
     #[inline]
     fn print(&mut self) -> RuntimeResult {
         let val = self.pop()?;
-        println!("{}", val);
+        writeln!(self.out, "{}", val)
+            .map_err(|e| self.error(format!("could not write output: {}", e)))
+    }
+
+    #[inline]
+    fn jump(&mut self) {
+        let offset = self.read_u16();
+        self.frame.ip += offset as usize;
+    }
+
+    #[inline]
+    fn jump_if_false(&mut self) -> RuntimeResult {
+        let offset = self.read_u16();
+        // The condition is left on the stack, the compiler emits explicit POPs
+        if Vm::is_falsy(self.peek(1)?) {
+            self.frame.ip += offset as usize;
+        }
+
         Ok(())
     }
 
     #[inline]
-    fn values_equal(val1: RuntimeValue, val2: RuntimeValue) -> bool {
-        match (val1, val2) {
-            (RuntimeValue::Bool(b1), RuntimeValue::Bool(b2)) => b1 == b2,
-            (RuntimeValue::Number(n1), RuntimeValue::Number(n2)) => n1 == n2,
-            (RuntimeValue::String(s1), RuntimeValue::String(s2)) => unsafe {
-                (*s1).as_str() == (*s2).as_str()
+    fn loop_back(&mut self) {
+        let offset = self.read_u16();
+        self.frame.ip -= offset as usize;
+    }
+
+    #[inline]
+    fn call_value(&mut self, callee: RuntimeValue, arg_count: usize) -> RuntimeResult {
+        match callee {
+            RuntimeValue::Closure(closure) => self.call(closure, arg_count),
+            RuntimeValue::BoundMethod(bound_method) => unsafe {
+                // The receiver takes the place of the callee, becoming 'this'
+                self.stack[self.sp - arg_count - 1] = PackedValue::pack((*bound_method).receiver);
+                self.call((*bound_method).method, arg_count)
             },
-            (RuntimeValue::Nil, RuntimeValue::Nil) => true,
-            _ => false,
+            RuntimeValue::Native(native) => self.call_native(native, arg_count),
+            RuntimeValue::Class(class) => {
+                self.maybe_collect();
+                let instance = self.heap.new_instance(class);
+                self.stack[self.sp - arg_count - 1] =
+                    PackedValue::pack(RuntimeValue::Instance(instance));
+
+                match unsafe { (*class).methods.get(self.init_string) } {
+                    Some(RuntimeValue::Closure(initializer)) => self.call(initializer, arg_count),
+                    _ if arg_count != 0 => {
+                        Err(self.error(format!("expected 0 arguments but got {}", arg_count)))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            _ => Err(self.error(format!(
+                "can only call functions, got {}",
+                callee.type_repr()
+            ))),
         }
     }
 
     #[inline]
-    fn is_falsy(val: RuntimeValue) -> bool {
-        match val {
-            RuntimeValue::Nil | RuntimeValue::Bool(false) => true,
-            _ => false,
+    fn invoke(&mut self, name: *mut StringObj) -> RuntimeResult {
+        let arg_count = self.read_byte() as usize;
+
+        let instance = match self.peek(arg_count + 1)? {
+            RuntimeValue::Instance(instance) => instance,
+            val => return Err(self.not_an_instance("methods", val)),
+        };
+
+        // A field holding a function is called like any other value
+        if let Some(field) = unsafe { (*instance).fields.get(name) } {
+            self.stack[self.sp - arg_count - 1] = PackedValue::pack(field);
+            return self.call_value(field, arg_count);
+        }
+
+        self.invoke_from_class(unsafe { (*instance).class }, name, arg_count)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: *mut ClassObj,
+        name: *mut StringObj,
+        arg_count: usize,
+    ) -> RuntimeResult {
+        match unsafe { (*class).methods.get(name) } {
+            Some(RuntimeValue::Closure(method)) => self.call(method, arg_count),
+            _ => Err(self.undefined_property(name)),
+        }
+    }
+
+    fn call(&mut self, closure: *mut ClosureObj, arg_count: usize) -> RuntimeResult {
+        let arity = unsafe { (*(*closure).function).arity };
+        if arg_count != arity {
+            return Err(self.error(format!(
+                "expected {} arguments but got {}",
+                arity, arg_count
+            )));
+        }
+
+        // The script's frame doesn't count towards the depth
+        if self.frames.len() + 1 > self.call_depth_limit {
+            return Err(self.error("stack overflow".to_string()));
+        }
+
+        // The arguments become the first locals of the callee
+        let frame = CallFrame::new(closure, self.sp - arg_count - 1);
+        let caller = mem::replace(&mut self.frame, frame);
+        self.frames.push(caller);
+
+        Ok(())
+    }
+
+    fn call_native(&mut self, native: *mut NativeObj, arg_count: usize) -> RuntimeResult {
+        let (arity, function) = unsafe { ((*native).arity, (*native).function) };
+        if arg_count != arity {
+            return Err(self.error(format!(
+                "expected {} arguments but got {}",
+                arity, arg_count
+            )));
         }
+
+        let args: Vec<Value> = self.stack[self.sp - arg_count..self.sp]
+            .iter()
+            .map(|arg| unsafe { Value::from_runtime(arg.unpack()) })
+            .collect();
+        let result = function(&mut NativeContext { vm: self }, &args);
+        let result = result.map_err(|message| self.error(message))?;
+
+        // The native and its arguments are still on the stack, keeping them alive
+        self.maybe_collect();
+        let result = match result.to_runtime(&mut self.heap) {
+            Some(result) => result,
+            None => {
+                let name = unsafe { (*native).name() };
+                let message = format!("native function '{}' can't return objects", name);
+                return Err(self.error(message));
+            }
+        };
+
+        self.sp -= arg_count + 1;
+        self.push(result)
     }
 
-    fn link_objects(&mut self) {
-        for v in &mut self.chunk.constants {
-            match v {
-                RuntimeValue::String(str_ptr) => unsafe {
-                    (**str_ptr).next_obj = self.objects;
-                    self.objects = (**str_ptr).as_obj_ptr();
-                },
-                _ => continue,
+    /// Returns true if the finished function was the top-level script
+    #[inline]
+    fn return_from_function(&mut self) -> Result<bool, RuntimeError> {
+        let result = self.pop()?;
+
+        // Discard the callee and its locals
+        self.close_upvalues(self.frame.slots);
+        self.sp = self.frame.slots;
+
+        match self.frames.pop() {
+            None => Ok(true),
+            Some(caller) => {
+                self.frame = caller;
+                self.push(result)?;
+                Ok(false)
             }
         }
     }
 
-    fn intern_strings(&mut self) {}
-}
+    fn closure(&mut self, index: usize) -> RuntimeResult {
+        let function = match self.chunk().constants[index].unpack() {
+            RuntimeValue::Function(function) => function,
+            _ => panic!("COMPILER ERROR: closure constant isn't a function"),
+        };
+
+        self.maybe_collect();
+        let closure = self.heap.new_closure(function);
+        // Push first, so the closure is reachable while its upvalues are captured
+        self.push(RuntimeValue::Closure(closure))?;
+
+        let upvalue_count = unsafe { (*function).upvalue_count };
+        for _ in 0..upvalue_count {
+            let upvalue = match self.read_byte() {
+                0 => {
+                    let index = self.read_byte() as usize;
+                    unsafe { (&(*self.frame.closure).upvalues)[index] }
+                }
+                LONG_LOCAL => {
+                    let index = self.read_long_operand();
+                    self.capture_upvalue(self.frame.slots + index)
+                }
+                _ => {
+                    let index = self.read_byte() as usize;
+                    self.capture_upvalue(self.frame.slots + index)
+                }
+            };
+
+            unsafe { (*closure).upvalues.push(upvalue) };
+        }
+
+        Ok(())
+    }
+
+    /// Returns an open upvalue for the slot, multiple closures capturing
+    /// the same variable share the upvalue
+    fn capture_upvalue(&mut self, slot: usize) -> *mut UpvalueObj {
+        let position = self
+            .open_upvalues
+            .binary_search_by_key(&slot, |u| unsafe { (**u).slot });
 
-impl<'s> Drop for Vm<'s> {
-    fn drop(&mut self) {
-        // Free the runtime objects
-        let mut next_obj = self.objects;
-        while next_obj != ptr::null_mut() {
+        match position {
+            Ok(i) => self.open_upvalues[i],
+            Err(i) => {
+                self.maybe_collect();
+                let upvalue = self.heap.new_upvalue(slot);
+                self.open_upvalues.insert(i, upvalue);
+                upvalue
+            }
+        }
+    }
+
+    /// Moves the values of all upvalues at or above 'last_slot' off the stack
+    fn close_upvalues(&mut self, last_slot: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
             unsafe {
-                match (*next_obj).typ {
-                    ObjTyp::String => {
-                        let str_ptr = mem::transmute::<*mut Obj, *mut StringObj>(next_obj);
-                        //println!("freeing {}", (*str_ptr).as_str());
-                        let next_ptr = (*str_ptr).next_obj;
-                        ptr::drop_in_place(str_ptr);
-                        next_obj = next_ptr;
-                    }
+                if (*upvalue).slot < last_slot {
+                    break;
                 }
+
+                (*upvalue).closed = Some(self.stack[(*upvalue).slot].unpack());
             }
+            self.open_upvalues.pop();
+        }
+    }
+
+    /// Has to be called before every allocation, while all live objects
+    /// are reachable from the roots
+    #[inline]
+    fn maybe_collect(&mut self) {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+    }
+
+    fn collect_garbage(&mut self) {
+        for value in &self.stack[..self.sp] {
+            self.heap.mark_value(value.unpack());
+        }
+
+        self.heap.mark_object(self.frame.closure as *mut Obj);
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure as *mut Obj);
+        }
+
+        for upvalue in &self.open_upvalues {
+            self.heap.mark_object(*upvalue as *mut Obj);
         }
+
+        self.heap.mark_table(&self.globals);
+        self.heap.mark_object(self.init_string as *mut Obj);
+
+        self.heap.collect();
     }
+
+    #[inline]
+    pub(crate) fn values_equal(val1: RuntimeValue, val2: RuntimeValue) -> bool {
+        match (val1, val2) {
+            (RuntimeValue::Bool(b1), RuntimeValue::Bool(b2)) => b1 == b2,
+            (RuntimeValue::Number(n1), RuntimeValue::Number(n2)) => n1 == n2,
+            // Strings are interned
+            (RuntimeValue::String(s1), RuntimeValue::String(s2)) => s1 == s2,
+            (RuntimeValue::Closure(c1), RuntimeValue::Closure(c2)) => c1 == c2,
+            (RuntimeValue::Class(c1), RuntimeValue::Class(c2)) => c1 == c2,
+            (RuntimeValue::Instance(i1), RuntimeValue::Instance(i2)) => i1 == i2,
+            (RuntimeValue::BoundMethod(b1), RuntimeValue::BoundMethod(b2)) => b1 == b2,
+            (RuntimeValue::Function(f1), RuntimeValue::Function(f2)) => f1 == f2,
+            (RuntimeValue::Native(n1), RuntimeValue::Native(n2)) => n1 == n2,
+            (RuntimeValue::Nil, RuntimeValue::Nil) => true,
+            _ => false,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_falsy(val: RuntimeValue) -> bool {
+        matches!(val, RuntimeValue::Nil | RuntimeValue::Bool(false))
+    }
+}
+
+/*
+An error that aborted the execution of a script.
+The trace lists the active function calls, starting with the one
+in which the error occurred.
+*/
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub message: String,
+    pub line: usize,
+    // Source code of the failed instruction
+    pub span: Span,
+    pub trace: Vec<TraceFrame>,
 }
 
 #[derive(Debug)]
-pub enum LoxRuntimeErr {
-    InvalidType,
-    MissingOperand,
-    StackOverflow,
-    StackUnderflow,
+pub struct TraceFrame {
+    // Formatted like a function value, '<fn name>' or '<script>'
+    pub function: String,
+    pub line: usize,
+    pub span: Span,
+}
+
+impl core::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "Runtime error at line {}, column {}: {}",
+            self.line, self.span.column, self.message
+        )?;
+        // Deep recursion would repeat the same frames thousands of times
+        let mut i = 0;
+        while i < self.trace.len() {
+            let frame = &self.trace[i];
+            write!(
+                f,
+                "\n    in {} at line {}, column {}",
+                frame.function, frame.line, frame.span.column
+            )?;
+
+            let repeated = self.trace[i + 1..]
+                .iter()
+                .take_while(|other| other.function == frame.function && other.span == frame.span)
+                .count();
+            if repeated > 0 {
+                write!(f, "\n    ... repeated {} more times", repeated)?;
+            }
+            i += repeated + 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{compiler::Compiler, heap::Heap, runtime_val::RuntimeValue, vm::Vm};
+
+    fn run_stressed(text: &str) -> Vm {
+        let mut heap = Heap::new();
+        heap.set_stress_gc(true);
+
+        let mut compiler = Compiler::new(text, &mut heap);
+        let script = compiler.compile().expect("compile error");
+
+        let mut vm = Vm::new(heap);
+        vm.execute(script).expect("runtime error");
+        vm
+    }
+
+    fn global_str(vm: &mut Vm, name: &str) -> String {
+        let name = vm.heap.intern(name);
+        match vm.globals.get(name) {
+            Some(RuntimeValue::String(s)) => unsafe { (*s).as_str().to_string() },
+            _ => panic!("expected a string global"),
+        }
+    }
+
+    #[test]
+    fn gc_keeps_reachable_objects() {
+        let mut vm = run_stressed(
+            r#"
+            class Greeter {
+                init(greeting) { this.greeting = greeting; }
+                greet(name) { return this.greeting + ", " + name; }
+            }
+            fun make() {
+                var g = Greeter("hello");
+                fun greet(name) { return g.greet(name); }
+                return greet;
+            }
+            var greet = make();
+            var garbage = "";
+            for (var i = 0; i < 10; i = i + 1) { garbage = garbage + "x"; }
+            var result = greet("world");
+            "#,
+        );
+
+        assert_eq!(global_str(&mut vm, "result"), "hello, world");
+        assert_eq!(global_str(&mut vm, "garbage"), "xxxxxxxxxx");
+    }
+
+    #[test]
+    fn globals_survive_compiling() {
+        let mut heap = Heap::new();
+        heap.set_stress_gc(true);
+        let mut vm = Vm::new(heap);
+
+        let inputs = [
+            "var s = \"a\" + \"b\";",
+            "class C { init() { this.f = s + \"c\"; } }",
+            "var t = \"x\" + \"y\"; var c = C();",
+            "var result = s + t + c.f;",
+        ];
+        for text in inputs {
+            let script = vm.compiler(text).compile().expect("compile error");
+            vm.execute(script).expect("runtime error");
+        }
+
+        assert_eq!(global_str(&mut vm, "result"), "abxyabc");
+    }
+
+    #[test]
+    fn runtime_error_trace() {
+        let mut heap = Heap::new();
+        let mut compiler = Compiler::new(
+            "fun inner(x) {\n  return -x;\n}\nfun outer() { return inner(\"s\"); }\nouter();",
+            &mut heap,
+        );
+        let script = compiler.compile().expect("compile error");
+
+        let mut vm = Vm::new(heap);
+        let err = vm.execute(script).expect_err("expected a runtime error");
+
+        assert_eq!(err.message, "cannot apply 'negate' to string");
+        assert_eq!(err.line, 2);
+        // Points at the '-'
+        assert_eq!(
+            (err.span.column, err.span.offset, err.span.len),
+            (10, 24, 1)
+        );
+
+        let trace: Vec<(&str, usize)> = err
+            .trace
+            .iter()
+            .map(|f| (f.function.as_str(), f.line))
+            .collect();
+        assert_eq!(
+            trace,
+            vec![("<fn inner>", 2), ("<fn outer>", 4), ("<script>", 5)]
+        );
+    }
 }